@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN refresh_token TEXT;
+
+DROP TABLE sessions;
@@ -0,0 +1,5 @@
+ALTER TABLE users
+    ADD COLUMN totp_secret TEXT,
+    ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN totp_last_step BIGINT,
+    ADD COLUMN recovery_codes TEXT[] NOT NULL DEFAULT '{}';
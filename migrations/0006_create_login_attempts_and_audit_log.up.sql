@@ -0,0 +1,18 @@
+-- Keys are `user:<username>` or `ip:<address>`
+CREATE TABLE login_attempts (
+    key TEXT PRIMARY KEY,
+    failures INT NOT NULL,
+    last_failure_at BIGINT NOT NULL,
+    locked_until BIGINT
+);
+
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    event TEXT NOT NULL,
+    username TEXT,
+    ip TEXT,
+    detail TEXT,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX audit_log_username_idx ON audit_log (username);
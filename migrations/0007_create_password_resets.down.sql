@@ -0,0 +1,5 @@
+DROP TABLE mail_outbox;
+
+DROP TABLE password_resets;
+
+ALTER TABLE users DROP COLUMN email;
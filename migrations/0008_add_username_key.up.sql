@@ -0,0 +1,10 @@
+-- Usernames are unique by their normalized form from now on. Existing names are
+-- backfilled with an approximation of it, the application computes the exact one.
+ALTER TABLE users ADD COLUMN username_key TEXT;
+
+UPDATE users SET username_key = lower(normalize(trim(username), NFKC));
+
+ALTER TABLE users
+    ALTER COLUMN username_key SET NOT NULL,
+    ADD CONSTRAINT users_username_key_unique UNIQUE (username_key),
+    DROP CONSTRAINT users_username_key;
@@ -0,0 +1,38 @@
+-- The Postgres schema of users, sessions and the audit log as of its migration 8.
+-- Roles and recovery codes are JSON arrays, SQLite has no array type.
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL,
+    username_key TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    email TEXT,
+    roles TEXT NOT NULL DEFAULT '["user"]',
+    totp_secret TEXT,
+    totp_enabled BOOLEAN NOT NULL DEFAULT false,
+    totp_last_step BIGINT,
+    recovery_codes TEXT NOT NULL DEFAULT '[]'
+);
+
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    last_used_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    user_agent TEXT,
+    ip TEXT
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event TEXT NOT NULL,
+    username TEXT,
+    ip TEXT,
+    detail TEXT,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX audit_log_username_idx ON audit_log (username);
@@ -0,0 +1,122 @@
+use serde_json::Value;
+
+use crate::{
+    app::{models::claims::Claims, services::auth::AuthService, state::AppState},
+    http::{
+        request::Request,
+        utils::{bad_request_response, generate_http_response},
+    },
+};
+
+use super::{
+    auth_handler::{auth_error_response, client_info, cookies_cleared_response, is_valid_email},
+    mfa_handler::second_factor,
+};
+
+fn setup(state: &AppState) -> AuthService {
+    AuthService::new(state.config.clone(), &state.db)
+}
+
+fn parse_body(request: &Request) -> Result<Value, String> {
+    serde_json::from_str(&request.body)
+        .map_err(|_| bad_request_response("Request body is not valid JSON"))
+}
+
+pub async fn me(state: &AppState, claims: &Claims) -> String {
+    let auth_service = setup(state);
+    match auth_service.account(claims.uid).await {
+        Ok(account) => generate_http_response(200, &account),
+        Err(error) => auth_error_response(error),
+    }
+}
+
+pub async fn update_me(state: &AppState, request: &Request<'_>, claims: &Claims) -> String {
+    // Expects `{"username": "...", "email": "..."}`, either may be left out and
+    // an `email` of null removes the address
+    let data = match parse_body(request) {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+
+    let username = match &data["username"] {
+        Value::Null => None,
+        Value::String(username) if !username.trim().is_empty() => Some(username.trim()),
+        _ => return bad_request_response("`username` has to be a non-empty string"),
+    };
+
+    let email = match data.get("email") {
+        None => None,
+        Some(Value::Null) => Some(None),
+        Some(Value::String(email)) if is_valid_email(email.trim()) => Some(Some(email.trim())),
+        _ => return bad_request_response("`email` is not a valid address"),
+    };
+
+    if username.is_none() && email.is_none() {
+        return bad_request_response("Nothing to update, expected a `username` or an `email`");
+    }
+
+    let auth_service = setup(state);
+    match auth_service
+        .update_account(claims.uid, username, email)
+        .await
+    {
+        Ok(account) => generate_http_response(200, &account),
+        Err(error) => auth_error_response(error),
+    }
+}
+
+pub async fn change_password(state: &AppState, request: &Request<'_>, claims: &Claims) -> String {
+    let data = match parse_body(request) {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+
+    let (current_password, new_password) = match (
+        data["current_password"].as_str(),
+        data["new_password"].as_str(),
+    ) {
+        (Some(current_password), Some(new_password)) if !new_password.is_empty() => {
+            (current_password, new_password)
+        }
+        _ => return bad_request_response("Expected the `current_password` and a `new_password`"),
+    };
+
+    let auth_service = setup(state);
+    match auth_service
+        .change_password(
+            claims.uid,
+            &claims.sid,
+            current_password,
+            new_password,
+            &client_info(request),
+        )
+        .await
+    {
+        Ok(_) => generate_http_response(200, &"Password changed"),
+        Err(error) => auth_error_response(error),
+    }
+}
+
+pub async fn delete_me(state: &AppState, request: &Request<'_>, claims: &Claims) -> String {
+    // The password confirms the deletion, accounts with two-factor authentication
+    // also need a `code` or a `recovery_code`
+    let data = match parse_body(request) {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+
+    let password = match data["password"].as_str() {
+        Some(password) => password,
+        None => return bad_request_response("Expected the `password` to confirm the deletion"),
+    };
+    let factor = second_factor(&data);
+
+    let auth_service = setup(state);
+    match auth_service
+        .delete_account(claims.uid, password, factor.as_ref(), &client_info(request))
+        .await
+    {
+        Ok(_) => cookies_cleared_response(&state.config.cookies, "Account deleted"),
+        Err(error) => auth_error_response(error),
+    }
+}
@@ -0,0 +1,95 @@
+use serde_json::Value;
+use sqlx::Error as PgError;
+
+use crate::{
+    app::{
+        models::role::Role,
+        services::{admin::AdminService, error::AuthError},
+        state::AppState,
+    },
+    http::{
+        request::Request,
+        utils::{
+            bad_request_response, generate_http_response, not_found_response, something_went_wrong,
+        },
+    },
+};
+
+fn setup(state: &AppState) -> Result<AdminService, PgError> {
+    let pool = state.db.postgres()?;
+    Ok(AdminService::new(pool, state.config.clone()))
+}
+
+fn admin_error_response(error: AuthError) -> String {
+    match error {
+        AuthError::UserNotFound => not_found_response(),
+        _ => something_went_wrong(error.to_string()),
+    }
+}
+
+pub async fn list_users(state: &AppState) -> String {
+    match setup(state) {
+        Ok(admin_service) => match admin_service.users().await {
+            Ok(users) => generate_http_response(200, &users),
+            Err(error) => admin_error_response(error),
+        },
+        Err(error) => something_went_wrong(error.to_string()),
+    }
+}
+
+// Who an admin action is recorded as in the audit log
+fn actor(request: &Request) -> String {
+    match (&request.claims, &request.api_key) {
+        (Some(claims), _) => claims.username.clone(),
+        (None, Some(caller)) => format!("API key {}", caller.key_id),
+        (None, None) => String::from("unknown"),
+    }
+}
+
+pub async fn unlock_user(state: &AppState, request: &Request<'_>, id: &str) -> String {
+    let id: i32 = match id.parse() {
+        Ok(id) => id,
+        Err(_) => return not_found_response(),
+    };
+
+    match setup(state) {
+        Ok(admin_service) => match admin_service.unlock(id, &actor(request)).await {
+            Ok(user) => generate_http_response(200, &user),
+            Err(error) => admin_error_response(error),
+        },
+        Err(error) => something_went_wrong(error.to_string()),
+    }
+}
+
+pub async fn set_roles(state: &AppState, request: &Request<'_>, id: &str) -> String {
+    let id: i32 = match id.parse() {
+        Ok(id) => id,
+        Err(_) => return not_found_response(),
+    };
+
+    // Expects `{"roles": ["user", "admin"]}`, every entry has to be a known role
+    let roles: Option<Vec<Role>> = serde_json::from_str::<Value>(&request.body)
+        .ok()
+        .and_then(|data| {
+            data["roles"].as_array().map(|roles| {
+                roles
+                    .iter()
+                    .map(|role| role.as_str().and_then(Role::parse))
+                    .collect::<Option<Vec<Role>>>()
+            })
+        })
+        .flatten();
+
+    let roles = match roles {
+        Some(roles) => roles,
+        None => return bad_request_response("Expected a list of known roles in `roles`"),
+    };
+
+    match setup(state) {
+        Ok(admin_service) => match admin_service.set_roles(id, &roles).await {
+            Ok(user) => generate_http_response(200, &user),
+            Err(error) => admin_error_response(error),
+        },
+        Err(error) => something_went_wrong(error.to_string()),
+    }
+}
@@ -0,0 +1,102 @@
+use serde_json::Value;
+use sqlx::Error as PgError;
+
+use crate::{
+    app::{
+        models::{claims::Claims, role::Permission},
+        services::{api_key::ApiKeyService, error::AuthError},
+        state::AppState,
+    },
+    http::{
+        request::Request,
+        utils::{
+            bad_request_response, forbidden_response, generate_http_response, not_found_response,
+            something_went_wrong,
+        },
+    },
+};
+
+fn setup(state: &AppState) -> Result<ApiKeyService, PgError> {
+    let pool = state.db.postgres()?;
+    Ok(ApiKeyService::new(pool))
+}
+
+fn api_key_error_response(error: AuthError) -> String {
+    match error {
+        AuthError::ApiKeyNotFound => not_found_response(),
+        _ => something_went_wrong(error.to_string()),
+    }
+}
+
+pub async fn create_api_key(state: &AppState, request: &Request<'_>, claims: &Claims) -> String {
+    // Expects `{"name": "ci", "scopes": ["users:read"], "expires_in": 86400}`,
+    // `scopes` and `expires_in` may be left out
+    let data: Value = match serde_json::from_str(&request.body) {
+        Ok(data) => data,
+        Err(_) => return bad_request_response("Request body is not valid JSON"),
+    };
+
+    let name = match data["name"].as_str().map(str::trim) {
+        Some(name) if !name.is_empty() => name,
+        _ => return bad_request_response("Expected a non-empty `name`"),
+    };
+
+    let scopes = match &data["scopes"] {
+        Value::Null => Some(Vec::new()),
+        Value::Array(scopes) => scopes
+            .iter()
+            .map(|scope| scope.as_str().and_then(Permission::parse))
+            .collect::<Option<Vec<Permission>>>(),
+        _ => None,
+    };
+    let scopes = match scopes {
+        Some(scopes) => scopes,
+        None => return bad_request_response("Expected a list of known scopes in `scopes`"),
+    };
+
+    // A key cannot be given more than its owner is allowed to do
+    if let Some(scope) = scopes.iter().find(|scope| !claims.has_permission(**scope)) {
+        return forbidden_response(&format!("You cannot grant the {} scope", scope));
+    }
+
+    let expires_in = match &data["expires_in"] {
+        Value::Null => None,
+        value => match value.as_i64() {
+            Some(secs) if secs > 0 => Some(secs),
+            _ => {
+                return bad_request_response("`expires_in` has to be a positive number of seconds")
+            }
+        },
+    };
+
+    match setup(state) {
+        Ok(api_key_service) => match api_key_service
+            .create(claims.uid, name, &scopes, expires_in)
+            .await
+        {
+            Ok(key) => generate_http_response(200, &key),
+            Err(error) => api_key_error_response(error),
+        },
+        Err(error) => something_went_wrong(error.to_string()),
+    }
+}
+
+pub async fn list_api_keys(state: &AppState, claims: &Claims) -> String {
+    match setup(state) {
+        Ok(api_key_service) => match api_key_service.list(claims.uid).await {
+            Ok(keys) => generate_http_response(200, &keys),
+            Err(error) => api_key_error_response(error),
+        },
+        Err(error) => something_went_wrong(error.to_string()),
+    }
+}
+
+pub async fn revoke_api_key(state: &AppState, claims: &Claims, id: &str) -> String {
+    match setup(state) {
+        Ok(api_key_service) => match api_key_service.revoke(claims.uid, id).await {
+            Ok(_) => generate_http_response(200, &"API key revoked"),
+            Err(error) => api_key_error_response(error),
+        },
+        Err(error) => something_went_wrong(error.to_string()),
+    }
+}
@@ -1,20 +1,35 @@
-use sqlx::Error as PgError;
-use std::env;
-use std::sync::mpsc::Sender;
-
 use crate::{
-    app::{models::user::User, services::auth::AuthService},
-    http::utils::{generate_http_response, not_found_response, something_went_wrong},
+    app::{
+        models::{
+            claims::Claims,
+            session::ClientInfo,
+            user::{User, UserProfile},
+        },
+        services::{
+            auth::{AuthService, LoginOutcome},
+            error::AuthError,
+            utils::{access_token_expiration_secs, REFRESH_TOKEN_EXPIRATION_SECS},
+        },
+        state::AppState,
+    },
+    config::{Config, TokenMode},
+    http::{
+        cookie::CookieOptions,
+        request::Request,
+        utils::{
+            bad_request_response, error_response_with_headers, extract_cookie,
+            generate_http_response, generate_http_response_with_headers, not_found_response,
+            something_went_wrong, unauthorized_response,
+        },
+    },
+    metrics,
 };
 use serde_json::{Error, Value};
 
-async fn setup() -> Result<AuthService, PgError> {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL is not specified");
-    let pool = sqlx::postgres::PgPool::connect(database_url.as_str()).await?;
-    let cloned_pool = pool.clone();
-    let auth_service = AuthService::new(pool);
+use super::mfa_handler::{mfa_error_response, second_factor};
 
-    Ok(auth_service)
+fn setup(state: &AppState) -> AuthService {
+    AuthService::new(state.config.clone(), &state.db)
 }
 
 fn parse_json(json_string: &str) -> Result<Value, Error> {
@@ -22,82 +37,267 @@ fn parse_json(json_string: &str) -> Result<Value, Error> {
     parsed_json
 }
 
-pub async fn login(
-    sender: &Sender<String>,
-    body: String,
-    cookies: Option<Vec<(&str, &str)>>,
-) -> String {
-    match setup().await {
-        Ok(auth_service) => {
-            let username;
-            let password;
-
-            match parse_json(body.as_str()) {
-                Ok(data) => {
-                    username = data["username"].to_string();
-                    password = data["password"].to_string();
-                }
-                Err(err) => {
-                    username = String::new();
-                    password = String::new();
-                }
-            }
+pub fn auth_error_response(error: AuthError) -> String {
+    match error {
+        AuthError::Database(error) => something_went_wrong(error.to_string()),
+        AuthError::PasswordHash(_) | AuthError::SigningKey(_) => {
+            something_went_wrong(error.to_string())
+        }
+        AuthError::SessionNotFound | AuthError::UserNotFound => not_found_response(),
+        AuthError::UsernameTaken => error_response_with_headers(409, &error.to_string(), &[]),
+        AuthError::Validation(_) => bad_request_response(&error.to_string()),
+        AuthError::TooManyAttempts(retry_after) => error_response_with_headers(
+            429,
+            &error.to_string(),
+            &[("Retry-After", retry_after.to_string())],
+        ),
+        AuthError::InvalidMfaCode | AuthError::MfaAlreadyEnabled | AuthError::MfaNotEnabled => {
+            mfa_error_response(error)
+        }
+        _ => unauthorized_response(&error.to_string()),
+    }
+}
+
+// Where issued tokens are handed to the client is picked with AUTH_TOKEN_MODE
+fn token_response(config: &Config, user: &User) -> String {
+    match config.auth.token_mode {
+        TokenMode::Json => generate_http_response(200, user),
+        TokenMode::Cookie => {
+            let options = &config.cookies;
+            let headers = [
+                (
+                    "Set-Cookie",
+                    options
+                        .build(
+                            "token",
+                            &user.access_token,
+                            access_token_expiration_secs(&config.jwt) as i64,
+                        )
+                        .to_string(),
+                ),
+                (
+                    "Set-Cookie",
+                    options
+                        .build(
+                            "refresh",
+                            &user.refresh_token,
+                            REFRESH_TOKEN_EXPIRATION_SECS as i64,
+                        )
+                        .to_string(),
+                ),
+            ];
+
+            generate_http_response_with_headers(200, &UserProfile::from(user), &headers)
+        }
+    }
+}
+
+pub fn client_info(request: &Request) -> ClientInfo {
+    ClientInfo::new(request.user_agent().map(String::from), request.client_ip())
+}
 
-            let response: Result<User, PgError> = auth_service
-                .login(username.as_str(), password.as_str(), cookies)
-                .await;
+// How a failed login or refresh is counted in the metrics
+fn failure_outcome(error: &AuthError) -> &'static str {
+    match error {
+        AuthError::TooManyAttempts(_) => "throttled",
+        AuthError::TokenReused => "reused",
+        AuthError::Database(_) | AuthError::PasswordHash(_) | AuthError::SigningKey(_) => "error",
+        _ => "failure",
+    }
+}
 
-            match response {
-                Ok(response) => {
-                    let formatted_response = generate_http_response(200, &response);
+pub async fn login(state: &AppState, request: &Request<'_>) -> String {
+    let auth_service = setup(state);
+    let username;
+    let password;
 
-                    let _ = sender.send(formatted_response.clone());
-                    return formatted_response;
-                }
-                Err(error) => something_went_wrong(error.to_string()),
+    // A malformed request is not a failed login, so it answers 400 without
+    // counting against the account
+    match parse_json(request.body.as_str()) {
+        Ok(data) => match (data["username"].as_str(), data["password"].as_str()) {
+            (Some(data_username), Some(data_password)) => {
+                username = data_username.to_string();
+                password = data_password.to_string();
             }
+            _ => return bad_request_response("Expected a `username` and a `password`"),
+        },
+        Err(_) => return bad_request_response("Request body is not valid JSON"),
+    }
+
+    let response = auth_service
+        .login(username.as_str(), password.as_str(), &client_info(request))
+        .await;
+
+    match response {
+        Ok(LoginOutcome::Authenticated(user)) => {
+            metrics::record_login("password", "success");
+            token_response(&state.config, &user)
         }
-        _ => {
-            return not_found_response();
+        // No tokens yet, not even cookies, until `/auth/login/mfa` succeeds
+        Ok(LoginOutcome::MfaRequired(challenge)) => {
+            metrics::record_login("password", "mfa_required");
+            generate_http_response(200, &challenge)
         }
+        Err(error) => {
+            metrics::record_login("password", failure_outcome(&error));
+            auth_error_response(error)
+        }
+    }
+}
+
+pub async fn login_mfa(state: &AppState, request: &Request<'_>) -> String {
+    // Expects the `mfa_token` from `/auth/login` with either a `code` or a `recovery_code`
+    let (mfa_token, factor) = match parse_json(request.body.as_str()) {
+        Ok(data) => match (data["mfa_token"].as_str(), second_factor(&data)) {
+            (Some(mfa_token), Some(factor)) => (mfa_token.to_string(), factor),
+            _ => {
+                return bad_request_response(
+                    "Expected an `mfa_token` with a `code` or a `recovery_code`",
+                )
+            }
+        },
+        Err(_) => return bad_request_response("Request body is not valid JSON"),
     };
 
-    return not_found_response();
+    let auth_service = setup(state);
+    match auth_service
+        .login_mfa(&mfa_token, &factor, &client_info(request))
+        .await
+    {
+        Ok(user) => {
+            metrics::record_login("mfa", "success");
+            token_response(&state.config, &user)
+        }
+        Err(error) => {
+            metrics::record_login("mfa", failure_outcome(&error));
+            auth_error_response(error)
+        }
+    }
+}
+
+pub fn is_valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty() && domain.contains('.') && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    }
 }
 
-pub async fn register(sender: &Sender<String>, body: String) -> String {
-    match setup().await {
-        Ok(auth_service) => {
-            let username;
-            let password;
+pub async fn register(state: &AppState, request: &Request<'_>) -> String {
+    let auth_service = setup(state);
+    let username;
+    let password;
+    let email;
 
-            match parse_json(body.as_str()) {
-                Ok(data) => {
-                    username = data["username"].to_string();
-                    password = data["password"].to_string();
-                }
-                Err(_) => {
-                    username = String::new();
-                    password = String::new();
-                }
+    match parse_json(request.body.as_str()) {
+        Ok(data) => match (data["username"].as_str(), data["password"].as_str()) {
+            (Some(data_username), Some(data_password)) => {
+                username = data_username.to_string();
+                password = data_password.to_string();
+                // Optional, but without it the password cannot be reset
+                email = data["email"].as_str().map(|email| email.trim().to_string());
             }
+            _ => return bad_request_response("Expected a `username` and a `password`"),
+        },
+        Err(_) => return bad_request_response("Request body is not valid JSON"),
+    }
 
-            let response: Result<User, PgError> = auth_service.register(&username, &password).await;
+    if email.as_ref().is_some_and(|email| !is_valid_email(email)) {
+        return bad_request_response("`email` is not a valid address");
+    }
 
-            match response {
-                Ok(response) => {
-                    let formatted_response = generate_http_response(200, &response);
+    let response = auth_service
+        .register(
+            &username,
+            &password,
+            email.as_deref(),
+            &client_info(request),
+        )
+        .await;
 
-                    let _ = sender.send(formatted_response.clone());
-                    return formatted_response;
-                }
-                Err(error) => something_went_wrong(error.to_string()),
-            }
+    match response {
+        Ok(response) => token_response(&state.config, &response),
+        Err(error) => auth_error_response(error),
+    }
+}
+
+// Clients may either post the refresh token or rely on the `refresh` cookie
+fn extract_refresh_token(request: &Request) -> Option<String> {
+    let body_token = parse_json(request.body.as_str())
+        .ok()
+        .and_then(|data| data["refresh_token"].as_str().map(String::from));
+
+    body_token.or_else(|| extract_cookie(request.cookies.as_ref(), "refresh"))
+}
+
+// Clears the token cookies along with sending `message`
+pub fn cookies_cleared_response(options: &CookieOptions, message: &str) -> String {
+    let headers = [
+        ("Set-Cookie", options.removal("token").to_string()),
+        ("Set-Cookie", options.removal("refresh").to_string()),
+    ];
+
+    generate_http_response_with_headers(200, &message, &headers)
+}
+
+fn logged_out_response(state: &AppState) -> String {
+    cookies_cleared_response(&state.config.cookies, "Logged out")
+}
+
+pub async fn refresh(state: &AppState, request: &Request<'_>) -> String {
+    let refresh_token = match extract_refresh_token(request) {
+        Some(token) => token,
+        None => return unauthorized_response("Could not extract refresh token"),
+    };
+
+    let auth_service = setup(state);
+    match auth_service.refresh(&refresh_token).await {
+        Ok(response) => {
+            metrics::record_refresh("success");
+            token_response(&state.config, &response)
         }
-        _ => {
-            return not_found_response();
+        Err(error) => {
+            metrics::record_refresh(failure_outcome(&error));
+            auth_error_response(error)
         }
+    }
+}
+
+pub async fn logout(state: &AppState, request: &Request<'_>) -> String {
+    // Without a refresh token there is nothing to revoke, but the cookies still get cleared
+    let refresh_token = match extract_refresh_token(request) {
+        Some(token) => token,
+        None => return logged_out_response(state),
     };
 
-    return not_found_response();
+    let auth_service = setup(state);
+    match auth_service.logout(&refresh_token).await {
+        Ok(_) | Err(AuthError::InvalidToken) => logged_out_response(state),
+        Err(error) => auth_error_response(error),
+    }
+}
+
+pub async fn logout_everywhere(state: &AppState, claims: &Claims) -> String {
+    let auth_service = setup(state);
+    match auth_service.logout_everywhere(claims.uid).await {
+        Ok(_) => logged_out_response(state),
+        Err(error) => auth_error_response(error),
+    }
+}
+
+pub async fn list_sessions(state: &AppState, claims: &Claims) -> String {
+    let auth_service = setup(state);
+    match auth_service.sessions(claims.uid, &claims.sid).await {
+        Ok(sessions) => generate_http_response(200, &sessions),
+        Err(error) => auth_error_response(error),
+    }
+}
+
+pub async fn revoke_session(state: &AppState, claims: &Claims, sid: &str) -> String {
+    let auth_service = setup(state);
+    match auth_service.revoke_session(claims.uid, sid).await {
+        Ok(_) => generate_http_response(200, &"Session revoked"),
+        Err(error) => auth_error_response(error),
+    }
 }
@@ -0,0 +1,29 @@
+use crate::{
+    app::{
+        services::health::{liveness, readiness},
+        state::AppState,
+    },
+    http::utils::generate_http_response_with_headers,
+};
+
+fn headers() -> [(&'static str, String); 2] {
+    [
+        ("Content-Type", String::from("application/json")),
+        ("Cache-Control", String::from("no-store")),
+    ]
+}
+
+pub fn healthz() -> String {
+    generate_http_response_with_headers(200, &liveness(), &headers())
+}
+
+// 503 until the server can take traffic, with the state of every check in the body
+pub async fn readyz(state: &AppState) -> String {
+    let readiness = readiness(&state.config.jwt, &state.db).await;
+    let status_code = match readiness.ready {
+        true => 200,
+        false => 503,
+    };
+
+    generate_http_response_with_headers(status_code, &readiness, &headers())
+}
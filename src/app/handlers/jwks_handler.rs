@@ -0,0 +1,51 @@
+use crate::{
+    app::{
+        models::jwk::JwkSet,
+        services::{
+            keys::{keyring, KeyError},
+            utils::{access_token_expiration_secs, current_timestamp},
+        },
+        state::AppState,
+    },
+    http::utils::{generate_http_response_with_headers, something_went_wrong},
+};
+
+pub fn jwks(state: &AppState) -> String {
+    let jwt = &state.config.jwt;
+    let keyring = match keyring(jwt) {
+        Ok(keyring) => keyring,
+        // Tokens signed with the shared secret have no public keys to publish
+        Err(KeyError::NoActiveKey) => {
+            let headers = [("Content-Type", String::from("application/json"))];
+            return generate_http_response_with_headers(200, &JwkSet { keys: vec![] }, &headers);
+        }
+        Err(error) => return something_went_wrong(error.to_string()),
+    };
+
+    let keys = keyring.verification_keys();
+    let jwks: Result<Vec<_>, KeyError> = keys.iter().map(|key| key.to_jwk()).collect();
+    let jwks = match jwks {
+        Ok(jwks) => jwks,
+        Err(error) => return something_went_wrong(error.to_string()),
+    };
+
+    // Caches must not keep a retired key around for longer than the server itself
+    // accepts it, so the lifetime is capped at the earliest retired key's expiry
+    let now = current_timestamp();
+    let max_age = keys
+        .iter()
+        .filter_map(|key| {
+            key.metadata
+                .verifiable_until(access_token_expiration_secs(jwt))
+        })
+        .map(|until| until.saturating_sub(now))
+        // JWKS_MAX_AGE is the upper bound for how long verifiers may cache the key set
+        .fold(jwt.jwks_max_age, usize::min);
+
+    let headers = [
+        ("Content-Type", String::from("application/json")),
+        ("Cache-Control", format!("public, max-age={}", max_age)),
+    ];
+
+    generate_http_response_with_headers(200, &JwkSet { keys: jwks }, &headers)
+}
@@ -0,0 +1,37 @@
+use crate::{
+    app::{services::utils::hash_token, state::AppState},
+    http::{
+        request::Request,
+        utils::{text_response_with_headers, unauthorized_response},
+    },
+    metrics,
+};
+
+// Scrapers send METRICS_TOKEN as a bearer token when it is set. Digests are
+// compared so the time taken says nothing about the token.
+fn is_authorized(state: &AppState, request: &Request) -> bool {
+    let expected = match &state.config.metrics.token {
+        Some(token) => token,
+        None => return true,
+    };
+
+    request
+        .authorization_header()
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .is_some_and(|token| hash_token(token) == hash_token(expected))
+}
+
+pub fn metrics(state: &AppState, request: &Request) -> String {
+    if !is_authorized(state, request) {
+        return unauthorized_response("A valid metrics token is required");
+    }
+
+    let headers = [
+        (
+            "Content-Type",
+            String::from("text/plain; version=0.0.4; charset=utf-8"),
+        ),
+        ("Cache-Control", String::from("no-store")),
+    ];
+    text_response_with_headers(200, &metrics::render(&state.db), &headers)
+}
@@ -0,0 +1,89 @@
+use serde_json::Value;
+
+use crate::{
+    app::{
+        models::claims::Claims,
+        services::{
+            error::AuthError,
+            mfa::{MfaService, SecondFactor},
+        },
+        state::AppState,
+    },
+    http::{
+        request::Request,
+        utils::{
+            bad_request_response, generate_http_response, not_found_response, something_went_wrong,
+            unauthorized_response,
+        },
+    },
+};
+
+use super::auth_handler::{auth_error_response, client_info};
+
+fn setup(state: &AppState) -> MfaService {
+    MfaService::new(state.config.clone(), &state.db)
+}
+
+pub fn mfa_error_response(error: AuthError) -> String {
+    match error {
+        AuthError::Database(error) => something_went_wrong(error.to_string()),
+        AuthError::UserNotFound => not_found_response(),
+        AuthError::MfaAlreadyEnabled | AuthError::MfaNotEnabled => {
+            bad_request_response(&error.to_string())
+        }
+        AuthError::TooManyAttempts(_) => auth_error_response(error),
+        _ => unauthorized_response(&error.to_string()),
+    }
+}
+
+// Reads `{"code": "123456"}` or `{"recovery_code": "abcd-ef01"}`
+pub fn second_factor(data: &Value) -> Option<SecondFactor> {
+    if let Some(code) = data["code"].as_str() {
+        return Some(SecondFactor::Totp(code.to_string()));
+    }
+
+    data["recovery_code"]
+        .as_str()
+        .map(|code| SecondFactor::RecoveryCode(code.to_string()))
+}
+
+pub async fn setup_totp(state: &AppState, claims: &Claims) -> String {
+    match setup(state).setup_totp(claims.uid).await {
+        Ok(totp_setup) => generate_http_response(200, &totp_setup),
+        Err(error) => mfa_error_response(error),
+    }
+}
+
+pub async fn confirm_totp(state: &AppState, request: &Request<'_>, claims: &Claims) -> String {
+    let code = match serde_json::from_str::<Value>(&request.body) {
+        Ok(data) => match data["code"].as_str() {
+            Some(code) => code.to_string(),
+            None => return bad_request_response("Expected the authenticator's `code`"),
+        },
+        Err(_) => return bad_request_response("Request body is not valid JSON"),
+    };
+
+    match setup(state).confirm_totp(claims.uid, &code).await {
+        Ok(recovery_codes) => generate_http_response(200, &recovery_codes),
+        Err(error) => mfa_error_response(error),
+    }
+}
+
+pub async fn disable_totp(state: &AppState, request: &Request<'_>, claims: &Claims) -> String {
+    // Turning it off takes a current code, so a stolen session alone is not enough
+    let factor = match serde_json::from_str::<Value>(&request.body) {
+        Ok(data) => match second_factor(&data) {
+            Some(factor) => factor,
+            None => return bad_request_response("Expected a `code` or a `recovery_code`"),
+        },
+        Err(_) => return bad_request_response("Request body is not valid JSON"),
+    };
+
+    match setup(state)
+        .disable_totp(claims.uid, &factor, &client_info(request))
+        .await
+    {
+        Ok(_) => generate_http_response(200, &"Two-factor authentication disabled"),
+        Err(error) => mfa_error_response(error),
+    }
+}
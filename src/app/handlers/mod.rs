@@ -1,2 +1,10 @@
+pub mod account_handler;
+pub mod admin_handler;
+pub mod api_key_handler;
 pub mod auth_handler;
+pub mod health_handler;
+pub mod jwks_handler;
+pub mod metrics_handler;
+pub mod mfa_handler;
+pub mod password_handler;
 pub mod test_handler;
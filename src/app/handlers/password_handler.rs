@@ -0,0 +1,86 @@
+use serde_json::Value;
+
+use crate::{
+    app::{
+        services::{error::AuthError, mail::mailer, password_reset::PasswordResetService},
+        state::AppState,
+    },
+    http::{
+        request::Request,
+        utils::{bad_request_response, generate_http_response, something_went_wrong},
+    },
+    logging,
+};
+
+fn setup(state: &AppState) -> Result<PasswordResetService, String> {
+    let pool = state.db.postgres().map_err(|error| error.to_string())?;
+    let mailer = mailer(&state.config.mail, &pool).map_err(|error| error.to_string())?;
+
+    Ok(PasswordResetService::new(
+        pool,
+        mailer,
+        state.config.clone(),
+    ))
+}
+
+pub async fn forgot_password(state: &AppState, request: &Request<'_>) -> String {
+    let email = match serde_json::from_str::<Value>(&request.body) {
+        Ok(data) => match data["email"].as_str() {
+            Some(email) => email.to_string(),
+            None => return bad_request_response("Expected the account's `email`"),
+        },
+        Err(_) => return bad_request_response("Request body is not valid JSON"),
+    };
+
+    let password_reset_service = match setup(state) {
+        Ok(password_reset_service) => password_reset_service,
+        Err(error) => return something_went_wrong(error),
+    };
+
+    // The answer is the same whether or not the address belongs to an account,
+    // so this cannot be used to find out who has one
+    let client_ip = request.client_ip();
+    if let Err(error) = password_reset_service
+        .request_reset(&email, client_ip.as_deref())
+        .await
+    {
+        logging::error(
+            "Could not start password reset",
+            &[("error", error.to_string().into())],
+        );
+    }
+
+    generate_http_response(
+        200,
+        &"If an account uses this address, a reset link has been sent to it",
+    )
+}
+
+pub async fn reset_password(state: &AppState, request: &Request<'_>) -> String {
+    let (token, password) = match serde_json::from_str::<Value>(&request.body) {
+        Ok(data) => match (data["token"].as_str(), data["password"].as_str()) {
+            (Some(token), Some(password)) if !password.is_empty() => {
+                (token.to_string(), password.to_string())
+            }
+            _ => return bad_request_response("Expected a `token` and a new `password`"),
+        },
+        Err(_) => return bad_request_response("Request body is not valid JSON"),
+    };
+
+    let password_reset_service = match setup(state) {
+        Ok(password_reset_service) => password_reset_service,
+        Err(error) => return something_went_wrong(error),
+    };
+
+    let client_ip = request.client_ip();
+    match password_reset_service
+        .reset_password(&token, &password, client_ip.as_deref())
+        .await
+    {
+        Ok(_) => generate_http_response(200, &"Password has been reset"),
+        Err(error @ (AuthError::InvalidResetToken | AuthError::Validation(_))) => {
+            bad_request_response(&error.to_string())
+        }
+        Err(error) => something_went_wrong(error.to_string()),
+    }
+}
@@ -1,4 +1,6 @@
 pub mod handlers;
 pub mod models;
+pub mod repositories;
 pub mod router;
 pub mod services;
+pub mod state;
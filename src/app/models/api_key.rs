@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+use super::role::{Permission, Role};
+
+// What the owner sees of a key, the secret itself is only shown once on creation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub details: ApiKey,
+    pub key: String,
+}
+
+// The caller behind a request made with an API key. It acts as the key's
+// owner, limited to the scopes the key was created with.
+#[derive(Debug)]
+pub struct ApiKeyCaller {
+    pub key_id: String,
+    // The key's owner
+    pub uid: i32,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+impl ApiKeyCaller {
+    fn is_in_scope(&self, permission: Permission) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| scope == &permission.to_string())
+    }
+
+    // The owner's role only counts when the key was given every permission
+    // that comes with it, so a key without scopes never acts as an admin
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.iter().any(|name| name == &role.to_string())
+            && role
+                .permissions()
+                .iter()
+                .all(|permission| self.is_in_scope(*permission))
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.is_in_scope(permission)
+            && self
+                .roles
+                .iter()
+                .filter_map(|name| Role::parse(name))
+                .any(|role| role.permissions().contains(&permission))
+    }
+}
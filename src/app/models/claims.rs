@@ -1,14 +1,43 @@
 use serde::{Deserialize, Serialize};
 
+use super::role::{Permission, Role};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenType {
+    Access,
+    Refresh,
+    // Proves the password step of a login, exchanged at `/auth/login/mfa`
+    MfaChallenge,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Claims {
     pub username: String,
     pub uid: i32,
+    pub sid: String,
+    // Keeps an access token from ever being accepted where a refresh token is expected
+    pub token_type: TokenType,
+    // Role names as stored on the user, only carried by access tokens
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub iss: String,
+    pub aud: String,
+    pub iat: usize,
+    pub nbf: usize,
     pub exp: usize,
+    pub jti: String,
 }
 
 impl Claims {
-    pub fn new(username: String, uid: i32, exp: usize) -> Self {
-        Claims { username, uid, exp }
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.iter().any(|name| name == &role.to_string())
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.roles
+            .iter()
+            .filter_map(|name| Role::parse(name))
+            .any(|role| role.permissions().contains(&permission))
     }
 }
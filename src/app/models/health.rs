@@ -0,0 +1,50 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    Failing,
+}
+
+// Returned by `/healthz`, answering at all is what shows the process is alive
+#[derive(Debug, Serialize)]
+pub struct Liveness {
+    pub status: HealthStatus,
+    pub uptime_secs: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    pub fn ok(detail: Option<String>) -> Self {
+        Check {
+            status: HealthStatus::Ok,
+            detail,
+        }
+    }
+
+    pub fn failing(detail: String) -> Self {
+        Check {
+            status: HealthStatus::Failing,
+            detail: Some(detail),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self.status, HealthStatus::Ok)
+    }
+}
+
+// Returned by `/readyz`, ready only when every check is ok
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
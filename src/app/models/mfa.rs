@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+// Returned by `/auth/totp` while enrollment still has to be confirmed with a first code
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpSetup {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+// Shown once, only their hashes are kept
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+// What `/auth/login` returns instead of tokens when the account has a second factor
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+}
+
+impl MfaChallenge {
+    pub fn new(mfa_token: String) -> Self {
+        MfaChallenge {
+            mfa_required: true,
+            mfa_token,
+        }
+    }
+}
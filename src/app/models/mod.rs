@@ -1,2 +1,8 @@
+pub mod api_key;
 pub mod claims;
+pub mod health;
+pub mod jwk;
+pub mod mfa;
+pub mod role;
+pub mod session;
 pub mod user;
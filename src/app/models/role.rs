@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+// Permissions double as API key scopes, a key can only use the permissions
+// it was created with and its owner still holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    ReadUsers,
+    ManageUsers,
+}
+
+impl Permission {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "users:read" => Some(Permission::ReadUsers),
+            "users:manage" => Some(Permission::ManageUsers),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::ReadUsers => write!(f, "users:read"),
+            Permission::ManageUsers => write!(f, "users:manage"),
+        }
+    }
+}
+
+impl Role {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "user" => Some(Role::User),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::User => &[],
+            Role::Admin => &[Permission::ReadUsers, Permission::ManageUsers],
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::User => write!(f, "user"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
@@ -18,3 +18,36 @@ impl User {
         }
     }
 }
+
+// What the client sees of a user when its tokens travel in cookies instead of the body
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub id: i32,
+    pub username: String,
+}
+
+impl From<&User> for UserProfile {
+    fn from(user: &User) -> Self {
+        UserProfile {
+            id: user.id,
+            username: user.username.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserSummary {
+    pub id: i32,
+    pub username: String,
+    pub roles: Vec<String>,
+}
+
+// The signed in user's own view of their account, returned by `/auth/me`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Account {
+    pub id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    pub mfa_enabled: bool,
+}
@@ -0,0 +1,5 @@
+pub mod session;
+pub mod user;
+
+#[cfg(test)]
+mod tests;
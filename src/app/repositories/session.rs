@@ -0,0 +1,338 @@
+use async_trait::async_trait;
+use sqlx::{postgres::PgPool, Row};
+
+#[cfg(feature = "sqlite")]
+use sqlx::sqlite::SqlitePool;
+#[cfg(test)]
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{
+    models::session::{ClientInfo, Session},
+    services::{
+        error::AuthError,
+        utils::{current_timestamp, REFRESH_TOKEN_EXPIRATION_SECS},
+    },
+};
+
+// Where sessions and the hashes of their current refresh tokens are kept
+#[async_trait]
+pub trait SessionRepository: Send + Sync {
+    async fn create(
+        &self,
+        sid: &str,
+        uid: i32,
+        token_hash: &str,
+        client: &ClientInfo,
+    ) -> Result<(), AuthError>;
+
+    // Swaps the token hash only if `current_hash` is still the latest one of a
+    // live session, so two concurrent refreshes with the same token cannot both
+    // succeed. Returns whether it did.
+    async fn rotate(
+        &self,
+        sid: &str,
+        uid: i32,
+        current_hash: &str,
+        new_hash: &str,
+    ) -> Result<bool, AuthError>;
+
+    async fn delete(&self, sid: &str, uid: i32) -> Result<bool, AuthError>;
+
+    async fn delete_for_user(&self, uid: i32) -> Result<(), AuthError>;
+
+    // Signs a user out everywhere except for the session the request came from
+    async fn delete_others(&self, uid: i32, current_sid: &str) -> Result<(), AuthError>;
+
+    // The live sessions of a user, most recently used first
+    async fn list(&self, uid: i32, current_sid: &str) -> Result<Vec<Session>, AuthError>;
+}
+
+// Lets the repository be picked at runtime, see `AuthService::new`
+#[async_trait]
+impl<T: SessionRepository + ?Sized> SessionRepository for Box<T> {
+    async fn create(
+        &self,
+        sid: &str,
+        uid: i32,
+        token_hash: &str,
+        client: &ClientInfo,
+    ) -> Result<(), AuthError> {
+        (**self).create(sid, uid, token_hash, client).await
+    }
+
+    async fn rotate(
+        &self,
+        sid: &str,
+        uid: i32,
+        current_hash: &str,
+        new_hash: &str,
+    ) -> Result<bool, AuthError> {
+        (**self).rotate(sid, uid, current_hash, new_hash).await
+    }
+
+    async fn delete(&self, sid: &str, uid: i32) -> Result<bool, AuthError> {
+        (**self).delete(sid, uid).await
+    }
+
+    async fn delete_for_user(&self, uid: i32) -> Result<(), AuthError> {
+        (**self).delete_for_user(uid).await
+    }
+
+    async fn delete_others(&self, uid: i32, current_sid: &str) -> Result<(), AuthError> {
+        (**self).delete_others(uid, current_sid).await
+    }
+
+    async fn list(&self, uid: i32, current_sid: &str) -> Result<Vec<Session>, AuthError> {
+        (**self).list(uid, current_sid).await
+    }
+}
+
+// The session SQL is the same for Postgres and SQLite
+macro_rules! sql_session_repository {
+    ($name:ident, $pool:ty) => {
+        pub struct $name {
+            pool: $pool,
+        }
+
+        impl $name {
+            pub fn new(pool: $pool) -> Self {
+                $name { pool }
+            }
+        }
+
+        #[async_trait]
+        impl SessionRepository for $name {
+            async fn create(
+                &self,
+                sid: &str,
+                uid: i32,
+                token_hash: &str,
+                client: &ClientInfo,
+            ) -> Result<(), AuthError> {
+                let now = current_timestamp() as i64;
+                let expires_at = now + REFRESH_TOKEN_EXPIRATION_SECS as i64;
+
+                let query = "INSERT INTO sessions (id, user_id, token_hash, created_at, last_used_at, expires_at, user_agent, ip) VALUES ($1, $2, $3, $4, $4, $5, $6, $7)";
+                sqlx::query(query)
+                    .bind(sid)
+                    .bind(uid)
+                    .bind(token_hash)
+                    .bind(now)
+                    .bind(expires_at)
+                    .bind(&client.user_agent)
+                    .bind(&client.ip)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(())
+            }
+
+            async fn rotate(
+                &self,
+                sid: &str,
+                uid: i32,
+                current_hash: &str,
+                new_hash: &str,
+            ) -> Result<bool, AuthError> {
+                let now = current_timestamp() as i64;
+                let expires_at = now + REFRESH_TOKEN_EXPIRATION_SECS as i64;
+
+                let query = "UPDATE sessions SET token_hash = $1, last_used_at = $2, expires_at = $3 WHERE id = $4 AND user_id = $5 AND token_hash = $6 AND expires_at > $2";
+                let result = sqlx::query(query)
+                    .bind(new_hash)
+                    .bind(now)
+                    .bind(expires_at)
+                    .bind(sid)
+                    .bind(uid)
+                    .bind(current_hash)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn delete(&self, sid: &str, uid: i32) -> Result<bool, AuthError> {
+                let query = "DELETE FROM sessions WHERE id = $1 AND user_id = $2";
+                let result = sqlx::query(query)
+                    .bind(sid)
+                    .bind(uid)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn delete_for_user(&self, uid: i32) -> Result<(), AuthError> {
+                let query = "DELETE FROM sessions WHERE user_id = $1";
+                sqlx::query(query).bind(uid).execute(&self.pool).await?;
+
+                Ok(())
+            }
+
+            async fn delete_others(&self, uid: i32, current_sid: &str) -> Result<(), AuthError> {
+                let query = "DELETE FROM sessions WHERE user_id = $1 AND id <> $2";
+                sqlx::query(query)
+                    .bind(uid)
+                    .bind(current_sid)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(())
+            }
+
+            async fn list(&self, uid: i32, current_sid: &str) -> Result<Vec<Session>, AuthError> {
+                let query = "SELECT id, created_at, last_used_at, expires_at, user_agent, ip FROM sessions WHERE user_id = $1 AND expires_at > $2 ORDER BY last_used_at DESC";
+                let rows = sqlx::query(query)
+                    .bind(uid)
+                    .bind(current_timestamp() as i64)
+                    .fetch_all(&self.pool)
+                    .await?;
+
+                let sessions: Result<Vec<Session>, sqlx::Error> = rows
+                    .iter()
+                    .map(|row| {
+                        let id: String = row.try_get("id")?;
+                        let current = id == current_sid;
+
+                        Ok(Session {
+                            id,
+                            created_at: row.try_get("created_at")?,
+                            last_used_at: row.try_get("last_used_at")?,
+                            expires_at: row.try_get("expires_at")?,
+                            user_agent: row.try_get("user_agent")?,
+                            ip: row.try_get("ip")?,
+                            current,
+                        })
+                    })
+                    .collect();
+
+                Ok(sessions?)
+            }
+        }
+    };
+}
+
+sql_session_repository!(PgSessionRepository, PgPool);
+#[cfg(feature = "sqlite")]
+sql_session_repository!(SqliteSessionRepository, SqlitePool);
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct MemorySession {
+    uid: i32,
+    token_hash: String,
+    created_at: i64,
+    last_used_at: i64,
+    expires_at: i64,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+// Keeps sessions in memory, for running the auth logic without a database.
+// Clones share the same sessions.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MemorySessionRepository {
+    sessions: Arc<Mutex<BTreeMap<String, MemorySession>>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl SessionRepository for MemorySessionRepository {
+    async fn create(
+        &self,
+        sid: &str,
+        uid: i32,
+        token_hash: &str,
+        client: &ClientInfo,
+    ) -> Result<(), AuthError> {
+        let now = current_timestamp() as i64;
+        let session = MemorySession {
+            uid,
+            token_hash: token_hash.to_string(),
+            created_at: now,
+            last_used_at: now,
+            expires_at: now + REFRESH_TOKEN_EXPIRATION_SECS as i64,
+            user_agent: client.user_agent.clone(),
+            ip: client.ip.clone(),
+        };
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(sid.to_string(), session);
+
+        Ok(())
+    }
+
+    async fn rotate(
+        &self,
+        sid: &str,
+        uid: i32,
+        current_hash: &str,
+        new_hash: &str,
+    ) -> Result<bool, AuthError> {
+        let now = current_timestamp() as i64;
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match sessions.get_mut(sid) {
+            Some(session)
+                if session.uid == uid
+                    && session.token_hash == current_hash
+                    && session.expires_at > now =>
+            {
+                session.token_hash = new_hash.to_string();
+                session.last_used_at = now;
+                session.expires_at = now + REFRESH_TOKEN_EXPIRATION_SECS as i64;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn delete(&self, sid: &str, uid: i32) -> Result<bool, AuthError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(sid) {
+            Some(session) if session.uid == uid => Ok(sessions.remove(sid).is_some()),
+            _ => Ok(false),
+        }
+    }
+
+    async fn delete_for_user(&self, uid: i32) -> Result<(), AuthError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.uid != uid);
+
+        Ok(())
+    }
+
+    async fn delete_others(&self, uid: i32, current_sid: &str) -> Result<(), AuthError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|sid, session| session.uid != uid || sid == current_sid);
+
+        Ok(())
+    }
+
+    async fn list(&self, uid: i32, current_sid: &str) -> Result<Vec<Session>, AuthError> {
+        let now = current_timestamp() as i64;
+        let sessions = self.sessions.lock().unwrap();
+
+        let mut list: Vec<Session> = sessions
+            .iter()
+            .filter(|(_, session)| session.uid == uid && session.expires_at > now)
+            .map(|(sid, session)| Session {
+                id: sid.clone(),
+                created_at: session.created_at,
+                last_used_at: session.last_used_at,
+                expires_at: session.expires_at,
+                user_agent: session.user_agent.clone(),
+                ip: session.ip.clone(),
+                current: sid == current_sid,
+            })
+            .collect();
+        list.sort_by_key(|session| std::cmp::Reverse(session.last_used_at));
+
+        Ok(list)
+    }
+}
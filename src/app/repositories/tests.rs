@@ -0,0 +1,449 @@
+// The same checks run against every backend, so Postgres, SQLite and the
+// in-memory repositories behave alike. The Postgres checks are ignored by
+// default, they run with `cargo test -- --ignored` once TEST_DATABASE_URL
+// points to a database that may be written to.
+use sqlx::postgres::PgPool;
+
+#[cfg(feature = "sqlite")]
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+#[cfg(feature = "sqlite")]
+use std::str::FromStr;
+
+use crate::{
+    app::{
+        models::session::ClientInfo,
+        services::{error::AuthError, utils::generate_id},
+    },
+    db::{migrate::migrate_up, DatabasePool},
+};
+
+use super::{
+    session::{MemorySessionRepository, PgSessionRepository, SessionRepository},
+    user::{MemoryUserRepository, PgUserRepository, UserRepository},
+};
+
+#[cfg(feature = "sqlite")]
+use super::{session::SqliteSessionRepository, user::SqliteUserRepository};
+
+type Repositories = (Box<dyn UserRepository>, Box<dyn SessionRepository>);
+
+async fn memory() -> Repositories {
+    (
+        Box::new(MemoryUserRepository::default()),
+        Box::new(MemorySessionRepository::default()),
+    )
+}
+
+// Every connection to `sqlite::memory:` opens a database of its own, so the
+// pool keeps to one
+#[cfg(feature = "sqlite")]
+async fn sqlite() -> Repositories {
+    let options = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .foreign_keys(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .unwrap();
+    migrate_up(&DatabasePool::Sqlite(pool.clone()), false)
+        .await
+        .unwrap();
+
+    (
+        Box::new(SqliteUserRepository::new(pool.clone())),
+        Box::new(SqliteSessionRepository::new(pool)),
+    )
+}
+
+async fn postgres() -> Repositories {
+    let url = std::env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must point to a Postgres database the tests may write to");
+    let pool = PgPool::connect(&url).await.unwrap();
+    migrate_up(&DatabasePool::Postgres(pool.clone()), false)
+        .await
+        .unwrap();
+
+    (
+        Box::new(PgUserRepository::new(pool.clone())),
+        Box::new(PgSessionRepository::new(pool)),
+    )
+}
+
+// A Postgres database is shared between runs, so names and session IDs are
+// made unique
+fn unique(name: &str) -> String {
+    format!("{}-{}", name, &generate_id()[..8])
+}
+
+async fn users_are_found_by_id_and_username(users: &dyn UserRepository) {
+    let username = unique("Found");
+    let created = users
+        .create(&username, "hash", Some("found@example.com"))
+        .await
+        .unwrap();
+
+    assert_eq!(created.username, username);
+    assert_eq!(created.roles, vec![String::from("user")]);
+    assert!(!created.totp_enabled);
+
+    let by_id = users.find_by_id(created.id).await.unwrap().unwrap();
+    assert_eq!(by_id.username, username);
+    assert_eq!(by_id.email.as_deref(), Some("found@example.com"));
+
+    // Usernames are looked up without regard to case
+    let by_username = users
+        .find_by_username(&username.to_uppercase())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(by_username.id, created.id);
+
+    assert!(users
+        .find_by_username(&unique("missing"))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+async fn usernames_are_unique(users: &dyn UserRepository) {
+    let username = unique("taken");
+    users.create(&username, "hash", None).await.unwrap();
+
+    let result = users.create(&username.to_uppercase(), "hash", None).await;
+    assert!(matches!(result, Err(AuthError::UsernameTaken)));
+
+    let other = users.create(&unique("other"), "hash", None).await.unwrap();
+    let result = users.update_profile(other.id, Some(&username), None).await;
+    assert!(matches!(result, Err(AuthError::UsernameTaken)));
+}
+
+async fn profiles_are_updated(users: &dyn UserRepository) {
+    let user = users
+        .create(&unique("profile"), "hash", None)
+        .await
+        .unwrap();
+    let renamed = unique("renamed");
+
+    users
+        .update_profile(user.id, Some(&renamed), Some(Some("new@example.com")))
+        .await
+        .unwrap();
+    let updated = users.find_by_id(user.id).await.unwrap().unwrap();
+    assert_eq!(updated.username, renamed);
+    assert_eq!(updated.email.as_deref(), Some("new@example.com"));
+
+    // Leaving the email out keeps it, `Some(None)` clears it
+    users.update_profile(user.id, None, None).await.unwrap();
+    let kept = users.find_by_id(user.id).await.unwrap().unwrap();
+    assert_eq!(kept.email.as_deref(), Some("new@example.com"));
+
+    users
+        .update_profile(user.id, None, Some(None))
+        .await
+        .unwrap();
+    let cleared = users.find_by_id(user.id).await.unwrap().unwrap();
+    assert_eq!(cleared.email, None);
+}
+
+async fn passwords_are_replaced_only_when_unchanged(users: &dyn UserRepository) {
+    let user = users
+        .create(&unique("password"), "first", None)
+        .await
+        .unwrap();
+
+    users.set_password(user.id, "second").await.unwrap();
+    assert!(!users
+        .replace_password(user.id, "first", "third")
+        .await
+        .unwrap());
+    assert!(users
+        .replace_password(user.id, "second", "third")
+        .await
+        .unwrap());
+
+    let stored = users.find_by_id(user.id).await.unwrap().unwrap();
+    assert_eq!(stored.password, "third");
+}
+
+async fn totp_steps_only_move_forward(users: &dyn UserRepository) {
+    let user = users.create(&unique("totp"), "hash", None).await.unwrap();
+
+    assert!(users.advance_totp_step(user.id, 5).await.unwrap());
+    assert!(!users.advance_totp_step(user.id, 5).await.unwrap());
+    assert!(!users.advance_totp_step(user.id, 4).await.unwrap());
+    assert!(users.advance_totp_step(user.id, 6).await.unwrap());
+
+    // Recovery codes only count for accounts with a second factor
+    assert!(!users.consume_recovery_code(user.id, "code").await.unwrap());
+}
+
+async fn totp_is_enabled_for_the_secret_being_enrolled(users: &dyn UserRepository) {
+    let user = users.create(&unique("enroll"), "hash", None).await.unwrap();
+    let codes = vec![String::from("first"), String::from("second")];
+
+    users.set_totp_secret(user.id, "FIRST").await.unwrap();
+    users.set_totp_secret(user.id, "SECOND").await.unwrap();
+
+    // A setup restarted in the meantime replaced the secret
+    assert!(!users
+        .enable_totp(user.id, "FIRST", 10, &codes)
+        .await
+        .unwrap());
+    assert!(users
+        .enable_totp(user.id, "SECOND", 10, &codes)
+        .await
+        .unwrap());
+    assert!(!users
+        .enable_totp(user.id, "SECOND", 11, &codes)
+        .await
+        .unwrap());
+
+    let enabled = users.find_by_id(user.id).await.unwrap().unwrap();
+    assert!(enabled.totp_enabled);
+    assert_eq!(enabled.totp_secret.as_deref(), Some("SECOND"));
+    assert!(!users.advance_totp_step(user.id, 10).await.unwrap());
+    assert!(users.consume_recovery_code(user.id, "first").await.unwrap());
+    assert!(!users.consume_recovery_code(user.id, "first").await.unwrap());
+
+    users.disable_totp(user.id).await.unwrap();
+    let disabled = users.find_by_id(user.id).await.unwrap().unwrap();
+    assert!(!disabled.totp_enabled);
+    assert_eq!(disabled.totp_secret, None);
+    assert!(!users
+        .consume_recovery_code(user.id, "second")
+        .await
+        .unwrap());
+}
+
+async fn roles_are_added_once(users: &dyn UserRepository) {
+    let user = users.create(&unique("roles"), "hash", None).await.unwrap();
+
+    users.add_role(user.id, "admin").await.unwrap();
+    users.add_role(user.id, "admin").await.unwrap();
+
+    let granted = users.find_by_id(user.id).await.unwrap().unwrap();
+    assert_eq!(
+        granted.roles,
+        vec![String::from("user"), String::from("admin")]
+    );
+}
+
+async fn users_are_deleted(users: &dyn UserRepository) {
+    let user = users
+        .create(&unique("deleted"), "hash", None)
+        .await
+        .unwrap();
+
+    users.delete(user.id).await.unwrap();
+
+    assert!(users.find_by_id(user.id).await.unwrap().is_none());
+}
+
+async fn sessions_rotate_from_the_current_hash(
+    users: &dyn UserRepository,
+    sessions: &dyn SessionRepository,
+) {
+    let user = users.create(&unique("rotate"), "hash", None).await.unwrap();
+    let other = users.create(&unique("other"), "hash", None).await.unwrap();
+    let sid = generate_id();
+    sessions
+        .create(&sid, user.id, "first", &ClientInfo::default())
+        .await
+        .unwrap();
+
+    assert!(!sessions
+        .rotate(&sid, user.id, "wrong", "second")
+        .await
+        .unwrap());
+    assert!(!sessions
+        .rotate(&sid, other.id, "first", "second")
+        .await
+        .unwrap());
+    assert!(sessions
+        .rotate(&sid, user.id, "first", "second")
+        .await
+        .unwrap());
+
+    // The hash it replaced is no longer the current one
+    assert!(!sessions
+        .rotate(&sid, user.id, "first", "third")
+        .await
+        .unwrap());
+    assert!(sessions
+        .rotate(&sid, user.id, "second", "third")
+        .await
+        .unwrap());
+}
+
+async fn sessions_are_listed_and_deleted(
+    users: &dyn UserRepository,
+    sessions: &dyn SessionRepository,
+) {
+    let user = users
+        .create(&unique("sessions"), "hash", None)
+        .await
+        .unwrap();
+    let other = users.create(&unique("other"), "hash", None).await.unwrap();
+    let client = ClientInfo::new(Some(String::from("tests")), Some(String::from("127.0.0.1")));
+    let sids: Vec<String> = (0..3).map(|_| generate_id()).collect();
+    for sid in &sids {
+        sessions
+            .create(sid, user.id, &generate_id(), &client)
+            .await
+            .unwrap();
+    }
+
+    let listed = sessions.list(user.id, &sids[1]).await.unwrap();
+    assert_eq!(listed.len(), 3);
+    for session in &listed {
+        assert_eq!(session.current, session.id == sids[1]);
+        assert_eq!(session.user_agent.as_deref(), Some("tests"));
+        assert_eq!(session.ip.as_deref(), Some("127.0.0.1"));
+    }
+    assert!(sessions.list(other.id, "").await.unwrap().is_empty());
+
+    // A session is only deleted for the user it belongs to
+    assert!(!sessions.delete(&sids[0], other.id).await.unwrap());
+    assert!(sessions.delete(&sids[0], user.id).await.unwrap());
+    assert!(!sessions.delete(&sids[0], user.id).await.unwrap());
+
+    sessions.delete_others(user.id, &sids[1]).await.unwrap();
+    let listed = sessions.list(user.id, &sids[1]).await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].id, sids[1]);
+
+    sessions.delete_for_user(user.id).await.unwrap();
+    assert!(sessions.list(user.id, "").await.unwrap().is_empty());
+}
+
+// One module per backend, each running every check, with the attributes given
+// on every test
+macro_rules! repository_tests {
+    ($backend:ident $(, #[$attribute:meta])*) => {
+        mod $backend {
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn users_are_found_by_id_and_username() {
+                let (users, _) = super::$backend().await;
+                super::users_are_found_by_id_and_username(&users).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn usernames_are_unique() {
+                let (users, _) = super::$backend().await;
+                super::usernames_are_unique(&users).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn profiles_are_updated() {
+                let (users, _) = super::$backend().await;
+                super::profiles_are_updated(&users).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn passwords_are_replaced_only_when_unchanged() {
+                let (users, _) = super::$backend().await;
+                super::passwords_are_replaced_only_when_unchanged(&users).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn totp_steps_only_move_forward() {
+                let (users, _) = super::$backend().await;
+                super::totp_steps_only_move_forward(&users).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn totp_is_enabled_for_the_secret_being_enrolled() {
+                let (users, _) = super::$backend().await;
+                super::totp_is_enabled_for_the_secret_being_enrolled(&users).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn roles_are_added_once() {
+                let (users, _) = super::$backend().await;
+                super::roles_are_added_once(&users).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn users_are_deleted() {
+                let (users, _) = super::$backend().await;
+                super::users_are_deleted(&users).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn sessions_rotate_from_the_current_hash() {
+                let (users, sessions) = super::$backend().await;
+                super::sessions_rotate_from_the_current_hash(&users, &sessions).await;
+            }
+
+            #[tokio::test]
+            $(#[$attribute])*
+            async fn sessions_are_listed_and_deleted() {
+                let (users, sessions) = super::$backend().await;
+                super::sessions_are_listed_and_deleted(&users, &sessions).await;
+            }
+        }
+    };
+}
+
+repository_tests!(memory);
+#[cfg(feature = "sqlite")]
+repository_tests!(sqlite);
+repository_tests!(
+    postgres,
+    #[ignore = "needs TEST_DATABASE_URL, run with `cargo test -- --ignored`"]
+);
+
+// Reset links are only kept in Postgres
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL, run with `cargo test -- --ignored`"]
+async fn reset_tokens_set_the_password_once() {
+    let url = std::env::var("TEST_DATABASE_URL").unwrap();
+    let pool = PgPool::connect(&url).await.unwrap();
+    migrate_up(&DatabasePool::Postgres(pool.clone()), false)
+        .await
+        .unwrap();
+    let users = PgUserRepository::new(pool.clone());
+    let user = users.create(&unique("reset"), "old", None).await.unwrap();
+    let token_hash = generate_id();
+
+    let query = "INSERT INTO password_resets (token_hash, user_id, created_at, expires_at) VALUES ($1, $2, 0, 100)";
+    sqlx::query(query)
+        .bind(&token_hash)
+        .bind(user.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // Expired by then
+    assert_eq!(
+        users
+            .reset_password(&token_hash, 100, "late")
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        users.reset_password(&token_hash, 50, "new").await.unwrap(),
+        Some(user.id)
+    );
+    assert_eq!(
+        users
+            .reset_password(&token_hash, 60, "again")
+            .await
+            .unwrap(),
+        None
+    );
+
+    let stored = users.find_by_id(user.id).await.unwrap().unwrap();
+    assert_eq!(stored.password, "new");
+}
@@ -0,0 +1,686 @@
+use async_trait::async_trait;
+use sqlx::{postgres::PgPool, postgres::PgRow, Row};
+#[cfg(test)]
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "sqlite")]
+use sqlx::sqlite::{SqlitePool, SqliteRow};
+
+use crate::{
+    app::services::{
+        error::{is_unique_violation, AuthError},
+        validation::username_key,
+    },
+    db::DatabasePool,
+};
+
+// A user as it is stored, password hash included. Never sent to a client.
+#[derive(Debug, Clone)]
+pub struct UserRecord {
+    pub id: i32,
+    pub username: String,
+    pub password: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    pub totp_enabled: bool,
+    pub totp_secret: Option<String>,
+}
+
+// Where users are kept. The auth logic only reaches users through this, so it
+// runs the same against Postgres and the in-memory repository.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn find_by_id(&self, uid: i32) -> Result<Option<UserRecord>, AuthError>;
+
+    // Matched on the normalized name, so `Alice` finds `alice`
+    async fn find_by_username(&self, username: &str) -> Result<Option<UserRecord>, AuthError>;
+
+    // Fails with `UsernameTaken` when the normalized name is in use
+    async fn create(
+        &self,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> Result<UserRecord, AuthError>;
+
+    // Changes the fields that are given, `email` set to `Some(None)` removes the address
+    async fn update_profile(
+        &self,
+        uid: i32,
+        username: Option<&str>,
+        email: Option<Option<&str>>,
+    ) -> Result<(), AuthError>;
+
+    async fn set_password(&self, uid: i32, password: &str) -> Result<(), AuthError>;
+
+    // Only swaps the hash if it is still `current`, returns whether it did
+    async fn replace_password(
+        &self,
+        uid: i32,
+        current: &str,
+        password: &str,
+    ) -> Result<bool, AuthError>;
+
+    async fn delete(&self, uid: i32) -> Result<(), AuthError>;
+
+    // Remembers the TOTP time step a code was accepted for. Refuses steps that are
+    // not newer than the last one, so a code cannot be replayed.
+    async fn advance_totp_step(&self, uid: i32, step: i64) -> Result<bool, AuthError>;
+
+    // Removes a recovery code of an account with TOTP enabled, returns whether it had it
+    async fn consume_recovery_code(&self, uid: i32, code_hash: &str) -> Result<bool, AuthError>;
+
+    // Stores the secret of an enrollment that still has to be confirmed
+    async fn set_totp_secret(&self, uid: i32, secret: &str) -> Result<(), AuthError>;
+
+    // Turns TOTP on with the recovery codes given as hashes. Only if `secret` is
+    // still the one being enrolled, returns whether it did.
+    async fn enable_totp(
+        &self,
+        uid: i32,
+        secret: &str,
+        step: i64,
+        recovery_codes: &[String],
+    ) -> Result<bool, AuthError>;
+
+    async fn disable_totp(&self, uid: i32) -> Result<(), AuthError>;
+
+    // Adds the role unless the user already has it
+    async fn add_role(&self, uid: i32, role: &str) -> Result<(), AuthError>;
+}
+
+// Lets the repository be picked at runtime, see `AuthService::new`
+#[async_trait]
+impl<T: UserRepository + ?Sized> UserRepository for Box<T> {
+    async fn find_by_id(&self, uid: i32) -> Result<Option<UserRecord>, AuthError> {
+        (**self).find_by_id(uid).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<UserRecord>, AuthError> {
+        (**self).find_by_username(username).await
+    }
+
+    async fn create(
+        &self,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> Result<UserRecord, AuthError> {
+        (**self).create(username, password, email).await
+    }
+
+    async fn update_profile(
+        &self,
+        uid: i32,
+        username: Option<&str>,
+        email: Option<Option<&str>>,
+    ) -> Result<(), AuthError> {
+        (**self).update_profile(uid, username, email).await
+    }
+
+    async fn set_password(&self, uid: i32, password: &str) -> Result<(), AuthError> {
+        (**self).set_password(uid, password).await
+    }
+
+    async fn replace_password(
+        &self,
+        uid: i32,
+        current: &str,
+        password: &str,
+    ) -> Result<bool, AuthError> {
+        (**self).replace_password(uid, current, password).await
+    }
+
+    async fn delete(&self, uid: i32) -> Result<(), AuthError> {
+        (**self).delete(uid).await
+    }
+
+    async fn advance_totp_step(&self, uid: i32, step: i64) -> Result<bool, AuthError> {
+        (**self).advance_totp_step(uid, step).await
+    }
+
+    async fn consume_recovery_code(&self, uid: i32, code_hash: &str) -> Result<bool, AuthError> {
+        (**self).consume_recovery_code(uid, code_hash).await
+    }
+
+    async fn set_totp_secret(&self, uid: i32, secret: &str) -> Result<(), AuthError> {
+        (**self).set_totp_secret(uid, secret).await
+    }
+
+    async fn enable_totp(
+        &self,
+        uid: i32,
+        secret: &str,
+        step: i64,
+        recovery_codes: &[String],
+    ) -> Result<bool, AuthError> {
+        (**self)
+            .enable_totp(uid, secret, step, recovery_codes)
+            .await
+    }
+
+    async fn disable_totp(&self, uid: i32) -> Result<(), AuthError> {
+        (**self).disable_totp(uid).await
+    }
+
+    async fn add_role(&self, uid: i32, role: &str) -> Result<(), AuthError> {
+        (**self).add_role(uid, role).await
+    }
+}
+
+// The repository for the database DATABASE_URL points to
+pub fn user_repository(db: &DatabasePool) -> Box<dyn UserRepository> {
+    match db.clone() {
+        DatabasePool::Postgres(pool) => Box::new(PgUserRepository::new(pool)),
+        #[cfg(feature = "sqlite")]
+        DatabasePool::Sqlite(pool) => Box::new(SqliteUserRepository::new(pool)),
+    }
+}
+
+fn pg_user_record(row: &PgRow) -> Result<UserRecord, sqlx::Error> {
+    Ok(UserRecord {
+        id: row.try_get("id")?,
+        username: row.try_get("username")?,
+        password: row.try_get("password")?,
+        email: row.try_get("email")?,
+        roles: row.try_get("roles")?,
+        totp_enabled: row.try_get("totp_enabled")?,
+        totp_secret: row.try_get("totp_secret")?,
+    })
+}
+
+fn pg_recovery_codes(recovery_codes: &[String]) -> Result<Vec<String>, sqlx::Error> {
+    Ok(recovery_codes.to_vec())
+}
+
+// Roles and recovery codes are JSON arrays in SQLite
+#[cfg(feature = "sqlite")]
+fn sqlite_user_record(row: &SqliteRow) -> Result<UserRecord, sqlx::Error> {
+    let roles: String = row.try_get("roles")?;
+
+    Ok(UserRecord {
+        id: row.try_get("id")?,
+        username: row.try_get("username")?,
+        password: row.try_get("password")?,
+        email: row.try_get("email")?,
+        roles: serde_json::from_str(&roles)
+            .map_err(|error| sqlx::Error::Decode(Box::new(error)))?,
+        totp_enabled: row.try_get("totp_enabled")?,
+        totp_secret: row.try_get("totp_secret")?,
+    })
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_recovery_codes(recovery_codes: &[String]) -> Result<String, sqlx::Error> {
+    serde_json::to_string(recovery_codes).map_err(|error| sqlx::Error::Protocol(error.to_string()))
+}
+
+fn unique_violation_as_taken(error: sqlx::Error) -> AuthError {
+    match is_unique_violation(&error) {
+        true => AuthError::UsernameTaken,
+        false => error.into(),
+    }
+}
+
+const USER_COLUMNS: &str = "id, username, password, email, roles, totp_enabled, totp_secret";
+
+// The queries are the same on Postgres and SQLite but for how roles and
+// recovery codes are kept, arrays in Postgres and JSON text in SQLite
+macro_rules! sql_user_repository {
+    (
+        $name:ident,
+        $pool:ty,
+        record: $record:ident,
+        recovery_codes: $recovery_codes:ident,
+        consume_recovery_code: $consume_recovery_code:expr,
+        no_recovery_codes: $no_recovery_codes:expr,
+        add_role: $add_role:expr $(,)?
+    ) => {
+        pub struct $name {
+            pool: $pool,
+        }
+
+        impl $name {
+            pub fn new(pool: $pool) -> Self {
+                $name { pool }
+            }
+        }
+
+        #[async_trait]
+        impl UserRepository for $name {
+            async fn find_by_id(&self, uid: i32) -> Result<Option<UserRecord>, AuthError> {
+                let query = format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS);
+                let result = sqlx::query(&query)
+                    .bind(uid)
+                    .fetch_optional(&self.pool)
+                    .await?;
+
+                Ok(result.as_ref().map($record).transpose()?)
+            }
+
+            async fn find_by_username(&self, username: &str) -> Result<Option<UserRecord>, AuthError> {
+                let query = format!("SELECT {} FROM users WHERE username_key = $1", USER_COLUMNS);
+                let result = sqlx::query(&query)
+                    .bind(username_key(username))
+                    .fetch_optional(&self.pool)
+                    .await?;
+
+                Ok(result.as_ref().map($record).transpose()?)
+            }
+
+            async fn create(
+                &self,
+                username: &str,
+                password: &str,
+                email: Option<&str>,
+            ) -> Result<UserRecord, AuthError> {
+                let query = format!("INSERT INTO users (username, username_key, password, email) VALUES ($1, $2, $3, $4) RETURNING {}", USER_COLUMNS);
+                let row = sqlx::query(&query)
+                    .bind(username)
+                    .bind(username_key(username))
+                    .bind(password)
+                    .bind(email)
+                    .fetch_one(&self.pool)
+                    .await
+                    .map_err(unique_violation_as_taken)?;
+
+                Ok($record(&row)?)
+            }
+
+            async fn update_profile(
+                &self,
+                uid: i32,
+                username: Option<&str>,
+                email: Option<Option<&str>>,
+            ) -> Result<(), AuthError> {
+                let query = "UPDATE users SET username = COALESCE($1, username), username_key = COALESCE($2, username_key), email = CASE WHEN $3 THEN $4 ELSE email END WHERE id = $5";
+                sqlx::query(query)
+                    .bind(username)
+                    .bind(username.map(username_key))
+                    .bind(email.is_some())
+                    .bind(email.flatten())
+                    .bind(uid)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(unique_violation_as_taken)?;
+
+                Ok(())
+            }
+
+            async fn set_password(&self, uid: i32, password: &str) -> Result<(), AuthError> {
+                let query = "UPDATE users SET password = $1 WHERE id = $2";
+                sqlx::query(query)
+                    .bind(password)
+                    .bind(uid)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(())
+            }
+
+            async fn replace_password(
+                &self,
+                uid: i32,
+                current: &str,
+                password: &str,
+            ) -> Result<bool, AuthError> {
+                let query = "UPDATE users SET password = $1 WHERE id = $2 AND password = $3";
+                let result = sqlx::query(query)
+                    .bind(password)
+                    .bind(uid)
+                    .bind(current)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(result.rows_affected() > 0)
+            }
+
+            // Sessions, and on Postgres API keys and reset links, go with the user
+            // through their foreign keys
+            async fn delete(&self, uid: i32) -> Result<(), AuthError> {
+                let query = "DELETE FROM users WHERE id = $1";
+                sqlx::query(query).bind(uid).execute(&self.pool).await?;
+
+                Ok(())
+            }
+
+            async fn advance_totp_step(&self, uid: i32, step: i64) -> Result<bool, AuthError> {
+                let query = "UPDATE users SET totp_last_step = $1 WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)";
+                let result = sqlx::query(query)
+                    .bind(step)
+                    .bind(uid)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn consume_recovery_code(&self, uid: i32, code_hash: &str) -> Result<bool, AuthError> {
+                let result = sqlx::query($consume_recovery_code)
+                    .bind(code_hash)
+                    .bind(uid)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn set_totp_secret(&self, uid: i32, secret: &str) -> Result<(), AuthError> {
+                let query = "UPDATE users SET totp_secret = $1, totp_last_step = NULL WHERE id = $2";
+                sqlx::query(query)
+                    .bind(secret)
+                    .bind(uid)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(())
+            }
+
+            async fn enable_totp(
+                &self,
+                uid: i32,
+                secret: &str,
+                step: i64,
+                recovery_codes: &[String],
+            ) -> Result<bool, AuthError> {
+                let query = "UPDATE users SET totp_enabled = true, totp_last_step = $1, recovery_codes = $2 WHERE id = $3 AND totp_secret = $4 AND NOT totp_enabled";
+                let result = sqlx::query(query)
+                    .bind(step)
+                    .bind($recovery_codes(recovery_codes)?)
+                    .bind(uid)
+                    .bind(secret)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn disable_totp(&self, uid: i32) -> Result<(), AuthError> {
+                let query = format!("UPDATE users SET totp_enabled = false, totp_secret = NULL, totp_last_step = NULL, recovery_codes = {} WHERE id = $1", $no_recovery_codes);
+                sqlx::query(&query).bind(uid).execute(&self.pool).await?;
+
+                Ok(())
+            }
+
+            async fn add_role(&self, uid: i32, role: &str) -> Result<(), AuthError> {
+                sqlx::query($add_role)
+                    .bind(role)
+                    .bind(uid)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(())
+            }
+        }
+    };
+}
+
+sql_user_repository!(
+    PgUserRepository,
+    PgPool,
+    record: pg_user_record,
+    recovery_codes: pg_recovery_codes,
+    consume_recovery_code: "UPDATE users SET recovery_codes = array_remove(recovery_codes, $1) WHERE id = $2 AND totp_enabled AND $1 = ANY(recovery_codes)",
+    no_recovery_codes: "'{}'",
+    add_role: "UPDATE users SET roles = array_append(roles, $1) WHERE id = $2 AND NOT ($1 = ANY(roles))",
+);
+#[cfg(feature = "sqlite")]
+sql_user_repository!(
+    SqliteUserRepository,
+    SqlitePool,
+    record: sqlite_user_record,
+    recovery_codes: sqlite_recovery_codes,
+    consume_recovery_code: "UPDATE users SET recovery_codes = (SELECT json_group_array(value) FROM json_each(users.recovery_codes) WHERE value <> $1) WHERE id = $2 AND totp_enabled AND EXISTS (SELECT 1 FROM json_each(users.recovery_codes) WHERE value = $1)",
+    no_recovery_codes: "'[]'",
+    add_role: "UPDATE users SET roles = json_insert(roles, '$[#]', $1) WHERE id = $2 AND NOT EXISTS (SELECT 1 FROM json_each(users.roles) WHERE value = $1)",
+);
+
+// Reset links are only kept in Postgres
+impl PgUserRepository {
+    // Uses up an unexpired reset token and sets the password of its user in one
+    // transaction, so neither happens without the other. Returns the user's ID,
+    // or `None` when the token was not usable.
+    pub async fn reset_password(
+        &self,
+        token_hash: &str,
+        now: i64,
+        password: &str,
+    ) -> Result<Option<i32>, AuthError> {
+        let mut transaction = self.pool.begin().await?;
+
+        let query = "UPDATE password_resets SET used_at = $1 WHERE token_hash = $2 AND used_at IS NULL AND expires_at > $1 RETURNING user_id";
+        let uid: i32 = match sqlx::query(query)
+            .bind(now)
+            .bind(token_hash)
+            .fetch_optional(&mut *transaction)
+            .await?
+        {
+            Some(row) => row.try_get("user_id")?,
+            None => return Ok(None),
+        };
+
+        let query = "UPDATE users SET password = $1 WHERE id = $2";
+        sqlx::query(query)
+            .bind(password)
+            .bind(uid)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(Some(uid))
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct MemoryUser {
+    record: UserRecord,
+    totp_last_step: Option<i64>,
+    recovery_codes: Vec<String>,
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct MemoryUsers {
+    last_id: i32,
+    users: BTreeMap<i32, MemoryUser>,
+}
+
+#[cfg(test)]
+impl MemoryUsers {
+    fn is_taken(&self, username: &str, except: Option<i32>) -> bool {
+        let key = username_key(username);
+        self.users.values().any(|user| {
+            Some(user.record.id) != except && username_key(&user.record.username) == key
+        })
+    }
+}
+
+// Keeps users in memory, for running the auth logic without a database. Clones
+// share the same users.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MemoryUserRepository {
+    state: Arc<Mutex<MemoryUsers>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl UserRepository for MemoryUserRepository {
+    async fn find_by_id(&self, uid: i32) -> Result<Option<UserRecord>, AuthError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.users.get(&uid).map(|user| user.record.clone()))
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<UserRecord>, AuthError> {
+        let key = username_key(username);
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .users
+            .values()
+            .find(|user| username_key(&user.record.username) == key)
+            .map(|user| user.record.clone()))
+    }
+
+    async fn create(
+        &self,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> Result<UserRecord, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_taken(username, None) {
+            return Err(AuthError::UsernameTaken);
+        }
+
+        state.last_id += 1;
+        let record = UserRecord {
+            id: state.last_id,
+            username: username.to_string(),
+            password: password.to_string(),
+            email: email.map(String::from),
+            roles: vec![String::from("user")],
+            totp_enabled: false,
+            totp_secret: None,
+        };
+        state.users.insert(
+            record.id,
+            MemoryUser {
+                record: record.clone(),
+                totp_last_step: None,
+                recovery_codes: Vec::new(),
+            },
+        );
+
+        Ok(record)
+    }
+
+    async fn update_profile(
+        &self,
+        uid: i32,
+        username: Option<&str>,
+        email: Option<Option<&str>>,
+    ) -> Result<(), AuthError> {
+        let mut state = self.state.lock().unwrap();
+        if username.is_some_and(|username| state.is_taken(username, Some(uid))) {
+            return Err(AuthError::UsernameTaken);
+        }
+
+        if let Some(user) = state.users.get_mut(&uid) {
+            if let Some(username) = username {
+                user.record.username = username.to_string();
+            }
+            if let Some(email) = email {
+                user.record.email = email.map(String::from);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn set_password(&self, uid: i32, password: &str) -> Result<(), AuthError> {
+        if let Some(user) = self.state.lock().unwrap().users.get_mut(&uid) {
+            user.record.password = password.to_string();
+        }
+
+        Ok(())
+    }
+
+    async fn replace_password(
+        &self,
+        uid: i32,
+        current: &str,
+        password: &str,
+    ) -> Result<bool, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        match state.users.get_mut(&uid) {
+            Some(user) if user.record.password == current => {
+                user.record.password = password.to_string();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn delete(&self, uid: i32) -> Result<(), AuthError> {
+        self.state.lock().unwrap().users.remove(&uid);
+        Ok(())
+    }
+
+    async fn advance_totp_step(&self, uid: i32, step: i64) -> Result<bool, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        match state.users.get_mut(&uid) {
+            Some(user) if user.totp_last_step.is_none_or(|last| last < step) => {
+                user.totp_last_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn consume_recovery_code(&self, uid: i32, code_hash: &str) -> Result<bool, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        let user = match state.users.get_mut(&uid) {
+            Some(user) if user.record.totp_enabled => user,
+            _ => return Ok(false),
+        };
+
+        let count = user.recovery_codes.len();
+        user.recovery_codes.retain(|code| code != code_hash);
+        Ok(user.recovery_codes.len() < count)
+    }
+    async fn set_totp_secret(&self, uid: i32, secret: &str) -> Result<(), AuthError> {
+        if let Some(user) = self.state.lock().unwrap().users.get_mut(&uid) {
+            user.record.totp_secret = Some(secret.to_string());
+            user.totp_last_step = None;
+        }
+
+        Ok(())
+    }
+
+    async fn enable_totp(
+        &self,
+        uid: i32,
+        secret: &str,
+        step: i64,
+        recovery_codes: &[String],
+    ) -> Result<bool, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        match state.users.get_mut(&uid) {
+            Some(user)
+                if !user.record.totp_enabled
+                    && user.record.totp_secret.as_deref() == Some(secret) =>
+            {
+                user.record.totp_enabled = true;
+                user.totp_last_step = Some(step);
+                user.recovery_codes = recovery_codes.to_vec();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn disable_totp(&self, uid: i32) -> Result<(), AuthError> {
+        if let Some(user) = self.state.lock().unwrap().users.get_mut(&uid) {
+            user.record.totp_enabled = false;
+            user.record.totp_secret = None;
+            user.totp_last_step = None;
+            user.recovery_codes.clear();
+        }
+
+        Ok(())
+    }
+    async fn add_role(&self, uid: i32, role: &str) -> Result<(), AuthError> {
+        if let Some(user) = self.state.lock().unwrap().users.get_mut(&uid) {
+            if !user.record.roles.iter().any(|existing| existing == role) {
+                user.record.roles.push(role.to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
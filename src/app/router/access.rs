@@ -0,0 +1,143 @@
+use crate::{
+    app::{
+        models::role::{Permission, Role},
+        state::AppState,
+    },
+    http::{
+        auth::{authenticate, AuthFailure},
+        request::Request,
+        utils::forbidden_response,
+    },
+};
+
+// What a caller needs to reach a route or a group of routes
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+    Public,
+    Authenticated,
+    Role(Role),
+    Permission(Permission),
+}
+
+// Checks the caller against a route's requirement, returning the response to
+// send back when it is not met: 401 without valid credentials, 403 without
+// the required role or permission
+pub fn authorize(state: &AppState, request: &Request, access: Access) -> Result<(), String> {
+    if let Access::Public = access {
+        return Ok(());
+    }
+
+    // API key callers are held to the key's scopes on top of their owner's roles
+    let is_allowed = match (&request.claims, &request.api_key) {
+        (Some(claims), _) => match access {
+            Access::Public | Access::Authenticated => true,
+            Access::Role(role) => claims.has_role(role),
+            Access::Permission(permission) => claims.has_permission(permission),
+        },
+        (None, Some(caller)) => match access {
+            Access::Public | Access::Authenticated => true,
+            Access::Role(role) => caller.has_role(role),
+            Access::Permission(permission) => caller.has_permission(permission),
+        },
+        (None, None) => {
+            let failure = authenticate(state, request)
+                .err()
+                .unwrap_or(AuthFailure::MissingCredentials);
+            return Err(failure.response());
+        }
+    };
+
+    if is_allowed {
+        Ok(())
+    } else {
+        Err(forbidden_response(
+            "You do not have permission to access this resource",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::models::api_key::ApiKeyCaller;
+
+    fn request_with_key(roles: &[&str], scopes: &[&str]) -> Request<'static> {
+        let mut request = Request::parse("GET /admin HTTP/1.1\r\n\r\n", None);
+        request.api_key = Some(ApiKeyCaller {
+            key_id: String::from("key"),
+            uid: 1,
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        });
+        request
+    }
+
+    fn status(result: Result<(), String>) -> u16 {
+        match result {
+            Ok(()) => 200,
+            Err(response) => response[9..12].parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_keys_without_scopes_are_not_admins() {
+        let state = AppState::for_tests();
+        let request = request_with_key(&["user", "admin"], &[]);
+
+        let result = authorize(&state, &request, Access::Role(Role::Admin));
+
+        assert_eq!(status(result), 403);
+    }
+
+    #[tokio::test]
+    async fn admin_keys_with_every_admin_scope_are_admins() {
+        let state = AppState::for_tests();
+        let request = request_with_key(&["admin"], &["users:read", "users:manage"]);
+
+        assert_eq!(
+            status(authorize(&state, &request, Access::Role(Role::Admin))),
+            200
+        );
+        assert_eq!(
+            status(authorize(&state, &request, Access::Role(Role::User))),
+            403
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_only_get_the_permissions_in_their_scopes() {
+        let state = AppState::for_tests();
+        let request = request_with_key(&["admin"], &["users:read"]);
+
+        assert_eq!(
+            status(authorize(
+                &state,
+                &request,
+                Access::Permission(Permission::ReadUsers)
+            )),
+            200
+        );
+        assert_eq!(
+            status(authorize(
+                &state,
+                &request,
+                Access::Permission(Permission::ManageUsers)
+            )),
+            403
+        );
+        assert_eq!(
+            status(authorize(&state, &request, Access::Role(Role::Admin))),
+            403
+        );
+    }
+
+    #[tokio::test]
+    async fn callers_without_credentials_get_401() {
+        let state = AppState::for_tests();
+        let request = Request::parse("GET /admin HTTP/1.1\r\n\r\n", None);
+
+        let result = authorize(&state, &request, Access::Authenticated);
+
+        assert_eq!(status(result), 401);
+    }
+}
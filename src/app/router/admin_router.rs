@@ -0,0 +1,91 @@
+use crate::{
+    app::{
+        handlers::admin_handler::{list_users, set_roles, unlock_user},
+        models::role::Permission,
+        state::AppState,
+    },
+    http::{request::Request, utils::not_found_response},
+};
+
+use super::access::{authorize, Access};
+
+enum AdminRoute {
+    ListUsers,
+    SetRoles(String),
+    Unlock(String),
+}
+
+pub struct AdminRouter {
+    state: AppState,
+}
+
+impl AdminRouter {
+    pub fn new(state: AppState) -> Self {
+        AdminRouter { state }
+    }
+
+    // The whole group already requires the admin role, routes narrow it down
+    // to the permission they need
+    pub async fn route(&self, request: &Request<'_>) -> String {
+        let path = request.uri.as_str();
+
+        if !path.starts_with("/admin") {
+            return not_found_response();
+        }
+
+        let user_id = |suffix: &str| {
+            path.trim_start_matches("/admin/users/")
+                .trim_end_matches(suffix)
+                .to_string()
+        };
+
+        let (access, route) = match (request.method.as_str(), path) {
+            ("GET", "/admin/users") => (
+                Access::Permission(Permission::ReadUsers),
+                AdminRoute::ListUsers,
+            ),
+            ("PUT", _) if path.starts_with("/admin/users/") && path.ends_with("/roles") => (
+                Access::Permission(Permission::ManageUsers),
+                AdminRoute::SetRoles(user_id("/roles")),
+            ),
+            ("POST", _) if path.starts_with("/admin/users/") && path.ends_with("/unlock") => (
+                Access::Permission(Permission::ManageUsers),
+                AdminRoute::Unlock(user_id("/unlock")),
+            ),
+            _ => return not_found_response(),
+        };
+
+        if let Err(response) = authorize(&self.state, request, access) {
+            return response;
+        }
+
+        match route {
+            AdminRoute::ListUsers => list_users(&self.state).await,
+            AdminRoute::SetRoles(user_id) => set_roles(&self.state, request, &user_id).await,
+            AdminRoute::Unlock(user_id) => unlock_user(&self.state, request, &user_id).await,
+        }
+    }
+
+    // Methods `route` accepts for a path, used to answer preflight requests
+    pub fn methods(&self, path: &str) -> &'static [&'static str] {
+        match path {
+            "/admin/users" => &["GET"],
+            _ if path.starts_with("/admin/users/") && path.ends_with("/roles") => &["PUT"],
+            _ if path.starts_with("/admin/users/") && path.ends_with("/unlock") => &["POST"],
+            _ => &[],
+        }
+    }
+
+    // `path` with the user ID replaced by a placeholder
+    pub fn template<'a>(&self, path: &'a str) -> &'a str {
+        if !path.starts_with("/admin/users/") {
+            path
+        } else if path.ends_with("/roles") {
+            "/admin/users/:id/roles"
+        } else if path.ends_with("/unlock") {
+            "/admin/users/:id/unlock"
+        } else {
+            path
+        }
+    }
+}
@@ -1,59 +1,145 @@
 use std::sync::mpsc;
 
-use crate::{app::handlers::test_handler::test_api, http::utils::not_found_response};
+use crate::{
+    app::{handlers::test_handler::test_api, models::role::Role, state::AppState},
+    http::{
+        request::Request,
+        utils::{needs_postgres_response, not_found_response},
+    },
+};
 
-use super::{another_router::AnotherRouter, auth_router::AuthRouter, test_router::TestRouter};
+use super::{
+    access::{authorize, Access},
+    admin_router::AdminRouter,
+    another_router::AnotherRouter,
+    auth_router::AuthRouter,
+    health_router::HealthRouter,
+    metrics_router::MetricsRouter,
+    test_router::TestRouter,
+    well_known_router::WellKnownRouter,
+};
+
+// NOTE: Set the access requirement of new route groups here, individual
+// routes can narrow it down further in their own router
+fn group_access(prefix: &str) -> Access {
+    match prefix {
+        // `/metrics` checks METRICS_TOKEN itself, scrapers have no user
+        "auth" | ".well-known" | "healthz" | "readyz" | "metrics" => Access::Public,
+        "admin" => Access::Role(Role::Admin),
+        _ => Access::Authenticated,
+    }
+}
+
+// Paths of the features that are only kept in Postgres. A SQLite database keeps
+// users and sessions, so on SQLite these answer 501 instead of failing.
+pub const POSTGRES_ONLY: [(&str, &str); 3] = [
+    ("/admin", "Admin routes"),
+    ("/auth/api-keys", "API keys"),
+    ("/auth/password/", "Password resets"),
+];
+
+fn postgres_feature(path: &str) -> Option<&'static str> {
+    POSTGRES_ONLY
+        .iter()
+        .find(|(prefix, _)| path.starts_with(prefix))
+        .map(|(_, feature)| *feature)
+}
 
 pub struct Router {
     sender: mpsc::Sender<String>,
+    state: AppState,
     test_router: TestRouter,
     another_router: AnotherRouter,
     auth_router: AuthRouter,
+    admin_router: AdminRouter,
+    well_known_router: WellKnownRouter,
+    health_router: HealthRouter,
+    metrics_router: MetricsRouter,
 }
 
 impl Router {
-    pub fn new(sender: mpsc::Sender<String>) -> Self {
+    pub fn new(sender: mpsc::Sender<String>, state: AppState) -> Self {
         let test_router = TestRouter::new(sender.clone());
         let another_router = AnotherRouter::new(sender.clone());
-        let auth_router = AuthRouter::new(sender.clone());
+        let auth_router = AuthRouter::new(state.clone());
+        let admin_router = AdminRouter::new(state.clone());
+        let well_known_router = WellKnownRouter::new(state.clone());
+        let health_router = HealthRouter::new(state.clone());
+        let metrics_router = MetricsRouter::new(state.clone());
 
         Router {
             sender,
+            state,
             test_router,
             another_router,
             auth_router,
+            admin_router,
+            well_known_router,
+            health_router,
+            metrics_router,
         }
     }
 
-    pub async fn route(
-        &self,
-        method: &str,
-        path: &str,
-        authorization_header: Option<&str>,
-        body: String,
-        cookies: Option<Vec<(&str, &str)>>,
-    ) -> String {
-        let mut segments = path.trim_matches('/').split("/");
-        let mut prefix = "/";
+    // The route group of a path, which is its first segment
+    pub fn prefix(path: &str) -> &str {
+        match path.trim_matches('/').split('/').next() {
+            Some(first_segment) => first_segment,
+            None => "/",
+        }
+    }
+
+    pub async fn route(&self, request: &Request<'_>) -> String {
+        let prefix = Router::prefix(&request.uri);
 
-        if let Some(first_segment) = segments.next() {
-            prefix = first_segment;
+        if let Err(response) = authorize(&self.state, request, group_access(prefix)) {
+            return response;
+        }
+
+        if let Some(feature) = postgres_feature(&request.uri) {
+            if self.state.db.postgres().is_err() {
+                return needs_postgres_response(feature);
+            }
         }
 
         match prefix {
             "/" => test_api(self.sender.clone()),
-            "test" => self
-                .test_router
-                .route(method, path, authorization_header, body),
-            "another" => self
-                .another_router
-                .route(method, path, authorization_header, body),
-            "auth" => {
-                self.auth_router
-                    .route(method, path, authorization_header, body, cookies)
-                    .await
-            }
+            "test" => self.test_router.route(request),
+            "another" => self.another_router.route(request),
+            "auth" => self.auth_router.route(request).await,
+            "admin" => self.admin_router.route(request).await,
+            ".well-known" => self.well_known_router.route(request),
+            "healthz" | "readyz" => self.health_router.route(request).await,
+            "metrics" => self.metrics_router.route(request),
             _ => not_found_response(),
         }
     }
+
+    // Methods the route at `path` accepts, empty when there is no such route
+    pub fn allowed_methods(&self, path: &str) -> &'static [&'static str] {
+        match Router::prefix(path) {
+            "/" => &["GET"],
+            "test" => self.test_router.methods(path),
+            "another" => self.another_router.methods(path),
+            "auth" => self.auth_router.methods(path),
+            "admin" => self.admin_router.methods(path),
+            ".well-known" => self.well_known_router.methods(path),
+            "healthz" | "readyz" => self.health_router.methods(path),
+            "metrics" => self.metrics_router.methods(path),
+            _ => &[],
+        }
+    }
+
+    // The route `path` belongs to with IDs left out, so metrics have one series
+    // per route rather than one per user or session
+    pub fn route_template<'a>(&self, path: &'a str) -> &'a str {
+        if self.allowed_methods(path).is_empty() {
+            return "unmatched";
+        }
+
+        match Router::prefix(path) {
+            "auth" => self.auth_router.template(path),
+            "admin" => self.admin_router.template(path),
+            _ => path,
+        }
+    }
 }
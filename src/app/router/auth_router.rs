@@ -1,35 +1,194 @@
-use std::sync::mpsc;
-
 use crate::{
-    app::handlers::auth_handler::{login, register},
-    http::utils::not_found_response,
+    app::{
+        handlers::{
+            account_handler::{change_password, delete_me, me, update_me},
+            api_key_handler::{create_api_key, list_api_keys, revoke_api_key},
+            auth_handler::{
+                list_sessions, login, login_mfa, logout, logout_everywhere, refresh, register,
+                revoke_session,
+            },
+            mfa_handler::{confirm_totp, disable_totp, setup_totp},
+            password_handler::{forgot_password, reset_password},
+        },
+        state::AppState,
+    },
+    http::{
+        request::Request,
+        utils::{forbidden_response, not_found_response},
+    },
 };
 
+use super::access::{authorize, Access};
+
+enum AuthRoute<'a> {
+    Login,
+    LoginMfa,
+    Register,
+    ForgotPassword,
+    ResetPassword,
+    Refresh,
+    Logout,
+    SetupTotp,
+    ConfirmTotp,
+    DisableTotp,
+    Me,
+    UpdateMe,
+    DeleteMe,
+    ChangePassword,
+    LogoutEverywhere,
+    ListSessions,
+    RevokeSession(&'a str),
+    CreateApiKey,
+    ListApiKeys,
+    RevokeApiKey(&'a str),
+}
+
 pub struct AuthRouter {
-    sender: mpsc::Sender<String>,
+    state: AppState,
 }
 
 impl AuthRouter {
-    pub fn new(sender: mpsc::Sender<String>) -> Self {
-        AuthRouter { sender }
+    pub fn new(state: AppState) -> Self {
+        AuthRouter { state }
     }
 
-    pub async fn route(
-        &self,
-        method: &str,
-        path: &str,
-        _authorization_header: Option<&str>,
-        body: String,
-        cookies: Option<Vec<(&str, &str)>>,
-    ) -> String {
+    // Account routes need a signed in user, API keys are turned away from them
+    // because they act for a user without being one of their sessions
+    pub async fn route(&self, request: &Request<'_>) -> String {
+        let path = request.uri.as_str();
+
         if !path.starts_with("/auth") {
             return not_found_response();
         }
 
-        match (method, path) {
-            ("POST", "/auth/login") => login(&self.sender, body, cookies).await,
-            ("POST", "/auth/register") => register(&self.sender, body).await,
-            _ => not_found_response(),
+        let (access, route) = match (request.method.as_str(), path) {
+            ("POST", "/auth/login") => (Access::Public, AuthRoute::Login),
+            ("POST", "/auth/login/mfa") => (Access::Public, AuthRoute::LoginMfa),
+            ("POST", "/auth/register") => (Access::Public, AuthRoute::Register),
+            ("POST", "/auth/password/forgot") => (Access::Public, AuthRoute::ForgotPassword),
+            ("POST", "/auth/password/reset") => (Access::Public, AuthRoute::ResetPassword),
+            ("POST", "/auth/refresh") => (Access::Public, AuthRoute::Refresh),
+            ("POST", "/auth/logout") => (Access::Public, AuthRoute::Logout),
+            ("POST", "/auth/totp") => (Access::Authenticated, AuthRoute::SetupTotp),
+            ("POST", "/auth/totp/confirm") => (Access::Authenticated, AuthRoute::ConfirmTotp),
+            ("DELETE", "/auth/totp") => (Access::Authenticated, AuthRoute::DisableTotp),
+            ("GET", "/auth/me") => (Access::Authenticated, AuthRoute::Me),
+            ("PATCH", "/auth/me") => (Access::Authenticated, AuthRoute::UpdateMe),
+            ("DELETE", "/auth/me") => (Access::Authenticated, AuthRoute::DeleteMe),
+            ("POST", "/auth/password") => (Access::Authenticated, AuthRoute::ChangePassword),
+            ("POST", "/auth/logout/all") => (Access::Authenticated, AuthRoute::LogoutEverywhere),
+            ("GET", "/auth/sessions") => (Access::Authenticated, AuthRoute::ListSessions),
+            ("DELETE", _) if path.starts_with("/auth/sessions/") => (
+                Access::Authenticated,
+                AuthRoute::RevokeSession(path.trim_start_matches("/auth/sessions/")),
+            ),
+            ("POST", "/auth/api-keys") => (Access::Authenticated, AuthRoute::CreateApiKey),
+            ("GET", "/auth/api-keys") => (Access::Authenticated, AuthRoute::ListApiKeys),
+            ("DELETE", _) if path.starts_with("/auth/api-keys/") => (
+                Access::Authenticated,
+                AuthRoute::RevokeApiKey(path.trim_start_matches("/auth/api-keys/")),
+            ),
+            _ => return not_found_response(),
+        };
+
+        if let Err(response) = authorize(&self.state, request, access) {
+            return response;
+        }
+
+        let state = &self.state;
+        match (route, request.claims.as_ref()) {
+            (AuthRoute::Login, _) => login(state, request).await,
+            (AuthRoute::LoginMfa, _) => login_mfa(state, request).await,
+            (AuthRoute::Register, _) => register(state, request).await,
+            (AuthRoute::ForgotPassword, _) => forgot_password(state, request).await,
+            (AuthRoute::ResetPassword, _) => reset_password(state, request).await,
+            (AuthRoute::Refresh, _) => refresh(state, request).await,
+            (AuthRoute::Logout, _) => logout(state, request).await,
+            (AuthRoute::SetupTotp, Some(claims)) => setup_totp(state, claims).await,
+            (AuthRoute::ConfirmTotp, Some(claims)) => confirm_totp(state, request, claims).await,
+            (AuthRoute::DisableTotp, Some(claims)) => disable_totp(state, request, claims).await,
+            (AuthRoute::Me, Some(claims)) => me(state, claims).await,
+            (AuthRoute::UpdateMe, Some(claims)) => update_me(state, request, claims).await,
+            (AuthRoute::DeleteMe, Some(claims)) => delete_me(state, request, claims).await,
+            (AuthRoute::ChangePassword, Some(claims)) => {
+                change_password(state, request, claims).await
+            }
+            (AuthRoute::LogoutEverywhere, Some(claims)) => logout_everywhere(state, claims).await,
+            (AuthRoute::ListSessions, Some(claims)) => list_sessions(state, claims).await,
+            (AuthRoute::RevokeSession(sid), Some(claims)) => {
+                revoke_session(state, claims, sid).await
+            }
+            (AuthRoute::CreateApiKey, Some(claims)) => create_api_key(state, request, claims).await,
+            (AuthRoute::ListApiKeys, Some(claims)) => list_api_keys(state, claims).await,
+            (AuthRoute::RevokeApiKey(id), Some(claims)) => revoke_api_key(state, claims, id).await,
+            (_, None) => forbidden_response("API keys cannot be used on this route"),
+        }
+    }
+
+    // Methods `route` accepts for a path, used to answer preflight requests
+    pub fn methods(&self, path: &str) -> &'static [&'static str] {
+        match path {
+            "/auth/totp" => &["POST", "DELETE"],
+            "/auth/me" => &["GET", "PATCH", "DELETE"],
+            "/auth/sessions" => &["GET"],
+            "/auth/api-keys" => &["POST", "GET"],
+            "/auth/login"
+            | "/auth/login/mfa"
+            | "/auth/totp/confirm"
+            | "/auth/register"
+            | "/auth/password"
+            | "/auth/password/forgot"
+            | "/auth/password/reset"
+            | "/auth/refresh"
+            | "/auth/logout"
+            | "/auth/logout/all" => &["POST"],
+            _ if path.starts_with("/auth/sessions/") || path.starts_with("/auth/api-keys/") => {
+                &["DELETE"]
+            }
+            _ => &[],
+        }
+    }
+
+    // `path` with the ID of a session or API key replaced by a placeholder
+    pub fn template<'a>(&self, path: &'a str) -> &'a str {
+        if path.starts_with("/auth/sessions/") {
+            "/auth/sessions/:sid"
+        } else if path.starts_with("/auth/api-keys/") {
+            "/auth/api-keys/:id"
+        } else {
+            path
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::models::api_key::ApiKeyCaller;
+
+    fn status(response: &str) -> u16 {
+        response[9..12].parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn account_routes_need_a_signed_in_user() {
+        let router = AuthRouter::new(AppState::for_tests());
+        let request = Request::parse("GET /auth/me HTTP/1.1\r\n\r\n", None);
+
+        assert_eq!(status(&router.route(&request).await), 401);
+    }
+
+    #[tokio::test]
+    async fn account_routes_turn_away_api_keys() {
+        let router = AuthRouter::new(AppState::for_tests());
+        let mut request = Request::parse("POST /auth/api-keys HTTP/1.1\r\n\r\n", None);
+        request.api_key = Some(ApiKeyCaller {
+            key_id: String::from("key"),
+            uid: 1,
+            roles: vec![String::from("user")],
+            scopes: Vec::new(),
+        });
+
+        assert_eq!(status(&router.route(&request).await), 403);
+    }
+}
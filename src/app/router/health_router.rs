@@ -0,0 +1,33 @@
+use crate::{
+    app::{
+        handlers::health_handler::{healthz, readyz},
+        state::AppState,
+    },
+    http::{request::Request, utils::not_found_response},
+};
+
+pub struct HealthRouter {
+    state: AppState,
+}
+
+impl HealthRouter {
+    pub fn new(state: AppState) -> Self {
+        HealthRouter { state }
+    }
+
+    pub async fn route(&self, request: &Request<'_>) -> String {
+        match (request.method.as_str(), request.uri.as_str()) {
+            ("GET", "/healthz") => healthz(),
+            ("GET", "/readyz") => readyz(&self.state).await,
+            _ => not_found_response(),
+        }
+    }
+
+    // Methods `route` accepts for a path, used to answer preflight requests
+    pub fn methods(&self, path: &str) -> &'static [&'static str] {
+        match path {
+            "/healthz" | "/readyz" => &["GET"],
+            _ => &[],
+        }
+    }
+}
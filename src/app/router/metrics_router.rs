@@ -0,0 +1,29 @@
+use crate::{
+    app::{handlers::metrics_handler::metrics, state::AppState},
+    http::{request::Request, utils::not_found_response},
+};
+
+pub struct MetricsRouter {
+    state: AppState,
+}
+
+impl MetricsRouter {
+    pub fn new(state: AppState) -> Self {
+        MetricsRouter { state }
+    }
+
+    pub fn route(&self, request: &Request) -> String {
+        match (request.method.as_str(), request.uri.as_str()) {
+            ("GET", "/metrics") => metrics(&self.state, request),
+            _ => not_found_response(),
+        }
+    }
+
+    // Methods `route` accepts for a path, used to answer preflight requests
+    pub fn methods(&self, path: &str) -> &'static [&'static str] {
+        match path {
+            "/metrics" => &["GET"],
+            _ => &[],
+        }
+    }
+}
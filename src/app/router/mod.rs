@@ -1,4 +1,9 @@
+pub mod access;
+pub mod admin_router;
 pub mod another_router;
 pub mod app;
 pub mod auth_router;
+pub mod health_router;
+pub mod metrics_router;
 pub mod test_router;
+pub mod well_known_router;
@@ -1,6 +1,9 @@
 use std::sync::mpsc;
 
-use crate::{app::handlers::test_handler::test_api, http::utils::not_found_response};
+use crate::{
+    app::handlers::test_handler::test_api,
+    http::{request::Request, utils::not_found_response},
+};
 
 pub struct TestRouter {
     sender: mpsc::Sender<String>,
@@ -11,21 +14,26 @@ impl TestRouter {
         TestRouter { sender }
     }
 
-    pub fn route(
-        &self,
-        method: &str,
-        path: &str,
-        _authorization_header: Option<&str>,
-        _body: String,
-    ) -> String {
+    pub fn route(&self, request: &Request) -> String {
+        let path = request.uri.as_str();
+
         if !path.starts_with("/test") {
             return not_found_response();
         }
 
-        match (method, path) {
+        match (request.method.as_str(), path) {
             ("GET", "/test") => test_api(self.sender.clone()),
             ("POST", "/test/create") => test_api(self.sender.clone()),
             _ => not_found_response(),
         }
     }
+
+    // Methods `route` accepts for a path, used to answer preflight requests
+    pub fn methods(&self, path: &str) -> &'static [&'static str] {
+        match path {
+            "/test" => &["GET"],
+            "/test/create" => &["POST"],
+            _ => &[],
+        }
+    }
 }
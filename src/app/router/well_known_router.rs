@@ -0,0 +1,35 @@
+use crate::{
+    app::{handlers::jwks_handler::jwks, state::AppState},
+    http::{request::Request, utils::not_found_response},
+};
+
+pub struct WellKnownRouter {
+    state: AppState,
+}
+
+impl WellKnownRouter {
+    pub fn new(state: AppState) -> Self {
+        WellKnownRouter { state }
+    }
+
+    pub fn route(&self, request: &Request) -> String {
+        let path = request.uri.as_str();
+
+        if !path.starts_with("/.well-known") {
+            return not_found_response();
+        }
+
+        match (request.method.as_str(), path) {
+            ("GET", "/.well-known/jwks.json") => jwks(&self.state),
+            _ => not_found_response(),
+        }
+    }
+
+    // Methods `route` accepts for a path, used to answer preflight requests
+    pub fn methods(&self, path: &str) -> &'static [&'static str] {
+        match path {
+            "/.well-known/jwks.json" => &["GET"],
+            _ => &[],
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use sqlx::{postgres::PgPool, Row};
+use std::sync::Arc;
+
+use crate::{
+    app::{
+        models::{role::Role, user::UserSummary},
+        repositories::user::UserRepository,
+    },
+    config::Config,
+};
+
+use super::{
+    audit::{record_event, AuditEvent},
+    error::AuthError,
+    lockout::{attempt_store, LoginThrottle},
+};
+
+pub struct AdminService {
+    pool: PgPool,
+    config: Arc<Config>,
+}
+
+impl AdminService {
+    pub fn new(pool: PgPool, config: Arc<Config>) -> Self {
+        AdminService { pool, config }
+    }
+
+    pub async fn users(&self) -> Result<Vec<UserSummary>, AuthError> {
+        let query = "SELECT id, username, roles FROM users ORDER BY id";
+        let rows = sqlx::query(query).fetch_all(&self.pool).await?;
+
+        let users = rows
+            .iter()
+            .map(|row| UserSummary {
+                id: row.get("id"),
+                username: row.get("username"),
+                roles: row.get("roles"),
+            })
+            .collect();
+
+        Ok(users)
+    }
+
+    // Replaces the roles of a user. They show up in the user's access tokens
+    // from the next refresh onwards.
+    pub async fn set_roles(&self, id: i32, roles: &[Role]) -> Result<UserSummary, AuthError> {
+        let roles: Vec<String> = roles.iter().map(Role::to_string).collect();
+
+        let query = "UPDATE users SET roles = $1 WHERE id = $2 RETURNING id, username, roles";
+        let result = sqlx::query(query)
+            .bind(&roles)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        match result {
+            Some(row) => Ok(UserSummary {
+                id: row.get("id"),
+                username: row.get("username"),
+                roles: row.get("roles"),
+            }),
+            None => Err(AuthError::UserNotFound),
+        }
+    }
+
+    // Lifts a lockout of the account. Locks on client IPs are left alone, they
+    // run out on their own.
+    pub async fn unlock(&self, id: i32, actor: &str) -> Result<UserSummary, AuthError> {
+        let query = "SELECT id, username, roles FROM users WHERE id = $1";
+        let user = match sqlx::query(query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+        {
+            Some(row) => UserSummary {
+                id: row.get("id"),
+                username: row.get("username"),
+                roles: row.get("roles"),
+            },
+            None => return Err(AuthError::UserNotFound),
+        };
+
+        let login = &self.config.login;
+        LoginThrottle::new(attempt_store(login, &self.pool), login.lockout)
+            .unlock(&user.username)
+            .await?;
+
+        let detail = format!("Unlocked by {}", actor);
+        record_event(
+            &self.pool,
+            AuditEvent::AccountUnlocked,
+            &user.username,
+            None,
+            &detail,
+        )
+        .await;
+
+        Ok(user)
+    }
+}
+
+// Kept apart from `AdminService` so the `users grant-role` command works on
+// every backend, not just Postgres
+pub async fn grant_role(
+    users: &dyn UserRepository,
+    username: &str,
+    role: Role,
+) -> Result<UserSummary, AuthError> {
+    let user = users
+        .find_by_username(username)
+        .await?
+        .ok_or(AuthError::UserNotFound)?;
+    users.add_role(user.id, &role.to_string()).await?;
+
+    let user = users
+        .find_by_id(user.id)
+        .await?
+        .ok_or(AuthError::UserNotFound)?;
+    Ok(UserSummary {
+        id: user.id,
+        username: user.username,
+        roles: user.roles,
+    })
+}
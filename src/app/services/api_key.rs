@@ -0,0 +1,131 @@
+use sqlx::{postgres::PgPool, Row};
+
+use crate::app::models::{
+    api_key::{ApiKey, ApiKeyCaller, NewApiKey},
+    role::Permission,
+};
+
+use super::{
+    error::AuthError,
+    utils::{current_timestamp, hash_token, random_hex},
+};
+
+// Keys look like `nfr_<id>_<secret>`. The id is stored as is so a key can be
+// looked up, listed and revoked, the secret only as a SHA-256 digest.
+const KEY_PREFIX: &str = "nfr_";
+
+fn split_key(key: &str) -> Option<(&str, &str)> {
+    let (id, secret) = key.strip_prefix(KEY_PREFIX)?.split_once('_')?;
+
+    if id.is_empty() || secret.is_empty() {
+        return None;
+    }
+
+    Some((id, secret))
+}
+
+#[derive(Debug)]
+pub struct ApiKeyService {
+    pool: PgPool,
+}
+
+impl ApiKeyService {
+    pub fn new(pool: PgPool) -> Self {
+        ApiKeyService { pool }
+    }
+
+    pub async fn create(
+        &self,
+        uid: i32,
+        name: &str,
+        scopes: &[Permission],
+        expires_in: Option<i64>,
+    ) -> Result<NewApiKey, AuthError> {
+        let id = random_hex(8);
+        let secret = random_hex(32);
+        let scopes: Vec<String> = scopes.iter().map(Permission::to_string).collect();
+        let created_at = current_timestamp() as i64;
+        let expires_at = expires_in.map(|secs| created_at + secs);
+
+        let query = "INSERT INTO api_keys (id, user_id, name, secret_hash, scopes, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7)";
+        sqlx::query(query)
+            .bind(&id)
+            .bind(uid)
+            .bind(name)
+            .bind(hash_token(&secret))
+            .bind(&scopes)
+            .bind(created_at)
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(NewApiKey {
+            key: format!("{}{}_{}", KEY_PREFIX, id, secret),
+            details: ApiKey {
+                id,
+                name: name.to_string(),
+                scopes,
+                created_at,
+                expires_at,
+                last_used_at: None,
+            },
+        })
+    }
+
+    pub async fn list(&self, uid: i32) -> Result<Vec<ApiKey>, AuthError> {
+        let query = "SELECT id, name, scopes, created_at, expires_at, last_used_at FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC";
+        let rows = sqlx::query(query).bind(uid).fetch_all(&self.pool).await?;
+
+        let keys = rows
+            .iter()
+            .map(|row| ApiKey {
+                id: row.get("id"),
+                name: row.get("name"),
+                scopes: row.get("scopes"),
+                created_at: row.get("created_at"),
+                expires_at: row.get("expires_at"),
+                last_used_at: row.get("last_used_at"),
+            })
+            .collect();
+
+        Ok(keys)
+    }
+
+    pub async fn revoke(&self, uid: i32, id: &str) -> Result<(), AuthError> {
+        let query = "DELETE FROM api_keys WHERE id = $1 AND user_id = $2";
+        let result = sqlx::query(query)
+            .bind(id)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+
+        match result.rows_affected() {
+            0 => Err(AuthError::ApiKeyNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    // Resolves the owner of a key and records that the key has been used
+    pub async fn authenticate(&self, key: &str) -> Result<ApiKeyCaller, AuthError> {
+        let (id, secret) = split_key(key).ok_or(AuthError::InvalidApiKey)?;
+        let now = current_timestamp() as i64;
+
+        let query = "UPDATE api_keys SET last_used_at = $1 FROM users WHERE api_keys.id = $2 AND api_keys.secret_hash = $3 AND (api_keys.expires_at IS NULL OR api_keys.expires_at > $1) AND users.id = api_keys.user_id RETURNING api_keys.scopes, users.id, users.roles";
+        let result = sqlx::query(query)
+            .bind(now)
+            .bind(id)
+            .bind(hash_token(secret))
+            .fetch_optional(&self.pool)
+            .await?;
+
+        match result {
+            Some(row) => Ok(ApiKeyCaller {
+                key_id: id.to_string(),
+                uid: row.get("id"),
+                roles: row.get("roles"),
+                scopes: row.get("scopes"),
+            }),
+            None => Err(AuthError::InvalidApiKey),
+        }
+    }
+}
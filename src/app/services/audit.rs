@@ -0,0 +1,163 @@
+use async_trait::async_trait;
+use sqlx::postgres::PgPool;
+#[cfg(feature = "sqlite")]
+use sqlx::sqlite::SqlitePool;
+use std::fmt;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+use crate::logging;
+
+use super::utils::current_timestamp;
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuditEvent {
+    LoginFailed,
+    LoginLocked,
+    AccountUnlocked,
+    PasswordResetRequested,
+    PasswordReset,
+    PasswordChanged,
+    UsernameChanged,
+    AccountDeleted,
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditEvent::LoginFailed => write!(f, "login_failed"),
+            AuditEvent::LoginLocked => write!(f, "login_locked"),
+            AuditEvent::AccountUnlocked => write!(f, "account_unlocked"),
+            AuditEvent::PasswordResetRequested => write!(f, "password_reset_requested"),
+            AuditEvent::PasswordReset => write!(f, "password_reset"),
+            AuditEvent::PasswordChanged => write!(f, "password_changed"),
+            AuditEvent::UsernameChanged => write!(f, "username_changed"),
+            AuditEvent::AccountDeleted => write!(f, "account_deleted"),
+        }
+    }
+}
+
+// The request goes on without its audit entry, the failure is logged instead
+fn log_write_failure(event: AuditEvent, error: sqlx::Error) {
+    logging::error(
+        "Could not write audit entry",
+        &[
+            ("event", event.to_string().into()),
+            ("error", error.to_string().into()),
+        ],
+    );
+}
+
+// Audit entries are best effort, failing to write one never fails the request
+pub async fn record_event(
+    pool: &PgPool,
+    event: AuditEvent,
+    username: &str,
+    ip: Option<&str>,
+    detail: &str,
+) {
+    let query = "INSERT INTO audit_log (event, username, ip, detail, created_at) VALUES ($1, $2, $3, $4, $5)";
+    let result = sqlx::query(query)
+        .bind(event.to_string())
+        .bind(username)
+        .bind(ip)
+        .bind(detail)
+        .bind(current_timestamp() as i64)
+        .execute(pool)
+        .await;
+
+    if let Err(error) = result {
+        log_write_failure(event, error);
+    }
+}
+
+// Where services that run without a database, like `AuthService`, write their entries
+#[async_trait]
+pub trait AuditLog: Send + Sync {
+    async fn record(&self, event: AuditEvent, username: &str, ip: Option<&str>, detail: &str);
+}
+
+pub struct PgAuditLog {
+    pool: PgPool,
+}
+
+impl PgAuditLog {
+    pub fn new(pool: PgPool) -> Self {
+        PgAuditLog { pool }
+    }
+}
+
+#[async_trait]
+impl AuditLog for PgAuditLog {
+    async fn record(&self, event: AuditEvent, username: &str, ip: Option<&str>, detail: &str) {
+        record_event(&self.pool, event, username, ip, detail).await;
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub struct SqliteAuditLog {
+    pool: SqlitePool,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteAuditLog {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteAuditLog { pool }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[async_trait]
+impl AuditLog for SqliteAuditLog {
+    async fn record(&self, event: AuditEvent, username: &str, ip: Option<&str>, detail: &str) {
+        let query = "INSERT INTO audit_log (event, username, ip, detail, created_at) VALUES ($1, $2, $3, $4, $5)";
+        let result = sqlx::query(query)
+            .bind(event.to_string())
+            .bind(username)
+            .bind(ip)
+            .bind(detail)
+            .bind(current_timestamp() as i64)
+            .execute(&self.pool)
+            .await;
+
+        if let Err(error) = result {
+            log_write_failure(event, error);
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub event: AuditEvent,
+    pub username: String,
+    pub ip: Option<String>,
+    pub detail: String,
+}
+
+// Keeps entries in memory, clones share the same entries
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MemoryAuditLog {
+    entries: Arc<Mutex<Vec<AuditEntry>>>,
+}
+
+#[cfg(test)]
+impl MemoryAuditLog {
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl AuditLog for MemoryAuditLog {
+    async fn record(&self, event: AuditEvent, username: &str, ip: Option<&str>, detail: &str) {
+        self.entries.lock().unwrap().push(AuditEntry {
+            event,
+            username: username.to_string(),
+            ip: ip.map(String::from),
+            detail: detail.to_string(),
+        });
+    }
+}
@@ -1,97 +1,728 @@
-use sqlx::{postgres::PgPool, Error, Row};
-extern crate bcrypt;
-
-use bcrypt::{hash, verify, DEFAULT_COST};
-
 use crate::{
-    app::models::{claims::Claims, user::User},
-    http::utils::extract_token_from_cookies,
+    app::{
+        models::{
+            mfa::MfaChallenge,
+            session::{ClientInfo, Session},
+            user::{Account, User},
+        },
+        repositories::{
+            session::{PgSessionRepository, SessionRepository},
+            user::{user_repository, UserRecord, UserRepository},
+        },
+    },
+    config::Config,
+    db::DatabasePool,
+    logging,
 };
+use std::sync::Arc;
 
-use super::utils::{generate_refresh_token, generate_token, store_refresh_token, verify_token};
+use super::{
+    audit::{AuditEvent, AuditLog, PgAuditLog},
+    error::AuthError,
+    lockout::{attempt_store, AttemptStore, LoginThrottle},
+    mfa::{verify_second_factor, SecondFactor},
+    password::{hash_password, verify_dummy_password, verify_password, Verification},
+    utils::{
+        generate_id, generate_mfa_token, generate_refresh_token, generate_token, hash_token,
+        verify_mfa_token, verify_refresh_token,
+    },
+    validation::{check_password, normalize_username},
+};
+
+#[cfg(feature = "sqlite")]
+use super::{audit::SqliteAuditLog, lockout::MemoryAttemptStore};
+#[cfg(feature = "sqlite")]
+use crate::app::repositories::session::SqliteSessionRepository;
 
 #[derive(Debug)]
-pub struct AuthService {
-    pool: PgPool,
+pub enum LoginOutcome {
+    Authenticated(User),
+    // The password was right, but the account has a second factor to check first
+    MfaRequired(MfaChallenge),
+}
+
+// Generic over where users and sessions are kept, so the same logic runs
+// against Postgres, SQLite and the in-memory repositories
+pub struct AuthService<U = Box<dyn UserRepository>, S = Box<dyn SessionRepository>> {
+    config: Arc<Config>,
+    users: U,
+    sessions: S,
+    throttle: LoginThrottle,
+    audit: Box<dyn AuditLog>,
+}
+
+pub(super) type Storage = (
+    Box<dyn UserRepository>,
+    Box<dyn SessionRepository>,
+    Box<dyn AttemptStore>,
+    Box<dyn AuditLog>,
+);
+
+// Picks the repositories for the database DATABASE_URL points to
+pub(super) fn storage(config: &Config, db: &DatabasePool) -> Storage {
+    match db.clone() {
+        DatabasePool::Postgres(pool) => (
+            user_repository(db),
+            Box::new(PgSessionRepository::new(pool.clone())),
+            attempt_store(&config.login, &pool),
+            Box::new(PgAuditLog::new(pool)),
+        ),
+        // A SQLite database serves a single instance, so failed logins are
+        // counted in memory
+        #[cfg(feature = "sqlite")]
+        DatabasePool::Sqlite(pool) => (
+            user_repository(db),
+            Box::new(SqliteSessionRepository::new(pool.clone())),
+            Box::new(MemoryAttemptStore),
+            Box::new(SqliteAuditLog::new(pool)),
+        ),
+    }
 }
 
 impl AuthService {
-    pub fn new(pool: PgPool) -> Self {
-        AuthService { pool }
+    // Keeps users and sessions in the database DATABASE_URL points to
+    pub fn new(config: Arc<Config>, db: &DatabasePool) -> Self {
+        let (users, sessions, attempts, audit) = storage(&config, db);
+        let throttle = LoginThrottle::new(attempts, config.login.lockout);
+        AuthService::with_repositories(config, users, sessions, throttle, audit)
+    }
+}
+
+impl<U: UserRepository, S: SessionRepository> AuthService<U, S> {
+    pub fn with_repositories(
+        config: Arc<Config>,
+        users: U,
+        sessions: S,
+        throttle: LoginThrottle,
+        audit: Box<dyn AuditLog>,
+    ) -> Self {
+        AuthService {
+            config,
+            users,
+            sessions,
+            throttle,
+            audit,
+        }
+    }
+
+    async fn login_failed(
+        &self,
+        username: &str,
+        client: &ClientInfo,
+        error: AuthError,
+    ) -> AuthError {
+        self.throttle
+            .fail(self.audit.as_ref(), username, client.ip.as_deref(), error)
+            .await
     }
 
     pub async fn login(
         &self,
         username: &str,
         password: &str,
-        cookies: Option<Vec<(&str, &str)>>,
-    ) -> Result<User, Error> {
-        let query = "SELECT * FROM users WHERE username = $1";
+        client: &ClientInfo,
+    ) -> Result<LoginOutcome, AuthError> {
+        self.throttle.check(username, client.ip.as_deref()).await?;
 
         // Check if a user with provided credentials exists
-        let result = sqlx::query(query)
-            .bind(username)
-            .fetch_optional(&self.pool)
-            .await?;
-
-        match result {
-            Some(result) => {
-                let id: i32 = result.try_get("id")?;
-                let username: String = result.try_get("username")?;
-                let hashed_password: String = result.try_get("password")?;
-                let refresh_token: String = result.try_get("refresh_token")?;
-
-                let is_password_correct = verify(password, &hashed_password);
-
-                match is_password_correct {
-                    Ok(_) => {
-                        let access_token = generate_token(id, username.as_str());
-
-                        return Ok(User::new(
-                            id,
-                            username.to_string(),
-                            access_token.to_string(),
-                            refresh_token.to_string(),
-                        ));
+        match self.users.find_by_username(username).await? {
+            Some(user) => {
+                match verify_password(&self.config.password, password, &user.password) {
+                    Verification::Invalid => {
+                        return Err(self
+                            .login_failed(&user.username, client, AuthError::InvalidCredentials)
+                            .await)
                     }
-                    Err(_) => return Err(Error::RowNotFound),
+                    Verification::ValidNeedsRehash => {
+                        self.upgrade_hash(user.id, password, &user.password).await
+                    }
+                    Verification::Valid => {}
+                }
+
+                if user.totp_enabled {
+                    return Ok(LoginOutcome::MfaRequired(MfaChallenge::new(
+                        generate_mfa_token(&self.config.jwt, user.id, &user.username),
+                    )));
                 }
+
+                self.throttle.record_success(&user.username).await?;
+                self.start_session(user.id, user.username, user.roles, client)
+                    .await
+                    .map(LoginOutcome::Authenticated)
             }
             None => {
-                eprintln!("User not found");
-                return Err(Error::RowNotFound);
+                logging::debug(
+                    "Login for an unknown username",
+                    &[("username", username.into())],
+                );
+                verify_dummy_password(&self.config.password, password);
+                Err(self
+                    .login_failed(username, client, AuthError::InvalidCredentials)
+                    .await)
+            }
+        }
+    }
+
+    // Replaces a hash made with an older algorithm or weaker parameters while the
+    // plain password is at hand. Only swapped if nobody changed the password in
+    // the meantime, and a failure here never fails the login.
+    async fn upgrade_hash(&self, uid: i32, password: &str, old_hash: &str) {
+        let new_hash = match hash_password(&self.config.password, password) {
+            Ok(new_hash) => new_hash,
+            Err(error) => {
+                logging::error(
+                    "Could not hash password",
+                    &[("uid", uid.into()), ("error", error.to_string().into())],
+                );
+                return;
             }
+        };
+
+        let result = self.users.replace_password(uid, old_hash, &new_hash).await;
+        if let Err(error) = result {
+            logging::warn(
+                "Could not upgrade password hash",
+                &[("uid", uid.into()), ("error", error.to_string().into())],
+            );
         }
     }
 
-    pub async fn register(&self, username: &str, password: &str) -> Result<User, Error> {
-        let hashed_password = hash(&password, DEFAULT_COST).unwrap();
-        let query = "INSERT INTO users (username, password) VALUES ($1, $2) RETURNING id, username";
-        let result = sqlx::query(query)
-            .bind(username)
-            .bind(hashed_password)
-            .fetch_optional(&self.pool)
+    // Second step of a login for accounts with two-factor authentication
+    pub async fn login_mfa(
+        &self,
+        mfa_token: &str,
+        factor: &SecondFactor,
+        client: &ClientInfo,
+    ) -> Result<User, AuthError> {
+        let claims = match verify_mfa_token(&self.config.jwt, mfa_token) {
+            Ok(token_data) => token_data.claims,
+            Err(_) => return Err(AuthError::InvalidMfaToken),
+        };
+
+        self.throttle
+            .check(&claims.username, client.ip.as_deref())
+            .await?;
+
+        // Guessing at codes counts against the account just like guessing at passwords
+        match verify_second_factor(claims.uid, factor, &self.users).await {
+            Ok(_) => self.throttle.record_success(&claims.username).await?,
+            Err(AuthError::InvalidMfaCode) => {
+                return Err(self
+                    .login_failed(&claims.username, client, AuthError::InvalidMfaCode)
+                    .await);
+            }
+            Err(error) => return Err(error),
+        }
+
+        let user = self.user(claims.uid).await?;
+        self.start_session(user.id, user.username, user.roles, client)
+            .await
+    }
+
+    pub async fn register(
+        &self,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+        client: &ClientInfo,
+    ) -> Result<User, AuthError> {
+        let username = normalize_username(username)?;
+        check_password(&self.config.password, password, &username)?;
+
+        let hashed_password = hash_password(&self.config.password, password)?;
+        let user = self
+            .users
+            .create(&username, &hashed_password, email)
+            .await?;
+
+        self.start_session(user.id, user.username, user.roles, client)
+            .await
+    }
+
+    async fn user(&self, uid: i32) -> Result<UserRecord, AuthError> {
+        self.users
+            .find_by_id(uid)
+            .await?
+            .ok_or(AuthError::UserNotFound)
+    }
+
+    // Every login opens a new session, which is the family its refresh tokens rotate within
+    async fn start_session(
+        &self,
+        id: i32,
+        username: String,
+        roles: Vec<String>,
+        client: &ClientInfo,
+    ) -> Result<User, AuthError> {
+        let sid = generate_id();
+        let jwt = &self.config.jwt;
+        let access_token = generate_token(jwt, id, username.as_str(), &sid, &roles)?;
+        let refresh_token = generate_refresh_token(jwt, username.as_str(), id, &sid);
+
+        self.sessions
+            .create(&sid, id, &hash_token(&refresh_token), client)
             .await?;
 
-        match result {
-            Some(result) => {
-                let id: i32 = result.get("id");
-                let access_token = generate_token(id, username);
-                let refresh_token = generate_refresh_token(&username, id);
+        Ok(User::new(id, username, access_token, refresh_token))
+    }
+
+    pub async fn refresh(&self, refresh_token: &str) -> Result<User, AuthError> {
+        let jwt = &self.config.jwt;
+        let claims = match verify_refresh_token(jwt, refresh_token) {
+            Ok(token_data) => token_data.claims,
+            Err(_) => return Err(AuthError::InvalidToken),
+        };
+
+        let new_refresh_token =
+            generate_refresh_token(jwt, &claims.username, claims.uid, &claims.sid);
+        let rotated = self
+            .sessions
+            .rotate(
+                &claims.sid,
+                claims.uid,
+                &hash_token(refresh_token),
+                &hash_token(&new_refresh_token),
+            )
+            .await?;
 
-                // Store the refresh token in database
-                store_refresh_token(&refresh_token, id, &self.pool).await;
+        match rotated {
+            // Roles are read again on every refresh, so role changes apply within one
+            // access token lifetime
+            true => {
+                let user = self
+                    .users
+                    .find_by_id(claims.uid)
+                    .await?
+                    .ok_or(AuthError::InvalidToken)?;
+                let access_token = generate_token(
+                    jwt,
+                    user.id,
+                    user.username.as_str(),
+                    &claims.sid,
+                    &user.roles,
+                )?;
 
-                return Ok(User::new(
-                    id,
-                    username.to_string(),
+                Ok(User::new(
+                    user.id,
+                    user.username,
                     access_token,
-                    refresh_token,
-                ));
+                    new_refresh_token,
+                ))
             }
-            None => {
-                return Err(Error::RowNotFound);
+            false => {
+                // A correctly signed token that is no longer the current one has already
+                // been rotated, so it is being replayed. Revoke the whole session so that
+                // neither the attacker nor the victim can keep using it.
+                logging::warn(
+                    "Refresh token reuse detected, revoking the session",
+                    &[
+                        ("uid", claims.uid.into()),
+                        ("sid", claims.sid.as_str().into()),
+                    ],
+                );
+                self.sessions.delete(&claims.sid, claims.uid).await?;
+                Err(AuthError::TokenReused)
+            }
+        }
+    }
+
+    pub async fn logout(&self, refresh_token: &str) -> Result<(), AuthError> {
+        let claims = match verify_refresh_token(&self.config.jwt, refresh_token) {
+            Ok(token_data) => token_data.claims,
+            Err(_) => return Err(AuthError::InvalidToken),
+        };
+
+        self.sessions.delete(&claims.sid, claims.uid).await?;
+        Ok(())
+    }
+
+    pub async fn logout_everywhere(&self, uid: i32) -> Result<(), AuthError> {
+        self.sessions.delete_for_user(uid).await
+    }
+
+    pub async fn sessions(&self, uid: i32, current_sid: &str) -> Result<Vec<Session>, AuthError> {
+        self.sessions.list(uid, current_sid).await
+    }
+
+    pub async fn revoke_session(&self, uid: i32, sid: &str) -> Result<(), AuthError> {
+        match self.sessions.delete(sid, uid).await? {
+            true => Ok(()),
+            false => Err(AuthError::SessionNotFound),
+        }
+    }
+
+    pub async fn account(&self, uid: i32) -> Result<Account, AuthError> {
+        let user = self.user(uid).await?;
+
+        Ok(Account {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            roles: user.roles,
+            mfa_enabled: user.totp_enabled,
+        })
+    }
+
+    // Changes the fields that are given. `email` set to `Some(None)` removes the address.
+    // Access tokens keep the old username until they are refreshed.
+    pub async fn update_account(
+        &self,
+        uid: i32,
+        username: Option<&str>,
+        email: Option<Option<&str>>,
+    ) -> Result<Account, AuthError> {
+        let current = self.account(uid).await?;
+        let username = username.map(normalize_username).transpose()?;
+
+        self.users
+            .update_profile(uid, username.as_deref(), email)
+            .await?;
+
+        if let Some(username) = username.filter(|username| *username != current.username) {
+            let detail = format!("Renamed from {}", current.username);
+            self.audit
+                .record(AuditEvent::UsernameChanged, &username, None, &detail)
+                .await;
+        }
+
+        self.account(uid).await
+    }
+
+    // Checks the password of a signed in user, counting wrong guesses like failed logins
+    // so a stolen access token cannot be used to find out the password
+    async fn confirm_password(
+        &self,
+        uid: i32,
+        password: &str,
+        client: &ClientInfo,
+    ) -> Result<(String, bool), AuthError> {
+        let user = self.user(uid).await?;
+        self.throttle
+            .check(&user.username, client.ip.as_deref())
+            .await?;
+
+        match verify_password(&self.config.password, password, &user.password) {
+            Verification::Invalid => Err(self
+                .login_failed(&user.username, client, AuthError::InvalidCredentials)
+                .await),
+            _ => Ok((user.username, user.totp_enabled)),
+        }
+    }
+
+    // Every other session is signed out, the one making the change stays signed in
+    pub async fn change_password(
+        &self,
+        uid: i32,
+        current_sid: &str,
+        current_password: &str,
+        new_password: &str,
+        client: &ClientInfo,
+    ) -> Result<(), AuthError> {
+        let (username, _) = self.confirm_password(uid, current_password, client).await?;
+        check_password(&self.config.password, new_password, &username)?;
+
+        let hashed_password = hash_password(&self.config.password, new_password)?;
+        self.users.set_password(uid, &hashed_password).await?;
+        self.sessions.delete_others(uid, current_sid).await?;
+
+        self.audit
+            .record(
+                AuditEvent::PasswordChanged,
+                &username,
+                client.ip.as_deref(),
+                "Other sessions signed out",
+            )
+            .await;
+
+        Ok(())
+    }
+
+    // Deleting an account takes the password, and the second factor when the
+    // account has one. Sessions, API keys and reset links go with it.
+    pub async fn delete_account(
+        &self,
+        uid: i32,
+        password: &str,
+        factor: Option<&SecondFactor>,
+        client: &ClientInfo,
+    ) -> Result<(), AuthError> {
+        let (username, is_totp_enabled) = self.confirm_password(uid, password, client).await?;
+
+        // Wrong codes count like failed logins, or a stolen access token could be
+        // used to guess at the second factor
+        if is_totp_enabled {
+            let factor = factor.ok_or(AuthError::InvalidMfaCode)?;
+            match verify_second_factor(uid, factor, &self.users).await {
+                Ok(_) => {}
+                Err(AuthError::InvalidMfaCode) => {
+                    return Err(self
+                        .login_failed(&username, client, AuthError::InvalidMfaCode)
+                        .await);
+                }
+                Err(error) => return Err(error),
             }
         }
+
+        self.sessions.delete_for_user(uid).await?;
+        self.users.delete(uid).await?;
+
+        self.audit
+            .record(
+                AuditEvent::AccountDeleted,
+                &username,
+                client.ip.as_deref(),
+                "Deleted by the account owner",
+            )
+            .await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::repositories::{session::MemorySessionRepository, user::MemoryUserRepository};
+    use crate::app::services::{audit::MemoryAuditLog, lockout::MemoryAttemptStore};
+
+    const PASSWORD: &str = "correct horse battery";
+
+    // Failed logins are counted in a static that every test shares, so each
+    // test signs in with its own username and without an IP
+    fn service() -> (
+        AuthService<MemoryUserRepository, MemorySessionRepository>,
+        MemoryUserRepository,
+        MemoryAuditLog,
+    ) {
+        let flags = [
+            "--exp=900",
+            "--jwt-algorithm=HS256",
+            "--jwt-secret=test-secret",
+            "--refresh-token-secret=test-refresh-secret",
+            "--argon2-memory-kib=64",
+            "--argon2-iterations=1",
+            "--login-max-failures=3",
+            "--login-max-delay-secs=0",
+        ]
+        .map(String::from);
+        let (config, _) = Config::load(&flags).expect("test configuration is valid");
+
+        let users = MemoryUserRepository::default();
+        let audit = MemoryAuditLog::default();
+        let throttle = LoginThrottle::new(Box::new(MemoryAttemptStore), config.login.lockout);
+        let service = AuthService::with_repositories(
+            Arc::new(config),
+            users.clone(),
+            MemorySessionRepository::default(),
+            throttle,
+            Box::new(audit.clone()),
+        );
+
+        (service, users, audit)
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo::default()
+    }
+
+    #[tokio::test]
+    async fn register_opens_a_session() {
+        let (service, users, _) = service();
+
+        let user = service
+            .register("register", PASSWORD, None, &client())
+            .await
+            .unwrap();
+
+        assert_eq!(user.username, "register");
+        let record = users.find_by_id(user.id).await.unwrap().unwrap();
+        assert_ne!(record.password, PASSWORD);
+        let sessions = service.sessions(user.id, "").await.unwrap();
+        assert_eq!(sessions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn register_refuses_a_taken_username() {
+        let (service, _, _) = service();
+        service
+            .register("taken", PASSWORD, None, &client())
+            .await
+            .unwrap();
+
+        let result = service.register("TAKEN", PASSWORD, None, &client()).await;
+
+        assert!(matches!(result, Err(AuthError::UsernameTaken)));
+    }
+
+    #[tokio::test]
+    async fn login_with_the_right_password() {
+        let (service, _, _) = service();
+        let registered = service
+            .register("login", PASSWORD, None, &client())
+            .await
+            .unwrap();
+
+        let outcome = service.login("login", PASSWORD, &client()).await.unwrap();
+
+        match outcome {
+            LoginOutcome::Authenticated(user) => assert_eq!(user.id, registered.id),
+            LoginOutcome::MfaRequired(_) => panic!("no second factor is set up"),
+        }
+    }
+
+    #[tokio::test]
+    async fn login_asks_for_the_second_factor() {
+        let (service, users, _) = service();
+        let registered = service
+            .register("second-factor", PASSWORD, None, &client())
+            .await
+            .unwrap();
+        users
+            .set_totp_secret(registered.id, "JBSWY3DPEHPK3PXP")
+            .await
+            .unwrap();
+        users
+            .enable_totp(registered.id, "JBSWY3DPEHPK3PXP", 0, &[])
+            .await
+            .unwrap();
+
+        let outcome = service
+            .login("second-factor", PASSWORD, &client())
+            .await
+            .unwrap();
+
+        assert!(matches!(outcome, LoginOutcome::MfaRequired(_)));
+    }
+
+    #[tokio::test]
+    async fn login_with_a_wrong_password() {
+        let (service, _, audit) = service();
+        service
+            .register("wrong-password", PASSWORD, None, &client())
+            .await
+            .unwrap();
+
+        let result = service
+            .login("wrong-password", "not the password", &client())
+            .await;
+
+        assert!(matches!(result, Err(AuthError::InvalidCredentials)));
+        let entries = audit.entries();
+        assert_eq!(entries.len(), 1);
+        assert!(matches!(entries[0].event, AuditEvent::LoginFailed));
+        assert_eq!(entries[0].username, "wrong-password");
+        assert_eq!(entries[0].ip, None);
+        assert_eq!(entries[0].detail, "Invalid username or password");
+    }
+
+    #[tokio::test]
+    async fn login_for_an_unknown_username() {
+        let (service, _, _) = service();
+
+        let result = service.login("nobody", PASSWORD, &client()).await;
+
+        assert!(matches!(result, Err(AuthError::InvalidCredentials)));
+        // A password was checked all the same, against a hash of no one's
+        let dummy_hash = service.config.password.dummy_hash.get().unwrap();
+        assert!(dummy_hash.starts_with("$argon2id$"));
+    }
+
+    #[tokio::test]
+    async fn too_many_failures_lock_the_account() {
+        let (service, _, audit) = service();
+        service
+            .register("lockout", PASSWORD, None, &client())
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            let result = service
+                .login("lockout", "not the password", &client())
+                .await;
+            assert!(matches!(result, Err(AuthError::InvalidCredentials)));
+        }
+
+        // Not even the right password gets through while the account is locked
+        let result = service.login("lockout", PASSWORD, &client()).await;
+        assert!(matches!(result, Err(AuthError::TooManyAttempts(_))));
+        assert!(audit
+            .entries()
+            .iter()
+            .any(|entry| matches!(entry.event, AuditEvent::LoginLocked)));
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_when_deleting_an_account_count_as_failures() {
+        let (service, users, _) = service();
+        let registered = service
+            .register("delete-guess", PASSWORD, None, &client())
+            .await
+            .unwrap();
+        users
+            .set_totp_secret(registered.id, "JBSWY3DPEHPK3PXP")
+            .await
+            .unwrap();
+        users
+            .enable_totp(registered.id, "JBSWY3DPEHPK3PXP", 0, &[])
+            .await
+            .unwrap();
+        let guess = SecondFactor::Totp(String::from("not a code"));
+
+        for _ in 0..3 {
+            let result = service
+                .delete_account(registered.id, PASSWORD, Some(&guess), &client())
+                .await;
+            assert!(matches!(result, Err(AuthError::InvalidMfaCode)));
+        }
+
+        let result = service
+            .delete_account(registered.id, PASSWORD, Some(&guess), &client())
+            .await;
+        assert!(matches!(result, Err(AuthError::TooManyAttempts(_))));
+        assert!(users.find_by_id(registered.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn refresh_rotates_the_refresh_token() {
+        let (service, _, _) = service();
+        let registered = service
+            .register("rotation", PASSWORD, None, &client())
+            .await
+            .unwrap();
+
+        let refreshed = service.refresh(&registered.refresh_token).await.unwrap();
+        assert_ne!(refreshed.refresh_token, registered.refresh_token);
+
+        // The new token is the current one and rotates in turn
+        let again = service.refresh(&refreshed.refresh_token).await.unwrap();
+        assert_ne!(again.refresh_token, refreshed.refresh_token);
+    }
+
+    #[tokio::test]
+    async fn reusing_a_refresh_token_revokes_the_session() {
+        let (service, _, _) = service();
+        let registered = service
+            .register("reuse", PASSWORD, None, &client())
+            .await
+            .unwrap();
+        let refreshed = service.refresh(&registered.refresh_token).await.unwrap();
+
+        let result = service.refresh(&registered.refresh_token).await;
+        assert!(matches!(result, Err(AuthError::TokenReused)));
+
+        // The token the rotation handed out went with the session
+        let result = service.refresh(&refreshed.refresh_token).await;
+        assert!(matches!(result, Err(AuthError::TokenReused)));
+        let sessions = service.sessions(registered.id, "").await.unwrap();
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_refuses_a_token_that_is_not_signed() {
+        let (service, _, _) = service();
+
+        let result = service.refresh("not a token").await;
+
+        assert!(matches!(result, Err(AuthError::InvalidToken)));
     }
 }
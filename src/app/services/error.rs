@@ -0,0 +1,89 @@
+use std::fmt;
+
+use super::{keys::KeyError, password::HashError, validation::ValidationError};
+
+#[derive(Debug)]
+pub enum AuthError {
+    Database(sqlx::Error),
+    InvalidCredentials,
+    InvalidToken,
+    TokenReused,
+    SessionNotFound,
+    UserNotFound,
+    UsernameTaken,
+    Validation(ValidationError),
+    PasswordHash(HashError),
+    InvalidApiKey,
+    ApiKeyNotFound,
+    InvalidMfaToken,
+    InvalidMfaCode,
+    MfaAlreadyEnabled,
+    MfaNotEnabled,
+    // Seconds until the next login attempt is accepted
+    TooManyAttempts(u64),
+    InvalidResetToken,
+    MailDelivery(String),
+    SigningKey(KeyError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Database(error) => write!(f, "{}", error),
+            AuthError::InvalidCredentials => write!(f, "Invalid username or password"),
+            AuthError::InvalidToken => write!(f, "Invalid refresh token"),
+            AuthError::TokenReused => write!(f, "Refresh token has been revoked"),
+            AuthError::SessionNotFound => write!(f, "Session does not exist"),
+            AuthError::UserNotFound => write!(f, "User does not exist"),
+            AuthError::UsernameTaken => write!(f, "Username is already taken"),
+            AuthError::Validation(error) => write!(f, "{}", error),
+            AuthError::PasswordHash(error) => write!(f, "{}", error),
+            AuthError::InvalidApiKey => write!(f, "API key is invalid, expired or revoked"),
+            AuthError::ApiKeyNotFound => write!(f, "API key does not exist"),
+            AuthError::InvalidMfaToken => write!(f, "MFA challenge is invalid or has expired"),
+            AuthError::InvalidMfaCode => write!(f, "Invalid authentication code"),
+            AuthError::MfaAlreadyEnabled => {
+                write!(f, "Two-factor authentication is already enabled")
+            }
+            AuthError::MfaNotEnabled => write!(f, "Two-factor authentication is not set up"),
+            AuthError::TooManyAttempts(_) => {
+                write!(f, "Too many failed login attempts, try again later")
+            }
+            AuthError::InvalidResetToken => write!(f, "Reset link is invalid or has expired"),
+            AuthError::MailDelivery(error) => write!(f, "{}", error),
+            AuthError::SigningKey(error) => write!(f, "Could not sign the token: {}", error),
+        }
+    }
+}
+
+impl From<ValidationError> for AuthError {
+    fn from(error: ValidationError) -> Self {
+        AuthError::Validation(error)
+    }
+}
+
+impl From<HashError> for AuthError {
+    fn from(error: HashError) -> Self {
+        AuthError::PasswordHash(error)
+    }
+}
+
+impl From<KeyError> for AuthError {
+    fn from(error: KeyError) -> Self {
+        AuthError::SigningKey(error)
+    }
+}
+
+impl From<sqlx::Error> for AuthError {
+    fn from(error: sqlx::Error) -> Self {
+        AuthError::Database(error)
+    }
+}
+
+// A clash with a unique constraint, SQLSTATE 23505 on Postgres
+pub fn is_unique_violation(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(error) => error.is_unique_violation(),
+        _ => false,
+    }
+}
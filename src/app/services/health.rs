@@ -0,0 +1,126 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::time::timeout;
+
+use crate::{
+    app::models::health::{Check, HealthStatus, Liveness, Readiness},
+    config::JwtConfig,
+    db::{migrate, DatabasePool},
+    http::thread_pool,
+};
+
+use super::{
+    keys::{is_asymmetric, keyring},
+    utils::current_timestamp,
+};
+
+// A readiness probe must answer quickly, a check that takes longer counts as failing
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+static STARTED_AT: AtomicUsize = AtomicUsize::new(0);
+static READY: AtomicBool = AtomicBool::new(false);
+
+pub fn mark_started() {
+    STARTED_AT.store(current_timestamp(), Ordering::Relaxed);
+}
+
+// Called once startup, migrations included, has finished
+pub fn mark_ready() {
+    READY.store(true, Ordering::Relaxed);
+}
+
+pub fn liveness() -> Liveness {
+    Liveness {
+        status: HealthStatus::Ok,
+        uptime_secs: current_timestamp().saturating_sub(STARTED_AT.load(Ordering::Relaxed)),
+    }
+}
+
+// Whatever tokens are signed with has to be there, checked at startup and on every probe
+pub fn check_signing_keys(jwt: &JwtConfig) -> Result<(), String> {
+    if is_asymmetric(jwt.algorithm) {
+        keyring(jwt).map_err(|error| error.to_string())?;
+    } else if jwt.secret.is_none() {
+        return Err(String::from("JWT_SECRET is not set"));
+    }
+
+    if jwt.refresh_secret.is_none() {
+        return Err(String::from("REFRESH_TOKEN_SECRET is not set"));
+    }
+
+    Ok(())
+}
+
+async fn within_timeout<T, E: ToString>(
+    check: impl Future<Output = Result<T, E>>,
+) -> Result<T, String> {
+    match timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result.map_err(|error| error.to_string()),
+        Err(_) => Err(format!("No answer within {}s", CHECK_TIMEOUT.as_secs())),
+    }
+}
+
+async fn check_database(pool: &DatabasePool, checks: &mut BTreeMap<&'static str, Check>) {
+    if let Err(error) = within_timeout(pool.ping()).await {
+        checks.insert("database", Check::failing(error));
+        checks.insert(
+            "migrations",
+            Check::failing(String::from("Database is unreachable")),
+        );
+        return;
+    }
+    checks.insert("database", Check::ok(None));
+
+    let migrations = match within_timeout(migrate::status(pool)).await {
+        Ok(migrations) => {
+            let pending = migrations
+                .iter()
+                .filter(|(_, applied_at)| applied_at.is_none())
+                .count();
+            match pending {
+                0 => Check::ok(None),
+                _ => Check::failing(format!("{} migrations pending", pending)),
+            }
+        }
+        Err(error) => Check::failing(error),
+    };
+    checks.insert("migrations", migrations);
+}
+
+pub async fn readiness(jwt: &JwtConfig, db: &DatabasePool) -> Readiness {
+    let mut checks = BTreeMap::new();
+
+    let startup = match READY.load(Ordering::Relaxed) {
+        true => Check::ok(None),
+        false => Check::failing(String::from("Still starting")),
+    };
+    checks.insert("startup", startup);
+
+    check_database(db, &mut checks).await;
+
+    let signing_keys = match check_signing_keys(jwt) {
+        Ok(_) => Check::ok(None),
+        Err(error) => Check::failing(error),
+    };
+    checks.insert("signing_keys", signing_keys);
+
+    let load = thread_pool::load();
+    let detail = format!(
+        "{} of {} workers busy, {} requests queued",
+        load.busy, load.workers, load.queued
+    );
+    let thread_pool = match load.is_saturated() {
+        true => Check::failing(detail),
+        false => Check::ok(Some(detail)),
+    };
+    checks.insert("thread_pool", thread_pool);
+
+    Readiness {
+        ready: checks.values().all(Check::is_ok),
+        checks,
+    }
+}
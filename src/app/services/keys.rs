@@ -0,0 +1,474 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::{app::models::jwk::Jwk, config::JwtConfig, logging};
+
+use super::utils::{access_token_expiration_secs, current_timestamp, generate_id};
+
+const MANIFEST_FILE: &str = "keys.json";
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(io::Error),
+    InvalidKey(String),
+    UnsupportedAlgorithm(String),
+    NoActiveKey,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(error) => write!(f, "{}", error),
+            KeyError::InvalidKey(message) => write!(f, "Invalid signing key: {}", message),
+            KeyError::UnsupportedAlgorithm(alg) => {
+                write!(f, "{} cannot be used for asymmetric signing", alg)
+            }
+            KeyError::NoActiveKey => write!(f, "No active signing key, roll one first"),
+        }
+    }
+}
+
+impl From<io::Error> for KeyError {
+    fn from(error: io::Error) -> Self {
+        KeyError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for KeyError {
+    fn from(error: serde_json::Error) -> Self {
+        KeyError::InvalidKey(error.to_string())
+    }
+}
+
+// Everything the server needs to know about a key apart from the key material
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyMetadata {
+    pub kid: String,
+    pub alg: Algorithm,
+    pub created_at: usize,
+    pub retired_at: Option<usize>,
+}
+
+impl KeyMetadata {
+    // Tokens signed by a retired key stay verifiable until the last of them has
+    // expired, `token_lifetime` seconds after it was retired
+    pub fn verifiable_until(&self, token_lifetime: usize) -> Option<usize> {
+        self.retired_at
+            .map(|retired_at| retired_at + token_lifetime)
+    }
+
+    fn is_verifiable(&self, now: usize, token_lifetime: usize) -> bool {
+        self.verifiable_until(token_lifetime)
+            .is_none_or(|verifiable_until| verifiable_until > now)
+    }
+}
+
+// `keys.json` in the keys directory. Each key is stored next to it as a
+// `<kid>.pem` private key and a `<kid>.pub.pem` public key.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    active: Option<String>,
+    keys: Vec<KeyMetadata>,
+}
+
+impl Manifest {
+    fn load(dir: &Path) -> Result<Self, KeyError> {
+        match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), KeyError> {
+        // Write to a temporary file first so a running server never reads half a manifest
+        let temporary = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        fs::write(&temporary, serde_json::to_string_pretty(self)?)?;
+        fs::rename(temporary, dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+pub struct SigningKey {
+    pub kid: String,
+    pub alg: Algorithm,
+    pub encoding_key: EncodingKey,
+}
+
+pub struct VerificationKey {
+    pub metadata: KeyMetadata,
+    pub public_pem: String,
+    pub decoding_key: DecodingKey,
+}
+
+impl VerificationKey {
+    pub fn to_jwk(&self) -> Result<Jwk, KeyError> {
+        use p256::{elliptic_curve::sec1::ToEncodedPoint, pkcs8::DecodePublicKey};
+        use rsa::traits::PublicKeyParts;
+
+        let invalid = |error: &dyn fmt::Display| KeyError::InvalidKey(error.to_string());
+        let encode = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+        let pem = self.public_pem.as_str();
+
+        let mut jwk = Jwk {
+            kty: String::new(),
+            kid: self.metadata.kid.clone(),
+            alg: format!("{:?}", self.metadata.alg),
+            key_use: String::from("sig"),
+            n: None,
+            e: None,
+            crv: None,
+            x: None,
+            y: None,
+        };
+
+        match self.metadata.alg {
+            Algorithm::RS256 => {
+                let key =
+                    rsa::RsaPublicKey::from_public_key_pem(pem).map_err(|error| invalid(&error))?;
+                jwk.kty = String::from("RSA");
+                jwk.n = Some(encode(&key.n().to_bytes_be()));
+                jwk.e = Some(encode(&key.e().to_bytes_be()));
+            }
+            Algorithm::ES256 => {
+                let key =
+                    p256::PublicKey::from_public_key_pem(pem).map_err(|error| invalid(&error))?;
+                let point = key.to_encoded_point(false);
+                jwk.kty = String::from("EC");
+                jwk.crv = Some(String::from("P-256"));
+                jwk.x = point.x().map(|x| encode(x));
+                jwk.y = point.y().map(|y| encode(y));
+            }
+            Algorithm::EdDSA => {
+                let key = ed25519_dalek::VerifyingKey::from_public_key_pem(pem)
+                    .map_err(|error| invalid(&error))?;
+                jwk.kty = String::from("OKP");
+                jwk.crv = Some(String::from("Ed25519"));
+                jwk.x = Some(encode(key.as_bytes()));
+            }
+            alg => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", alg))),
+        }
+
+        Ok(jwk)
+    }
+}
+
+pub struct Keyring {
+    signing_key: SigningKey,
+    verification_keys: Vec<VerificationKey>,
+    token_lifetime: usize,
+}
+
+impl Keyring {
+    pub fn load(dir: &Path, token_lifetime: usize) -> Result<Self, KeyError> {
+        let manifest = Manifest::load(dir)?;
+        let active = manifest.active.as_ref().ok_or(KeyError::NoActiveKey)?;
+        let now = current_timestamp();
+
+        let mut signing_key = None;
+        let mut verification_keys = vec![];
+
+        for metadata in manifest
+            .keys
+            .iter()
+            .filter(|key| key.is_verifiable(now, token_lifetime))
+        {
+            if &metadata.kid == active {
+                let private_pem = fs::read(private_key_path(dir, &metadata.kid))?;
+                signing_key = Some(SigningKey {
+                    kid: metadata.kid.clone(),
+                    alg: metadata.alg,
+                    encoding_key: encoding_key(metadata.alg, &private_pem)?,
+                });
+            }
+
+            let public_pem = fs::read_to_string(public_key_path(dir, &metadata.kid))?;
+            verification_keys.push(VerificationKey {
+                decoding_key: decoding_key(metadata.alg, public_pem.as_bytes())?,
+                metadata: metadata.clone(),
+                public_pem,
+            });
+        }
+
+        Ok(Keyring {
+            signing_key: signing_key.ok_or(KeyError::NoActiveKey)?,
+            verification_keys,
+            token_lifetime,
+        })
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.signing_key
+    }
+
+    // Checked at every lookup rather than on load, so a retired key stops being
+    // trusted once its tokens have expired even if keys.json never changes
+    fn live_keys(&self) -> impl Iterator<Item = &VerificationKey> {
+        let now = current_timestamp();
+        self.verification_keys
+            .iter()
+            .filter(move |key| key.metadata.is_verifiable(now, self.token_lifetime))
+    }
+
+    pub fn verification_key(&self, kid: &str) -> Option<&VerificationKey> {
+        self.live_keys().find(|key| key.metadata.kid == kid)
+    }
+
+    pub fn verification_keys(&self) -> Vec<&VerificationKey> {
+        self.live_keys().collect()
+    }
+}
+
+fn private_key_path(dir: &Path, kid: &str) -> PathBuf {
+    dir.join(format!("{}.pem", kid))
+}
+
+fn public_key_path(dir: &Path, kid: &str) -> PathBuf {
+    dir.join(format!("{}.pub.pem", kid))
+}
+
+fn encoding_key(alg: Algorithm, pem: &[u8]) -> Result<EncodingKey, KeyError> {
+    let key = match alg {
+        Algorithm::RS256 => EncodingKey::from_rsa_pem(pem),
+        Algorithm::ES256 => EncodingKey::from_ec_pem(pem),
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(pem),
+        _ => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", alg))),
+    };
+
+    key.map_err(|error| KeyError::InvalidKey(error.to_string()))
+}
+
+fn decoding_key(alg: Algorithm, pem: &[u8]) -> Result<DecodingKey, KeyError> {
+    let key = match alg {
+        Algorithm::RS256 => DecodingKey::from_rsa_pem(pem),
+        Algorithm::ES256 => DecodingKey::from_ec_pem(pem),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+        _ => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", alg))),
+    };
+
+    key.map_err(|error| KeyError::InvalidKey(error.to_string()))
+}
+
+// Generates a PKCS#8 private key and its SPKI public key, both PEM encoded
+fn generate_key_pair(alg: Algorithm) -> Result<(String, String), KeyError> {
+    use p256::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+
+    let invalid = |error: &dyn fmt::Display| KeyError::InvalidKey(error.to_string());
+
+    match alg {
+        Algorithm::RS256 => {
+            let private_key =
+                rsa::RsaPrivateKey::new(&mut OsRng, 2048).map_err(|error| invalid(&error))?;
+            let private_pem = private_key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|error| invalid(&error))?;
+            let public_pem = private_key
+                .to_public_key()
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|error| invalid(&error))?;
+
+            Ok((private_pem.to_string(), public_pem))
+        }
+        Algorithm::ES256 => {
+            let private_key = p256::SecretKey::random(&mut OsRng);
+            let private_pem = private_key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|error| invalid(&error))?;
+            let public_pem = private_key
+                .public_key()
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|error| invalid(&error))?;
+
+            Ok((private_pem.to_string(), public_pem))
+        }
+        Algorithm::EdDSA => {
+            let private_key = ed25519_dalek::SigningKey::generate(&mut OsRng);
+            let private_pem = private_key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|error| invalid(&error))?;
+            let public_pem = private_key
+                .verifying_key()
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|error| invalid(&error))?;
+
+            Ok((private_pem.to_string(), public_pem))
+        }
+        _ => Err(KeyError::UnsupportedAlgorithm(format!("{:?}", alg))),
+    }
+}
+
+fn write_private_key(path: &Path, pem: &str) -> Result<(), KeyError> {
+    fs::write(path, pem)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    Ok(())
+}
+
+// Generates a new signing key and makes it the active one. The previous key is
+// retired but kept for verification until tokens signed with it have expired,
+// and keys that are past that point are deleted.
+pub fn roll_key(
+    dir: &Path,
+    alg: Algorithm,
+    token_lifetime: usize,
+) -> Result<KeyMetadata, KeyError> {
+    fs::create_dir_all(dir)?;
+
+    let mut manifest = Manifest::load(dir)?;
+    let now = current_timestamp();
+    let kid = generate_id();
+
+    let (private_pem, public_pem) = generate_key_pair(alg)?;
+    write_private_key(&private_key_path(dir, &kid), &private_pem)?;
+    fs::write(public_key_path(dir, &kid), public_pem)?;
+
+    for key in manifest.keys.iter_mut() {
+        if key.retired_at.is_none() {
+            key.retired_at = Some(now);
+        }
+    }
+
+    let (verifiable, expired): (Vec<KeyMetadata>, Vec<KeyMetadata>) = manifest
+        .keys
+        .drain(..)
+        .partition(|key| key.is_verifiable(now, token_lifetime));
+
+    let metadata = KeyMetadata {
+        kid: kid.clone(),
+        alg,
+        created_at: now,
+        retired_at: None,
+    };
+
+    manifest.keys = verifiable;
+    manifest.keys.push(metadata.clone());
+    manifest.active = Some(kid);
+    manifest.save(dir)?;
+
+    for key in expired {
+        let _ = fs::remove_file(private_key_path(dir, &key.kid));
+        let _ = fs::remove_file(public_key_path(dir, &key.kid));
+    }
+
+    Ok(metadata)
+}
+
+pub fn list_keys(dir: &Path) -> Result<(Option<String>, Vec<KeyMetadata>), KeyError> {
+    let manifest = Manifest::load(dir)?;
+    Ok((manifest.active, manifest.keys))
+}
+
+pub fn is_asymmetric(alg: Algorithm) -> bool {
+    matches!(alg, Algorithm::RS256 | Algorithm::ES256 | Algorithm::EdDSA)
+}
+
+struct CachedKeyring {
+    modified: SystemTime,
+    keyring: Arc<Keyring>,
+}
+
+static KEYRING: Mutex<Option<CachedKeyring>> = Mutex::new(None);
+
+// Returns the keyring, reloading it whenever the manifest changes on disk so a
+// key rolled by another process is picked up without restarting the server.
+// When the keys cannot be read again the last keyring that loaded is kept.
+// The keys are in JWT_KEYS_DIR.
+pub fn keyring(jwt: &JwtConfig) -> Result<Arc<Keyring>, KeyError> {
+    let dir = &jwt.keys_dir;
+    let modified = fs::metadata(dir.join(MANIFEST_FILE)).and_then(|metadata| metadata.modified());
+
+    let mut cached = KEYRING.lock().unwrap();
+    if let (Ok(modified), Some(cached)) = (&modified, cached.as_ref()) {
+        if cached.modified == *modified {
+            return Ok(Arc::clone(&cached.keyring));
+        }
+    }
+
+    let loaded = modified
+        .map_err(|_| KeyError::NoActiveKey)
+        .and_then(|modified| {
+            let keyring = Keyring::load(dir, access_token_expiration_secs(jwt))?;
+            Ok((modified, Arc::new(keyring)))
+        });
+
+    match (loaded, cached.as_ref()) {
+        (Ok((modified, keyring)), _) => {
+            *cached = Some(CachedKeyring {
+                modified,
+                keyring: Arc::clone(&keyring),
+            });
+            Ok(keyring)
+        }
+        (Err(error), Some(cached)) => {
+            logging::warn(
+                "Could not reload the JWT signing keys, keeping the ones loaded before",
+                &[("error", error.to_string().into())],
+            );
+            Ok(Arc::clone(&cached.keyring))
+        }
+        (Err(error), None) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::{thread, time::Duration};
+
+    fn keys_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("keys-{}", generate_id()))
+    }
+
+    #[test]
+    fn retired_keys_stop_verifying_once_their_tokens_have_expired() {
+        let dir = keys_dir();
+        let retired = roll_key(&dir, Algorithm::EdDSA, 2).unwrap();
+        let active = roll_key(&dir, Algorithm::EdDSA, 2).unwrap();
+
+        let keyring = Keyring::load(&dir, 2).unwrap();
+        assert!(keyring.verification_key(&retired.kid).is_some());
+        assert_eq!(keyring.verification_keys().len(), 2);
+
+        // Nothing on disk changes, the same keyring drops the key by itself
+        thread::sleep(Duration::from_secs(3));
+        assert!(keyring.verification_key(&retired.kid).is_none());
+        assert!(keyring.verification_key(&active.kid).is_some());
+        assert_eq!(keyring.verification_keys().len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_last_good_keyring_is_kept_when_the_keys_cannot_be_read() {
+        let dir = keys_dir();
+        let active = roll_key(&dir, Algorithm::EdDSA, 900).unwrap();
+        let flags = [
+            String::from("--exp=900"),
+            String::from("--jwt-algorithm=EdDSA"),
+            format!("--jwt-keys-dir={}", dir.display()),
+        ];
+        let (config, _) = Config::load(&flags).unwrap();
+
+        assert_eq!(keyring(&config.jwt).unwrap().signing_key().kid, active.kid);
+
+        thread::sleep(Duration::from_millis(20));
+        fs::write(dir.join(MANIFEST_FILE), "not a manifest").unwrap();
+        assert_eq!(keyring(&config.jwt).unwrap().signing_key().kid, active.kid);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
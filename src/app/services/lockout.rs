@@ -0,0 +1,319 @@
+use async_trait::async_trait;
+use sqlx::{postgres::PgPool, Row};
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::config::{AttemptStoreKind, LoginConfig};
+
+use super::{
+    audit::{AuditEvent, AuditLog},
+    error::AuthError,
+    utils::current_timestamp,
+    validation::username_key,
+};
+
+// Failed login attempts counted against one key, which is either a username or a client IP
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AttemptState {
+    pub failures: i32,
+    pub last_failure_at: i64,
+    pub locked_until: Option<i64>,
+}
+
+// Where failed attempts are counted. Postgres keeps the counters across
+// restarts and shares them between instances, the in-memory store is for
+// single instance setups without that need.
+#[async_trait]
+pub trait AttemptStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<AttemptState>, AuthError>;
+
+    // Counts one more failure, starting over when the previous one is older
+    // than `window_secs`
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        window_secs: i64,
+    ) -> Result<AttemptState, AuthError>;
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), AuthError>;
+
+    async fn clear(&self, key: &str) -> Result<(), AuthError>;
+}
+
+pub struct PgAttemptStore {
+    pool: PgPool,
+}
+
+impl PgAttemptStore {
+    pub fn new(pool: PgPool) -> Self {
+        PgAttemptStore { pool }
+    }
+}
+
+#[async_trait]
+impl AttemptStore for PgAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<AttemptState>, AuthError> {
+        let query =
+            "SELECT failures, last_failure_at, locked_until FROM login_attempts WHERE key = $1";
+        let result = sqlx::query(query)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        match result {
+            Some(row) => Ok(Some(AttemptState {
+                failures: row.try_get("failures")?,
+                last_failure_at: row.try_get("last_failure_at")?,
+                locked_until: row.try_get("locked_until")?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        window_secs: i64,
+    ) -> Result<AttemptState, AuthError> {
+        // A single statement, so concurrent failures are all counted
+        let query = "INSERT INTO login_attempts (key, failures, last_failure_at) VALUES ($1, 1, $2) ON CONFLICT (key) DO UPDATE SET failures = CASE WHEN login_attempts.last_failure_at < $2 - $3 THEN 1 ELSE login_attempts.failures + 1 END, last_failure_at = $2 RETURNING failures, last_failure_at, locked_until";
+        let row = sqlx::query(query)
+            .bind(key)
+            .bind(now)
+            .bind(window_secs)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(AttemptState {
+            failures: row.try_get("failures")?,
+            last_failure_at: row.try_get("last_failure_at")?,
+            locked_until: row.try_get("locked_until")?,
+        })
+    }
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), AuthError> {
+        let query = "UPDATE login_attempts SET locked_until = $1 WHERE key = $2";
+        sqlx::query(query)
+            .bind(until)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), AuthError> {
+        let query = "DELETE FROM login_attempts WHERE key = $1";
+        sqlx::query(query).bind(key).execute(&self.pool).await?;
+
+        Ok(())
+    }
+}
+
+// Services, and the store with them, are built anew for every request, so the
+// counters live in a static that outlasts them
+static MEMORY_ATTEMPTS: Mutex<BTreeMap<String, AttemptState>> = Mutex::new(BTreeMap::new());
+
+pub struct MemoryAttemptStore;
+
+#[async_trait]
+impl AttemptStore for MemoryAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<AttemptState>, AuthError> {
+        Ok(MEMORY_ATTEMPTS.lock().unwrap().get(key).copied())
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        window_secs: i64,
+    ) -> Result<AttemptState, AuthError> {
+        let mut attempts = MEMORY_ATTEMPTS.lock().unwrap();
+
+        // Forget keys that have nothing left to enforce, so the map does not keep growing
+        attempts.retain(|_, state| {
+            state.last_failure_at >= now - window_secs
+                || state.locked_until.is_some_and(|until| until > now)
+        });
+
+        let state = attempts.entry(key.to_string()).or_default();
+        if state.last_failure_at < now - window_secs {
+            state.failures = 0;
+        }
+        state.failures += 1;
+        state.last_failure_at = now;
+
+        Ok(*state)
+    }
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), AuthError> {
+        if let Some(state) = MEMORY_ATTEMPTS.lock().unwrap().get_mut(key) {
+            state.locked_until = Some(until);
+        }
+
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), AuthError> {
+        MEMORY_ATTEMPTS.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+// Picked with LOGIN_ATTEMPT_STORE, either `postgres` (the default) or `memory`
+pub fn attempt_store(login: &LoginConfig, pool: &PgPool) -> Box<dyn AttemptStore> {
+    match login.attempt_store {
+        AttemptStoreKind::Memory => Box::new(MemoryAttemptStore),
+        AttemptStoreKind::Postgres => Box::new(PgAttemptStore::new(pool.clone())),
+    }
+}
+
+// Set with LOGIN_MAX_FAILURES, LOGIN_MAX_FAILURES_PER_IP, LOGIN_LOCKOUT_SECS,
+// LOGIN_FAILURE_WINDOW_SECS and LOGIN_MAX_DELAY_SECS
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    pub max_failures_per_user: i32,
+    pub max_failures_per_ip: i32,
+    pub lockout_secs: i64,
+    pub window_secs: i64,
+    pub max_delay_secs: i64,
+}
+
+impl LockoutPolicy {
+    // Each failure doubles the time until the next attempt is accepted
+    fn delay_secs(&self, failures: i32) -> i64 {
+        if failures <= 0 {
+            return 0;
+        }
+
+        2i64.saturating_pow((failures - 1) as u32)
+            .min(self.max_delay_secs)
+    }
+
+    // Seconds until a key may try again, if it has to wait at all
+    fn retry_after(&self, state: &AttemptState, now: i64) -> Option<i64> {
+        if let Some(until) = state.locked_until.filter(|until| *until > now) {
+            return Some(until - now);
+        }
+
+        if state.last_failure_at < now - self.window_secs {
+            return None;
+        }
+
+        let allowed_at = state.last_failure_at + self.delay_secs(state.failures);
+        Some(allowed_at - now).filter(|secs| *secs > 0)
+    }
+}
+
+// Counted on the normalized name, so `Alice` and `ALICE` share one counter
+fn user_key(username: &str) -> String {
+    format!("user:{}", username_key(username))
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+// Counts failed logins per username and per client IP. Both slow down with every
+// failure and are locked for a while once they reach their limit.
+pub struct LoginThrottle {
+    store: Box<dyn AttemptStore>,
+    policy: LockoutPolicy,
+}
+
+impl LoginThrottle {
+    pub fn new(store: Box<dyn AttemptStore>, policy: LockoutPolicy) -> Self {
+        LoginThrottle { store, policy }
+    }
+
+    fn keys(&self, username: &str, ip: Option<&str>) -> Vec<(String, i32)> {
+        let mut keys = vec![(user_key(username), self.policy.max_failures_per_user)];
+        if let Some(ip) = ip {
+            keys.push((ip_key(ip), self.policy.max_failures_per_ip));
+        }
+
+        keys
+    }
+
+    // Refuses an attempt while the username or the IP has to wait
+    pub async fn check(&self, username: &str, ip: Option<&str>) -> Result<(), AuthError> {
+        let now = current_timestamp() as i64;
+
+        for (key, _) in self.keys(username, ip) {
+            let retry_after = match self.store.get(&key).await? {
+                Some(state) => self.policy.retry_after(&state, now),
+                None => None,
+            };
+
+            if let Some(secs) = retry_after {
+                return Err(AuthError::TooManyAttempts(secs as u64));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Counts a failure and returns the keys it locked
+    pub async fn record_failure(
+        &self,
+        username: &str,
+        ip: Option<&str>,
+    ) -> Result<Vec<String>, AuthError> {
+        let now = current_timestamp() as i64;
+        let mut locked = Vec::new();
+
+        for (key, max_failures) in self.keys(username, ip) {
+            let state = self
+                .store
+                .record_failure(&key, now, self.policy.window_secs)
+                .await?;
+
+            if state.failures >= max_failures {
+                self.store
+                    .lock(&key, now + self.policy.lockout_secs)
+                    .await?;
+                locked.push(key);
+            }
+        }
+
+        Ok(locked)
+    }
+
+    // Counts a failed attempt and writes it, and any lockout it caused, to the
+    // audit log. Returns the error to fail the attempt with.
+    pub async fn fail(
+        &self,
+        audit: &dyn AuditLog,
+        username: &str,
+        ip: Option<&str>,
+        error: AuthError,
+    ) -> AuthError {
+        let locked = match self.record_failure(username, ip).await {
+            Ok(locked) => locked,
+            Err(store_error) => return store_error,
+        };
+
+        audit
+            .record(AuditEvent::LoginFailed, username, ip, &error.to_string())
+            .await;
+        for key in locked {
+            let detail = format!("{} locked after too many failures", key);
+            audit
+                .record(AuditEvent::LoginLocked, username, ip, &detail)
+                .await;
+        }
+
+        error
+    }
+
+    // Only the username is cleared on success, so signing in to one account
+    // does not reset the counter of an IP guessing at others
+    pub async fn record_success(&self, username: &str) -> Result<(), AuthError> {
+        self.store.clear(&user_key(username)).await
+    }
+
+    pub async fn unlock(&self, username: &str) -> Result<(), AuthError> {
+        self.store.clear(&user_key(username)).await
+    }
+}
@@ -0,0 +1,162 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use std::{fmt, fs::OpenOptions, io::Write};
+
+use crate::config::{MailConfig, MailerKind};
+
+use super::utils::current_timestamp;
+
+#[derive(Debug, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not send mail: {}", self.0)
+    }
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+// Keeps mail in the `mail_outbox` table instead of sending it, so the flows
+// that send mail work offline and their messages can be inspected
+pub struct OutboxMailer {
+    pool: PgPool,
+}
+
+impl OutboxMailer {
+    pub fn new(pool: PgPool) -> Self {
+        OutboxMailer { pool }
+    }
+}
+
+#[async_trait]
+impl Mailer for OutboxMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let query =
+            "INSERT INTO mail_outbox (recipient, subject, body, created_at) VALUES ($1, $2, $3, $4)";
+        sqlx::query(query)
+            .bind(&email.to)
+            .bind(&email.subject)
+            .bind(&email.body)
+            .bind(current_timestamp() as i64)
+            .execute(&self.pool)
+            .await
+            .map_err(|error| MailError(error.to_string()))?;
+
+        Ok(())
+    }
+}
+
+// Appends every message as a JSON line to a local file
+pub struct FileMailer {
+    path: String,
+}
+
+impl FileMailer {
+    pub fn new(path: String) -> Self {
+        FileMailer { path }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let line = serde_json::to_string(email).map_err(|error| MailError(error.to_string()))?;
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(|error| MailError(error.to_string()))
+    }
+}
+
+#[cfg(feature = "smtp")]
+pub struct SmtpMailer {
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+    from: lettre::message::Mailbox,
+}
+
+#[cfg(feature = "smtp")]
+impl SmtpMailer {
+    // Configured with SMTP_HOST, SMTP_PORT, SMTP_USERNAME and SMTP_PASSWORD.
+    // Connections are upgraded with STARTTLS.
+    pub fn new(config: &MailConfig) -> Result<Self, MailError> {
+        use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport};
+
+        let host = config
+            .smtp_host
+            .as_deref()
+            .ok_or_else(|| MailError(String::from("SMTP_HOST is not provided")))?;
+
+        let mut builder = AsyncSmtpTransport::<lettre::Tokio1Executor>::starttls_relay(host)
+            .map_err(|error| MailError(error.to_string()))?
+            .port(config.smtp_port);
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let from = config
+            .from
+            .parse()
+            .map_err(|_| MailError(String::from("MAIL_FROM is not a valid address")))?;
+
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[cfg(feature = "smtp")]
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        use lettre::{AsyncTransport, Message};
+
+        let to = email
+            .to
+            .parse()
+            .map_err(|_| MailError(format!("{} is not a valid address", email.to)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&email.subject)
+            .body(email.body.clone())
+            .map_err(|error| MailError(error.to_string()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|error| MailError(error.to_string()))?;
+
+        Ok(())
+    }
+}
+
+// Picked with MAILER: `outbox` (the default), `file` to write to MAIL_OUTBOX_PATH,
+// or `smtp` when the crate is built with the `smtp` feature
+pub fn mailer(config: &MailConfig, pool: &PgPool) -> Result<Box<dyn Mailer>, MailError> {
+    match config.mailer {
+        MailerKind::File => Ok(Box::new(FileMailer::new(config.outbox_path.clone()))),
+        #[cfg(feature = "smtp")]
+        MailerKind::Smtp => Ok(Box::new(SmtpMailer::new(config)?)),
+        #[cfg(not(feature = "smtp"))]
+        MailerKind::Smtp => Err(MailError(String::from(
+            "SMTP support is not compiled in, build with the `smtp` feature",
+        ))),
+        MailerKind::Outbox => Ok(Box::new(OutboxMailer::new(pool.clone()))),
+    }
+}
@@ -0,0 +1,283 @@
+use std::sync::Arc;
+
+use crate::{
+    app::{
+        models::{
+            mfa::{RecoveryCodes, TotpSetup},
+            session::ClientInfo,
+        },
+        repositories::user::{UserRecord, UserRepository},
+    },
+    config::Config,
+    db::DatabasePool,
+};
+
+use super::{
+    audit::AuditLog,
+    auth::storage,
+    error::AuthError,
+    lockout::LoginThrottle,
+    totp::{generate_secret, provisioning_uri, verify_code},
+    utils::{current_timestamp, hash_token, random_hex},
+};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+// What a user can prove the second step of a login with
+#[derive(Debug)]
+pub enum SecondFactor {
+    Totp(String),
+    RecoveryCode(String),
+}
+
+// Recovery codes are handed out as `xxxx-xxxx`, but are accepted in any case
+// and with or without the dash
+fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_ascii_lowercase().replace('-', "")
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = random_hex(4);
+            format!("{}-{}", &code[..4], &code[4..])
+        })
+        .collect()
+}
+
+// Checks a second factor of a user with two-factor authentication enabled.
+// Both a TOTP code and a recovery code can only be used once.
+pub async fn verify_second_factor<U: UserRepository + ?Sized>(
+    uid: i32,
+    factor: &SecondFactor,
+    users: &U,
+) -> Result<(), AuthError> {
+    let accepted = match factor {
+        SecondFactor::Totp(code) => {
+            let secret = match users.find_by_id(uid).await? {
+                Some(UserRecord {
+                    totp_enabled: true,
+                    totp_secret: Some(secret),
+                    ..
+                }) => secret,
+                _ => return Err(AuthError::MfaNotEnabled),
+            };
+
+            let step = verify_code(&secret, code, current_timestamp() as u64)
+                .ok_or(AuthError::InvalidMfaCode)?;
+
+            // Only move forward, so a code that has been seen once cannot be replayed
+            // within its validity window
+            users.advance_totp_step(uid, step).await?
+        }
+        SecondFactor::RecoveryCode(code) => {
+            let code_hash = hash_token(&normalize_recovery_code(code));
+            users.consume_recovery_code(uid, &code_hash).await?
+        }
+    };
+
+    match accepted {
+        true => Ok(()),
+        false => Err(AuthError::InvalidMfaCode),
+    }
+}
+
+// Generic over where users are kept, like `AuthService`
+pub struct MfaService<U = Box<dyn UserRepository>> {
+    config: Arc<Config>,
+    users: U,
+    throttle: LoginThrottle,
+    audit: Box<dyn AuditLog>,
+}
+
+impl MfaService {
+    pub fn new(config: Arc<Config>, db: &DatabasePool) -> Self {
+        let (users, _, attempts, audit) = storage(&config, db);
+        let throttle = LoginThrottle::new(attempts, config.login.lockout);
+        MfaService::with_repositories(config, users, throttle, audit)
+    }
+}
+
+impl<U: UserRepository> MfaService<U> {
+    pub fn with_repositories(
+        config: Arc<Config>,
+        users: U,
+        throttle: LoginThrottle,
+        audit: Box<dyn AuditLog>,
+    ) -> Self {
+        MfaService {
+            config,
+            users,
+            throttle,
+            audit,
+        }
+    }
+
+    // Starts enrollment with a fresh secret. It only takes effect once confirmed,
+    // so starting over just replaces a secret that was never confirmed.
+    pub async fn setup_totp(&self, uid: i32) -> Result<TotpSetup, AuthError> {
+        let user = self
+            .users
+            .find_by_id(uid)
+            .await?
+            .ok_or(AuthError::UserNotFound)?;
+        if user.totp_enabled {
+            return Err(AuthError::MfaAlreadyEnabled);
+        }
+
+        let secret = generate_secret();
+        self.users.set_totp_secret(uid, &secret).await?;
+
+        Ok(TotpSetup {
+            otpauth_uri: provisioning_uri(&self.config.auth.totp_issuer, &secret, &user.username),
+            secret,
+        })
+    }
+
+    // Turns two-factor authentication on once the user shows their authenticator
+    // produces the right codes, and hands out the recovery codes
+    pub async fn confirm_totp(&self, uid: i32, code: &str) -> Result<RecoveryCodes, AuthError> {
+        let user = self
+            .users
+            .find_by_id(uid)
+            .await?
+            .ok_or(AuthError::UserNotFound)?;
+        let secret = match (user.totp_secret, user.totp_enabled) {
+            (_, true) => return Err(AuthError::MfaAlreadyEnabled),
+            (Some(secret), false) => secret,
+            (None, false) => return Err(AuthError::MfaNotEnabled),
+        };
+
+        let step = verify_code(&secret, code, current_timestamp() as u64)
+            .ok_or(AuthError::InvalidMfaCode)?;
+
+        let recovery_codes = generate_recovery_codes();
+        let code_hashes: Vec<String> = recovery_codes
+            .iter()
+            .map(|code| hash_token(&normalize_recovery_code(code)))
+            .collect();
+
+        // Guarded by the secret, so a setup restarted in the meantime is not confirmed
+        match self
+            .users
+            .enable_totp(uid, &secret, step, &code_hashes)
+            .await?
+        {
+            true => Ok(RecoveryCodes { recovery_codes }),
+            false => Err(AuthError::InvalidMfaCode),
+        }
+    }
+
+    // Wrong codes count against the account like failed logins, so a stolen
+    // access token cannot be used to guess at the second factor
+    pub async fn disable_totp(
+        &self,
+        uid: i32,
+        factor: &SecondFactor,
+        client: &ClientInfo,
+    ) -> Result<(), AuthError> {
+        let username = self
+            .users
+            .find_by_id(uid)
+            .await?
+            .ok_or(AuthError::UserNotFound)?
+            .username;
+        let ip = client.ip.as_deref();
+        self.throttle.check(&username, ip).await?;
+
+        match verify_second_factor(uid, factor, &self.users).await {
+            Ok(_) => self.throttle.record_success(&username).await?,
+            Err(AuthError::InvalidMfaCode) => {
+                return Err(self
+                    .throttle
+                    .fail(
+                        self.audit.as_ref(),
+                        &username,
+                        ip,
+                        AuthError::InvalidMfaCode,
+                    )
+                    .await);
+            }
+            Err(error) => return Err(error),
+        }
+
+        self.users.disable_totp(uid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::repositories::user::MemoryUserRepository;
+
+    async fn user_with_recovery_codes(users: &MemoryUserRepository, codes: &[String]) -> i32 {
+        let user = users.create("recovery", "hash", None).await.unwrap();
+        let code_hashes: Vec<String> = codes
+            .iter()
+            .map(|code| hash_token(&normalize_recovery_code(code)))
+            .collect();
+        users
+            .set_totp_secret(user.id, "JBSWY3DPEHPK3PXP")
+            .await
+            .unwrap();
+        users
+            .enable_totp(user.id, "JBSWY3DPEHPK3PXP", 0, &code_hashes)
+            .await
+            .unwrap();
+
+        user.id
+    }
+
+    #[test]
+    fn recovery_codes_are_handed_out_as_pairs_of_hex() {
+        let codes = generate_recovery_codes();
+
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for code in &codes {
+            assert_eq!(code.len(), 9);
+            assert_eq!(&code[4..5], "-");
+            assert!(normalize_recovery_code(code)
+                .chars()
+                .all(|c| c.is_ascii_hexdigit()));
+        }
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_are_accepted_in_any_case_once() {
+        let users = MemoryUserRepository::default();
+        let codes = vec![String::from("abcd-ef01"), String::from("2345-6789")];
+        let uid = user_with_recovery_codes(&users, &codes).await;
+
+        // Only the hashes are kept
+        let code = SecondFactor::RecoveryCode(String::from(" ABCDEF01 "));
+        assert!(verify_second_factor(uid, &code, &users).await.is_ok());
+        assert!(matches!(
+            verify_second_factor(uid, &code, &users).await,
+            Err(AuthError::InvalidMfaCode)
+        ));
+
+        let other = SecondFactor::RecoveryCode(String::from("2345-6789"));
+        assert!(verify_second_factor(uid, &other, &users).await.is_ok());
+
+        let unknown = SecondFactor::RecoveryCode(String::from("0000-0000"));
+        assert!(matches!(
+            verify_second_factor(uid, &unknown, &users).await,
+            Err(AuthError::InvalidMfaCode)
+        ));
+    }
+
+    #[tokio::test]
+    async fn totp_codes_need_two_factor_authentication_on() {
+        let users = MemoryUserRepository::default();
+        let user = users
+            .create("no-second-factor", "hash", None)
+            .await
+            .unwrap();
+        let code = SecondFactor::Totp(String::from("123456"));
+
+        assert!(matches!(
+            verify_second_factor(user.id, &code, &users).await,
+            Err(AuthError::MfaNotEnabled)
+        ));
+    }
+}
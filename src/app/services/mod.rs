@@ -1,2 +1,15 @@
+pub mod admin;
+pub mod api_key;
+pub mod audit;
 pub mod auth;
+pub mod error;
+pub mod health;
+pub mod keys;
+pub mod lockout;
+pub mod mail;
+pub mod mfa;
+pub mod password;
+pub mod password_reset;
+pub mod totp;
 pub mod utils;
+pub mod validation;
@@ -0,0 +1,301 @@
+use argon2::{
+    password_hash::{PasswordHash, SaltString},
+    Algorithm, Argon2, Params, PasswordHasher as _, PasswordVerifier as _, Version,
+};
+use rand::rngs::OsRng;
+use std::fmt;
+
+use crate::config::{HasherKind, PasswordConfig};
+
+#[derive(Debug)]
+pub struct HashError(String);
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not hash password: {}", self.0)
+    }
+}
+
+// A password hashing scheme. Hashes are stored in a self-describing format,
+// PHC strings for Argon2id and the usual `$2b$` strings for bcrypt, so every
+// stored hash can be checked whatever the current configuration is.
+pub trait PasswordHasher {
+    fn hash(&self, password: &str) -> Result<String, HashError>;
+
+    // Whether this scheme produced the hash
+    fn recognizes(&self, hash: &str) -> bool;
+
+    fn verify(&self, password: &str, hash: &str) -> bool;
+
+    // Whether a hash of this scheme is weaker than what this hasher would produce now
+    fn is_weaker(&self, hash: &str) -> bool;
+}
+
+pub struct Argon2idHasher {
+    params: Params,
+}
+
+impl Argon2idHasher {
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl PasswordHasher for Argon2idHasher {
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|error| HashError(error.to_string()))
+    }
+
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2id$")
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        // The parameters stored in the hash are used, not the configured ones
+        match PasswordHash::new(hash) {
+            Ok(parsed) => self
+                .argon2()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn is_weaker(&self, hash: &str) -> bool {
+        let parsed = match PasswordHash::new(hash) {
+            Ok(parsed) => parsed,
+            Err(_) => return true,
+        };
+
+        match Params::try_from(&parsed) {
+            Ok(params) => {
+                params.m_cost() < self.params.m_cost()
+                    || params.t_cost() < self.params.t_cost()
+                    || params.p_cost() < self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+pub struct BcryptHasher {
+    cost: u32,
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        bcrypt::hash(password, self.cost).map_err(|error| HashError(error.to_string()))
+    }
+
+    fn recognizes(&self, hash: &str) -> bool {
+        ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        matches!(bcrypt::verify(password, hash), Ok(true))
+    }
+
+    fn is_weaker(&self, hash: &str) -> bool {
+        // `$2b$12$...`, the cost is the second field
+        match hash.split('$').nth(2).map(str::parse::<u32>) {
+            Some(Ok(cost)) => cost < self.cost,
+            _ => true,
+        }
+    }
+}
+
+// Parameters come from ARGON2_MEMORY_KIB, ARGON2_ITERATIONS and ARGON2_PARALLELISM
+fn configured_argon2id(config: &PasswordConfig) -> Argon2idHasher {
+    Argon2idHasher {
+        params: config.argon2.clone(),
+    }
+}
+
+fn configured_bcrypt(config: &PasswordConfig) -> BcryptHasher {
+    BcryptHasher {
+        cost: config.bcrypt_cost,
+    }
+}
+
+// New hashes use PASSWORD_HASHER, `argon2id` (the default) or `bcrypt`
+fn current_hasher(config: &PasswordConfig) -> Box<dyn PasswordHasher> {
+    match config.hasher {
+        HasherKind::Bcrypt => Box::new(configured_bcrypt(config)),
+        HasherKind::Argon2id => Box::new(configured_argon2id(config)),
+    }
+}
+
+pub fn hash_password(config: &PasswordConfig, password: &str) -> Result<String, HashError> {
+    current_hasher(config).hash(password)
+}
+
+// Checks a password against a hash of no one's password, so a login for a
+// username that does not exist costs as much as one for a username that does
+pub fn verify_dummy_password(config: &PasswordConfig, password: &str) {
+    let hash = config
+        .dummy_hash
+        .get_or_init(|| hash_password(config, "no one's password").unwrap_or_default());
+    verify_password(config, password, hash);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    // The password is right, but the hash should be replaced with a fresh one
+    ValidNeedsRehash,
+}
+
+pub fn verify_password(config: &PasswordConfig, password: &str, hash: &str) -> Verification {
+    let current = current_hasher(config);
+    let hashers: [Box<dyn PasswordHasher>; 2] = [
+        Box::new(configured_argon2id(config)),
+        Box::new(configured_bcrypt(config)),
+    ];
+
+    let hasher = match hashers.iter().find(|hasher| hasher.recognizes(hash)) {
+        Some(hasher) => hasher,
+        None => return Verification::Invalid,
+    };
+
+    if !hasher.verify(password, hash) {
+        return Verification::Invalid;
+    }
+
+    if !current.recognizes(hash) || current.is_weaker(hash) {
+        Verification::ValidNeedsRehash
+    } else {
+        Verification::Valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    // Cheap costs, so the tests do not spend their time hashing
+    fn config(flags: &[&str]) -> PasswordConfig {
+        let mut args = vec![
+            "--argon2-memory-kib=64",
+            "--argon2-iterations=1",
+            "--argon2-parallelism=1",
+            "--bcrypt-cost=4",
+        ];
+        args.extend(flags);
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+
+        Config::load(&args).unwrap().0.password
+    }
+
+    #[test]
+    fn passwords_verify_against_their_own_hash() {
+        for hasher in ["argon2id", "bcrypt"] {
+            let config = config(&[&format!("--password-hasher={}", hasher)]);
+            let hash = hash_password(&config, "correct horse").unwrap();
+
+            assert_eq!(
+                verify_password(&config, "correct horse", &hash),
+                Verification::Valid,
+                "{}",
+                hasher
+            );
+            assert_eq!(
+                verify_password(&config, "wrong horse", &hash),
+                Verification::Invalid,
+                "{}",
+                hasher
+            );
+        }
+    }
+
+    #[test]
+    fn bcrypt_hashes_are_replaced_once_argon2id_is_configured() {
+        let bcrypt = config(&["--password-hasher=bcrypt"]);
+        let hash = hash_password(&bcrypt, "correct horse").unwrap();
+        assert!(hash.starts_with("$2b$04$"));
+
+        let argon2id = config(&["--password-hasher=argon2id"]);
+        assert_eq!(
+            verify_password(&argon2id, "correct horse", &hash),
+            Verification::ValidNeedsRehash
+        );
+        // A wrong password is never worth a rehash
+        assert_eq!(
+            verify_password(&argon2id, "wrong horse", &hash),
+            Verification::Invalid
+        );
+    }
+
+    #[test]
+    fn argon2id_hashes_with_lower_costs_are_replaced() {
+        let hash = hash_password(&config(&[]), "correct horse").unwrap();
+
+        // (flags raising one cost, outcome)
+        let cases: [(&[&str], Verification); 4] = [
+            (&[], Verification::Valid),
+            (&["--argon2-memory-kib=128"], Verification::ValidNeedsRehash),
+            (&["--argon2-iterations=2"], Verification::ValidNeedsRehash),
+            (&["--argon2-parallelism=2"], Verification::ValidNeedsRehash),
+        ];
+
+        for (flags, outcome) in cases {
+            assert_eq!(
+                verify_password(&config(flags), "correct horse", &hash),
+                outcome,
+                "{:?}",
+                flags
+            );
+        }
+    }
+
+    #[test]
+    fn argon2id_hashes_with_higher_costs_are_kept() {
+        let stronger = config(&["--argon2-memory-kib=128", "--argon2-iterations=2"]);
+        let hash = hash_password(&stronger, "correct horse").unwrap();
+
+        assert_eq!(
+            verify_password(&config(&[]), "correct horse", &hash),
+            Verification::Valid
+        );
+    }
+
+    #[test]
+    fn bcrypt_hashes_with_a_lower_cost_are_replaced() {
+        let hash = hash_password(&config(&["--password-hasher=bcrypt"]), "correct horse").unwrap();
+        let costlier = config(&["--password-hasher=bcrypt", "--bcrypt-cost=5"]);
+
+        assert_eq!(
+            verify_password(&costlier, "correct horse", &hash),
+            Verification::ValidNeedsRehash
+        );
+        assert!(!configured_bcrypt(&costlier).is_weaker("$2b$05$abc"));
+        assert!(configured_bcrypt(&costlier).is_weaker("$2b$xx$abc"));
+    }
+
+    #[test]
+    fn unrecognized_hashes_never_verify() {
+        let config = config(&[]);
+
+        for hash in [
+            "",
+            "plain text",
+            "$1$md5crypt$hash",
+            "$argon2i$v=19$m=64,t=1,p=1$c2FsdA$aGFzaA",
+        ] {
+            assert_eq!(
+                verify_password(&config, "plain text", hash),
+                Verification::Invalid,
+                "{:?}",
+                hash
+            );
+        }
+    }
+}
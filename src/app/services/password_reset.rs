@@ -0,0 +1,186 @@
+use sqlx::{postgres::PgPool, Row};
+use std::sync::Arc;
+
+use crate::{
+    app::repositories::{
+        session::{PgSessionRepository, SessionRepository},
+        user::PgUserRepository,
+    },
+    config::Config,
+    logging,
+};
+
+use super::{
+    audit::{record_event, AuditEvent},
+    error::AuthError,
+    lockout::{attempt_store, LoginThrottle},
+    mail::{Email, Mailer},
+    password::hash_password,
+    utils::{current_timestamp, hash_token, random_hex},
+    validation::check_password,
+};
+
+pub struct PasswordResetService {
+    pool: PgPool,
+    mailer: Box<dyn Mailer>,
+    config: Arc<Config>,
+}
+
+impl PasswordResetService {
+    pub fn new(pool: PgPool, mailer: Box<dyn Mailer>, config: Arc<Config>) -> Self {
+        PasswordResetService {
+            pool,
+            mailer,
+            config,
+        }
+    }
+
+    // Reset links are valid for an hour, override with PASSWORD_RESET_EXPIRATION_SECS
+    fn reset_expiration_secs(&self) -> i64 {
+        self.config.auth.password_reset_expiration_secs
+    }
+
+    // The page of the client that asks for the new password, it gets the token
+    // as a `token` query parameter. Set with PASSWORD_RESET_URL.
+    fn reset_url(&self) -> &str {
+        &self.config.auth.password_reset_url
+    }
+
+    // Mails a reset link if an account has this address. Whether one does is
+    // never revealed to the caller, not even by how long it takes: only the
+    // lookup happens before returning, the link is stored and mailed in the
+    // background.
+    pub async fn request_reset(self, email: &str, ip: Option<&str>) -> Result<(), AuthError> {
+        let query = "SELECT id, username, email FROM users WHERE lower(email) = lower($1)";
+        let row = match sqlx::query(query)
+            .bind(email.trim())
+            .fetch_optional(&self.pool)
+            .await?
+        {
+            Some(row) => row,
+            None => return Ok(()),
+        };
+
+        let uid: i32 = row.try_get("id")?;
+        let username: String = row.try_get("username")?;
+        let email: String = row.try_get("email")?;
+
+        let ip = ip.map(String::from);
+        tokio::spawn(async move {
+            let result = self
+                .send_reset_link(uid, &username, email, ip.as_deref())
+                .await;
+            if let Err(error) = result {
+                logging::error(
+                    "Could not send password reset link",
+                    &[("uid", uid.into()), ("error", error.to_string().into())],
+                );
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn send_reset_link(
+        &self,
+        uid: i32,
+        username: &str,
+        email: String,
+        ip: Option<&str>,
+    ) -> Result<(), AuthError> {
+        // Only the latest link works, asking again invalidates the ones sent before
+        let query = "DELETE FROM password_resets WHERE user_id = $1";
+        sqlx::query(query).bind(uid).execute(&self.pool).await?;
+
+        let token = random_hex(32);
+        let now = current_timestamp() as i64;
+        let query = "INSERT INTO password_resets (token_hash, user_id, created_at, expires_at) VALUES ($1, $2, $3, $4)";
+        sqlx::query(query)
+            .bind(hash_token(&token))
+            .bind(uid)
+            .bind(now)
+            .bind(now + self.reset_expiration_secs())
+            .execute(&self.pool)
+            .await?;
+
+        let link = format!("{}?token={}", self.reset_url(), token);
+        let message = Email {
+            to: email,
+            subject: String::from("Reset your password"),
+            body: format!(
+                "Someone asked to reset the password of {}.\n\nOpen {} within {} minutes to choose a new one. If it was not you, you can ignore this message.\n",
+                username,
+                link,
+                self.reset_expiration_secs() / 60
+            ),
+        };
+        self.mailer
+            .send(&message)
+            .await
+            .map_err(|error| AuthError::MailDelivery(error.to_string()))?;
+
+        record_event(
+            &self.pool,
+            AuditEvent::PasswordResetRequested,
+            username,
+            ip,
+            "Reset link sent",
+        )
+        .await;
+
+        Ok(())
+    }
+
+    // Sets a new password with a reset token, which can only be used once. All
+    // sessions are signed out, as whoever held the old password may have one.
+    pub async fn reset_password(
+        &self,
+        token: &str,
+        password: &str,
+        ip: Option<&str>,
+    ) -> Result<(), AuthError> {
+        let now = current_timestamp() as i64;
+        let token_hash = hash_token(token);
+
+        // The password is checked before the token is used up, so a rejected
+        // password does not cost the user their reset link
+        let query = "SELECT users.username FROM password_resets JOIN users ON users.id = password_resets.user_id WHERE password_resets.token_hash = $1 AND password_resets.used_at IS NULL AND password_resets.expires_at > $2";
+        let row = sqlx::query(query)
+            .bind(&token_hash)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(AuthError::InvalidResetToken)?;
+
+        let username: String = row.try_get("username")?;
+        check_password(&self.config.password, password, &username)?;
+
+        // Hashing takes a while, so it is done before the transaction starts
+        let hashed_password = hash_password(&self.config.password, password)?;
+        let uid = PgUserRepository::new(self.pool.clone())
+            .reset_password(&token_hash, now, &hashed_password)
+            .await?
+            .ok_or(AuthError::InvalidResetToken)?;
+
+        PgSessionRepository::new(self.pool.clone())
+            .delete_for_user(uid)
+            .await?;
+
+        // Proving access to the mailbox is enough to lift a lockout of the account
+        let login = &self.config.login;
+        LoginThrottle::new(attempt_store(login, &self.pool), login.lockout)
+            .unlock(&username)
+            .await?;
+
+        record_event(
+            &self.pool,
+            AuditEvent::PasswordReset,
+            &username,
+            ip,
+            "Password reset with a reset link",
+        )
+        .await;
+
+        Ok(())
+    }
+}
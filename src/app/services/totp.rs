@@ -0,0 +1,152 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use sha1::Sha1;
+
+// RFC 6238 defaults, which is what authenticator apps expect when the
+// provisioning URI does not say otherwise
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+// Codes from one step before or after the current one are accepted too, to
+// make up for clock drift and the time it takes to type the code in
+const ALLOWED_DRIFT: i64 = 1;
+
+const BASE32: Alphabet = Alphabet::Rfc4648 { padding: false };
+
+// 160 random bits, the key size RFC 4226 recommends for HMAC-SHA1
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    thread_rng().fill_bytes(&mut bytes);
+    base32::encode(BASE32, &bytes)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// `issuer` is shown as the account's issuer in authenticator apps, set with TOTP_ISSUER
+pub fn provisioning_uri(issuer: &str, secret: &str, username: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(username),
+        secret,
+        percent_encode(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+fn code_at(key: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation from RFC 4226
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    binary % 10u32.pow(DIGITS)
+}
+
+// Checks a code against the steps around `timestamp` and returns the step it
+// matched, so callers can refuse to accept the same code twice
+pub fn verify_code(secret: &str, code: &str, timestamp: u64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32::decode(BASE32, secret)?;
+    let current_step = (timestamp / STEP_SECS) as i64;
+
+    (-ALLOWED_DRIFT..=ALLOWED_DRIFT)
+        .map(|drift| current_step + drift)
+        .filter(|step| *step >= 0)
+        .find(|step| code_at(&key, *step as u64) == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The ASCII key "12345678901234567890" from the RFC 6238 test vectors
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn codes_match_the_rfc_6238_sha1_vectors() {
+        // The RFC lists 8 digit codes, these are their last 6 digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (timestamp, code) in vectors {
+            assert_eq!(
+                verify_code(SECRET, code, timestamp),
+                Some((timestamp / STEP_SECS) as i64),
+                "code at {}",
+                timestamp
+            );
+        }
+    }
+
+    #[test]
+    fn codes_are_accepted_one_step_either_side() {
+        // The code for step 1, which runs from 30 to 59
+        let code = "287082";
+
+        assert_eq!(verify_code(SECRET, code, 0), Some(1));
+        assert_eq!(verify_code(SECRET, code, 89), Some(1));
+        assert_eq!(verify_code(SECRET, code, 90), None);
+    }
+
+    #[test]
+    fn malformed_codes_are_rejected() {
+        for code in ["", "28708", "2870820", "28708a", "-28708"] {
+            assert_eq!(verify_code(SECRET, code, 59), None, "code {:?}", code);
+        }
+
+        // Surrounding whitespace is left over from copying the code
+        assert_eq!(verify_code(SECRET, " 287082\n", 59), Some(1));
+        assert_eq!(verify_code("not base32!", "287082", 59), None);
+    }
+
+    #[test]
+    fn generated_secrets_decode_to_160_bits() {
+        let secret = generate_secret();
+
+        assert_eq!(
+            base32::decode(BASE32, &secret).map(|key| key.len()),
+            Some(20)
+        );
+        assert_ne!(secret, generate_secret());
+    }
+
+    #[test]
+    fn provisioning_uris_encode_the_issuer_and_username() {
+        let uri = provisioning_uri("My App", "SECRET", "ada@example.com");
+
+        assert_eq!(
+            uri,
+            "otpauth://totp/My%20App:ada%40example.com?secret=SECRET&issuer=My%20App&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
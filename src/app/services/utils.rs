@@ -1,116 +1,224 @@
-use dotenv::dotenv;
 use jsonwebtoken::{
-    decode, encode,
+    decode, decode_header, encode,
     errors::{Error, ErrorKind},
     Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
 };
-use sqlx::PgPool;
-use std::{
-    env,
-    time::{SystemTime, UNIX_EPOCH},
-    usize,
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    app::models::claims::{Claims, TokenType},
+    config::JwtConfig,
 };
 
-use crate::{app::models::claims::Claims, http::utils::refresh_access_token};
+use super::{
+    error::AuthError,
+    keys::{is_asymmetric, keyring},
+};
 
-pub fn generate_token(uid: i32, username: &str) -> String {
-    dotenv().ok();
-    let secret = match env::var("JWT_SECRET") {
-        Ok(jwt_secret) => jwt_secret,
-        _ => panic!("JWT_SECRET is not provided"),
-    };
-    let expiration_secs: usize = match env::var("EXP") {
-        Ok(exp) => exp.parse().unwrap(),
-        _ => panic!("EXP is not provided"),
-    };
+// Refresh tokens, and the sessions they belong to, last for a week
+pub const REFRESH_TOKEN_EXPIRATION_SECS: usize = 604800;
 
-    let exp = SystemTime::now()
+// Time a user has to enter their second factor after giving the right password
+pub const MFA_CHALLENGE_EXPIRATION_SECS: usize = 300;
+
+pub fn current_timestamp() -> usize {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs() as usize
-        + expiration_secs;
+}
+
+// EXP, checked to be set before the server starts and before `keys` runs
+pub fn access_token_expiration_secs(jwt: &JwtConfig) -> usize {
+    jwt.expiration_secs.expect("EXP is checked at startup")
+}
+
+fn new_claims(
+    jwt: &JwtConfig,
+    uid: i32,
+    username: &str,
+    sid: &str,
+    roles: &[String],
+    token_type: TokenType,
+    expiration_secs: usize,
+) -> Claims {
+    let now = current_timestamp();
 
-    let claims = Claims::new(username.to_string(), uid, exp);
+    Claims {
+        username: username.to_string(),
+        uid,
+        sid: sid.to_string(),
+        token_type,
+        roles: roles.to_vec(),
+        iss: jwt.issuer.clone(),
+        aud: jwt.audience.clone(),
+        iat: now,
+        nbf: now,
+        exp: now + expiration_secs,
+        jti: generate_id(),
+    }
+}
+
+fn validation(jwt: &JwtConfig, algorithm: Algorithm) -> Validation {
+    // `leeway` is the clock skew tolerated when checking `exp` and `nbf`
+    let mut validation = Validation::new(algorithm);
+    validation.leeway = jwt.leeway;
+    validation.validate_nbf = true;
+    validation.set_issuer(&[&jwt.issuer]);
+    validation.set_audience(&[&jwt.audience]);
+    validation.set_required_spec_claims(&["exp", "nbf", "iat", "iss", "aud", "jti"]);
+
+    validation
+}
 
+fn expect_token_type(
+    token_data: TokenData<Claims>,
+    token_type: TokenType,
+) -> Result<TokenData<Claims>, Error> {
+    if token_data.claims.token_type == token_type {
+        Ok(token_data)
+    } else {
+        Err(ErrorKind::InvalidToken.into())
+    }
+}
+
+fn jwt_secret(jwt: &JwtConfig) -> &str {
+    jwt.secret
+        .as_deref()
+        .expect("JWT_SECRET is checked at startup")
+}
+
+// Signed with JWT_ALGORITHM. HS256, the default, signs with JWT_SECRET,
+// anything else uses the keys in JWT_KEYS_DIR.
+pub fn generate_token(
+    jwt: &JwtConfig,
+    uid: i32,
+    username: &str,
+    sid: &str,
+    roles: &[String],
+) -> Result<String, AuthError> {
+    let claims = new_claims(
+        jwt,
+        uid,
+        username,
+        sid,
+        roles,
+        TokenType::Access,
+        access_token_expiration_secs(jwt),
+    );
+
+    let algorithm = jwt.algorithm;
+    if !is_asymmetric(algorithm) {
+        let encoding_key = EncodingKey::from_secret(jwt_secret(jwt).as_ref());
+        return Ok(encode(&Header::new(algorithm), &claims, &encoding_key).unwrap());
+    }
+
+    // The `kid` header tells verifiers which of the published keys to check against
+    let keyring = keyring(jwt)?;
+    let signing_key = keyring.signing_key();
+    let mut header = Header::new(signing_key.alg);
+    header.kid = Some(signing_key.kid.clone());
+
+    Ok(encode(&header, &claims, &signing_key.encoding_key).unwrap())
+}
+
+fn refresh_token_secret(jwt: &JwtConfig) -> &str {
+    jwt.refresh_secret
+        .as_deref()
+        .expect("REFRESH_TOKEN_SECRET is checked at startup")
+}
+
+pub fn generate_refresh_token(jwt: &JwtConfig, username: &str, uid: i32, sid: &str) -> String {
+    let secret = refresh_token_secret(jwt);
+
+    let claims = new_claims(
+        jwt,
+        uid,
+        username,
+        sid,
+        &[],
+        TokenType::Refresh,
+        REFRESH_TOKEN_EXPIRATION_SECS,
+    );
     let encoding_key = EncodingKey::from_secret(secret.as_ref());
     encode(&Header::new(Algorithm::HS256), &claims, &encoding_key).unwrap()
 }
 
-pub fn generate_refresh_token(username: &str, uid: i32) -> String {
-    dotenv().ok();
+pub fn verify_token(jwt: &JwtConfig, token: &str) -> Result<TokenData<Claims>, Error> {
+    let header = decode_header(token)?;
+
+    // Tokens without a key id are signed with the shared secret
+    let kid = match header.kid {
+        Some(kid) => kid,
+        None => {
+            let algorithm = jwt.algorithm;
+            if is_asymmetric(algorithm) {
+                return Err(ErrorKind::InvalidAlgorithm.into());
+            }
 
-    let secret = match env::var("REFRESH_TOKEN_SECRET") {
-        Ok(refresh_secret) => refresh_secret,
-        _ => panic!("REFRESH_TOKEN_SECRET is invalid"),
+            let decoding_key = DecodingKey::from_secret(jwt_secret(jwt).as_ref());
+            let token_data = decode::<Claims>(token, &decoding_key, &validation(jwt, algorithm))?;
+            return expect_token_type(token_data, TokenType::Access);
+        }
     };
 
-    let exp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as usize
-        + 604800;
+    let keyring = keyring(jwt).map_err(|_| Error::from(ErrorKind::InvalidKeyFormat))?;
+    let key = keyring
+        .verification_key(&kid)
+        .ok_or_else(|| Error::from(ErrorKind::InvalidSignature))?;
 
-    let claims = Claims::new(username.to_string(), uid, exp);
-    let encoding_key = EncodingKey::from_secret(&secret.as_ref());
-    encode(&Header::new(Algorithm::HS256), &claims, &encoding_key).unwrap()
+    // Only accept the algorithm the key was generated for, never the one the token claims
+    let token_data =
+        decode::<Claims>(token, &key.decoding_key, &validation(jwt, key.metadata.alg))?;
+    expect_token_type(token_data, TokenType::Access)
 }
 
-pub fn verify_token(
-    token: &str,
-    cookies: Option<Vec<(&str, &str)>>,
-) -> Result<TokenData<Claims>, Error> {
-    dotenv().ok();
-    let secret = match env::var("JWT_SECRET") {
-        Ok(jwt_secret) => jwt_secret,
-        _ => panic!("JWT_SECRET is not provided"),
-    };
+pub fn verify_refresh_token(jwt: &JwtConfig, token: &str) -> Result<TokenData<Claims>, Error> {
+    let secret = refresh_token_secret(jwt);
 
     let decoding_key = DecodingKey::from_secret(secret.as_ref());
-    let validation = Validation::new(Algorithm::HS256);
+    let token_data = decode::<Claims>(token, &decoding_key, &validation(jwt, Algorithm::HS256))?;
 
-    match decode::<Claims>(token, &decoding_key, &validation) {
-        Ok(token_data) => Ok(token_data),
-        Err(error) => {
-            return handle_expired_token(error, cookies, decoding_key, validation);
-        }
-    }
+    expect_token_type(token_data, TokenType::Refresh)
 }
 
-fn handle_expired_token(
-    error: Error,
-    cookies: Option<Vec<(&str, &str)>>,
-    decoding_key: DecodingKey<'_>,
-    validation: Validation,
-) -> Result<TokenData<Claims>, Error> {
-    match error.kind() {
-        ErrorKind::ExpiredSignature => {
-            let access_token = refresh_access_token(cookies);
-            match decode::<Claims>(
-                access_token.unwrap_or_else(|_| String::new()).as_str(),
-                &decoding_key,
-                &validation,
-            ) {
-                Ok(token_data) => Ok(token_data),
-                Err(error) => {
-                    eprintln!("Invalid refresh token");
-                    return Err(error);
-                }
-            }
-        }
-        _ => {
-            eprintln!("Error while verifying token");
-            return Err(error);
-        }
-    }
+// Challenge tokens never leave the server's own login flow, so like refresh
+// tokens they are signed with REFRESH_TOKEN_SECRET and carry no session
+pub fn generate_mfa_token(jwt: &JwtConfig, uid: i32, username: &str) -> String {
+    let claims = new_claims(
+        jwt,
+        uid,
+        username,
+        "",
+        &[],
+        TokenType::MfaChallenge,
+        MFA_CHALLENGE_EXPIRATION_SECS,
+    );
+    let encoding_key = EncodingKey::from_secret(refresh_token_secret(jwt).as_ref());
+    encode(&Header::new(Algorithm::HS256), &claims, &encoding_key).unwrap()
+}
+
+pub fn verify_mfa_token(jwt: &JwtConfig, token: &str) -> Result<TokenData<Claims>, Error> {
+    let decoding_key = DecodingKey::from_secret(refresh_token_secret(jwt).as_ref());
+    let token_data = decode::<Claims>(token, &decoding_key, &validation(jwt, Algorithm::HS256))?;
+
+    expect_token_type(token_data, TokenType::MfaChallenge)
+}
+
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn generate_id() -> String {
+    random_hex(16)
 }
 
-pub async fn store_refresh_token(refresh_token: &str, id: i32, pool: &PgPool) {
-    // Store the refresh token in database
-    let store_refresh_token_query = "UPDATE users SET refresh_token = $1 WHERE id = $2;";
-    sqlx::query(store_refresh_token_query)
-        .bind(&refresh_token)
-        .bind(id)
-        .execute(pool)
-        .await
-        .unwrap();
+// Refresh tokens are only ever stored as a SHA-256 digest, so a leaked
+// sessions table cannot be replayed against `/auth/refresh`
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
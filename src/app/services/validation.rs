@@ -0,0 +1,304 @@
+use caseless::Caseless;
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+use unicode_normalization::UnicodeNormalization;
+
+use sha1::{Digest, Sha1};
+
+use crate::{config::PasswordConfig, logging};
+
+const USERNAME_MIN_LENGTH: usize = 3;
+const USERNAME_MAX_LENGTH: usize = 32;
+
+// Names that could pass for the service itself or clash with routes, compared case-folded
+const RESERVED_USERNAMES: [&str; 14] = [
+    "admin",
+    "administrator",
+    "api",
+    "auth",
+    "me",
+    "moderator",
+    "null",
+    "root",
+    "security",
+    "support",
+    "system",
+    "undefined",
+    "well-known",
+    "www",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    UsernameLength,
+    UsernameCharacters,
+    UsernameReserved,
+    PasswordLength(usize, usize),
+    PasswordContainsUsername,
+    PasswordBreached,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::UsernameLength => write!(
+                f,
+                "Username has to be between {} and {} characters long",
+                USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+            ),
+            ValidationError::UsernameCharacters => write!(
+                f,
+                "Username can only contain letters, digits, `.`, `_` and `-`, and has to start with a letter or a digit"
+            ),
+            ValidationError::UsernameReserved => write!(f, "Username is reserved"),
+            ValidationError::PasswordLength(min, max) => write!(
+                f,
+                "Password has to be between {} and {} characters long",
+                min, max
+            ),
+            ValidationError::PasswordContainsUsername => {
+                write!(f, "Password cannot contain the username")
+            }
+            ValidationError::PasswordBreached => write!(
+                f,
+                "Password has appeared in a data breach, choose a different one"
+            ),
+        }
+    }
+}
+
+// The form two usernames are compared in. NFKC folds look-alike compatibility
+// characters together and case folding makes `Alice` and `ALICE` the same name.
+pub fn username_key(username: &str) -> String {
+    username.trim().nfkc().default_case_fold().nfkc().collect()
+}
+
+// Validates a username and returns the form it is stored and shown in, which
+// keeps the case the user picked
+pub fn normalize_username(username: &str) -> Result<String, ValidationError> {
+    let username: String = username.trim().nfkc().collect();
+
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        return Err(ValidationError::UsernameLength);
+    }
+
+    let starts_alphanumeric = username.chars().next().is_some_and(char::is_alphanumeric);
+    let is_allowed = username
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !starts_alphanumeric || !is_allowed {
+        return Err(ValidationError::UsernameCharacters);
+    }
+
+    if RESERVED_USERNAMES.contains(&username_key(&username).as_str()) {
+        return Err(ValidationError::UsernameReserved);
+    }
+
+    Ok(username)
+}
+
+// SHA-1 digests of the passwords in PASSWORD_BREACHED_LIST, read once so a
+// registration does not go through the whole file
+#[derive(Debug, Default)]
+pub struct BreachedPasswords(HashSet<[u8; 20]>);
+
+impl BreachedPasswords {
+    // Each line is either a password or the upper or lower case hex SHA-1 of
+    // one, optionally followed by `:count` as in the Have I Been Pwned downloads
+    fn read(path: &Path) -> std::io::Result<Self> {
+        let mut digests = HashSet::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let line = line.trim_end();
+            digests.insert(Sha1::digest(line.as_bytes()).into());
+
+            let hash = line.split(':').next().unwrap_or_default();
+            let mut digest = [0u8; 20];
+            if hex::decode_to_slice(hash, &mut digest).is_ok() {
+                digests.insert(digest);
+            }
+        }
+
+        Ok(BreachedPasswords(digests))
+    }
+
+    fn contains(&self, password: &str) -> bool {
+        self.0
+            .contains(&<[u8; 20]>::from(Sha1::digest(password.as_bytes())))
+    }
+}
+
+// Reads PASSWORD_BREACHED_LIST the first time it is needed. A list that cannot
+// be read is logged and counts as empty.
+pub fn breached_passwords(config: &PasswordConfig) -> Option<&BreachedPasswords> {
+    let path = config.breached_list.as_ref()?;
+
+    Some(
+        config
+            .breached_passwords
+            .get_or_init(|| match BreachedPasswords::read(path) {
+                Ok(breached) => {
+                    logging::info(
+                        "Loaded breached password list",
+                        &[
+                            ("path", path.display().to_string().into()),
+                            ("passwords", breached.0.len().to_string().into()),
+                        ],
+                    );
+                    breached
+                }
+                Err(error) => {
+                    logging::error(
+                        "Could not read breached password list",
+                        &[
+                            ("path", path.display().to_string().into()),
+                            ("error", error.to_string().into()),
+                        ],
+                    );
+                    BreachedPasswords::default()
+                }
+            }),
+    )
+}
+
+fn is_breached(config: &PasswordConfig, password: &str) -> bool {
+    breached_passwords(config).is_some_and(|breached| breached.contains(password))
+}
+
+// Length limits come from PASSWORD_MIN_LENGTH and PASSWORD_MAX_LENGTH
+pub fn check_password(
+    config: &PasswordConfig,
+    password: &str,
+    username: &str,
+) -> Result<(), ValidationError> {
+    let min_length = config.min_length;
+    let max_length = config.max_length;
+
+    let length = password.chars().count();
+    if length < min_length || length > max_length {
+        return Err(ValidationError::PasswordLength(min_length, max_length));
+    }
+
+    let username = username_key(username);
+    if !username.is_empty() && username_key(password).contains(&username) {
+        return Err(ValidationError::PasswordContainsUsername);
+    }
+
+    if is_breached(config, password) {
+        return Err(ValidationError::PasswordBreached);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::{env, fs};
+
+    #[test]
+    fn usernames_keep_their_case_and_lose_outer_whitespace() {
+        assert_eq!(normalize_username("  Alice "), Ok(String::from("Alice")));
+        assert_eq!(
+            normalize_username("bob.smith_2-x"),
+            Ok(String::from("bob.smith_2-x"))
+        );
+        // NFKC turns full-width letters into the usual ones
+        assert_eq!(normalize_username("Ａｌｉｃｅ"), Ok(String::from("Alice")));
+    }
+
+    #[test]
+    fn invalid_usernames_are_rejected() {
+        // (username, error)
+        let cases = [
+            ("al", ValidationError::UsernameLength),
+            ("   al   ", ValidationError::UsernameLength),
+            (&*"a".repeat(33), ValidationError::UsernameLength),
+            ("_alice", ValidationError::UsernameCharacters),
+            ("alice smith", ValidationError::UsernameCharacters),
+            ("alice@example", ValidationError::UsernameCharacters),
+            ("admin", ValidationError::UsernameReserved),
+            ("Admin", ValidationError::UsernameReserved),
+            ("ＲＯＯＴ", ValidationError::UsernameReserved),
+            ("well-known", ValidationError::UsernameReserved),
+        ];
+
+        for (username, error) in cases {
+            assert_eq!(normalize_username(username), Err(error), "{:?}", username);
+        }
+    }
+
+    #[test]
+    fn usernames_that_look_alike_share_a_key() {
+        assert_eq!(username_key("Alice"), username_key("ALICE"));
+        assert_eq!(username_key(" alice "), username_key("alice"));
+        assert_eq!(username_key("Ａｌｉｃｅ"), username_key("alice"));
+        // Full case folding, not just lower casing
+        assert_eq!(username_key("STRASSE"), username_key("straße"));
+        assert_ne!(username_key("alice"), username_key("alice2"));
+    }
+
+    fn config(flags: &[&str]) -> PasswordConfig {
+        let args: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+        Config::load(&args).unwrap().0.password
+    }
+
+    #[test]
+    fn passwords_are_checked_for_length_and_the_username() {
+        let config = config(&["--password-min-length=8", "--password-max-length=12"]);
+
+        assert_eq!(check_password(&config, "long enough", "alice"), Ok(()));
+        assert_eq!(
+            check_password(&config, "short", "alice"),
+            Err(ValidationError::PasswordLength(8, 12))
+        );
+        assert_eq!(
+            check_password(&config, "far too long here", "alice"),
+            Err(ValidationError::PasswordLength(8, 12))
+        );
+        assert_eq!(
+            check_password(&config, "my ALICE pw", "Alice"),
+            Err(ValidationError::PasswordContainsUsername)
+        );
+    }
+
+    #[test]
+    fn breached_passwords_are_found_by_password_or_sha1() {
+        let path = env::temp_dir().join(format!("breached-{}.txt", std::process::id()));
+        // SHA-1 of "hunter22" upper case with a count, then a plain password
+        let hunter22 = hex::encode_upper(Sha1::digest(b"hunter22"));
+        fs::write(&path, format!("{}:1024\ncorrect horse\n", hunter22)).unwrap();
+        let config = config(&[&format!("--password-breached-list={}", path.display())]);
+
+        assert_eq!(
+            check_password(&config, "hunter22", ""),
+            Err(ValidationError::PasswordBreached)
+        );
+        assert_eq!(
+            check_password(&config, "correct horse", ""),
+            Err(ValidationError::PasswordBreached)
+        );
+        assert_eq!(check_password(&config, "battery staple", ""), Ok(()));
+
+        // Read once, later changes to the file are not picked up
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            check_password(&config, "hunter22", ""),
+            Err(ValidationError::PasswordBreached)
+        );
+    }
+
+    #[test]
+    fn a_missing_breached_list_lets_passwords_through() {
+        let config = config(&["--password-breached-list=/nonexistent/breached.txt"]);
+
+        assert_eq!(check_password(&config, "hunter22", ""), Ok(()));
+    }
+}
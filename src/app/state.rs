@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use crate::{config::Config, db::DatabasePool};
+
+// Handed to every router and handler, instead of each of them reading
+// settings from somewhere global or opening a database pool of their own
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub db: DatabasePool,
+}
+
+impl AppState {
+    pub fn new(config: Arc<Config>, db: DatabasePool) -> Self {
+        AppState { config, db }
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    // Settings good enough to sign tokens, with a pool that never connects
+    // unless a test uses it
+    pub fn for_tests() -> Self {
+        let flags = [
+            "--exp=900",
+            "--jwt-algorithm=HS256",
+            "--jwt-secret=test-secret",
+            "--refresh-token-secret=test-refresh-secret",
+            "--database-url=postgres://localhost/unused",
+        ]
+        .map(String::from);
+        let (config, _) = Config::load(&flags).expect("test configuration is valid");
+        let db = crate::db::open(&config.database).expect("test database URL is valid");
+
+        AppState::new(Arc::new(config), db)
+    }
+}
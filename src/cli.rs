@@ -0,0 +1,195 @@
+use jsonwebtoken::Algorithm;
+use std::{str::FromStr, sync::Arc};
+
+use crate::{
+    app::{
+        models::role::Role,
+        repositories::user::user_repository,
+        services::{
+            admin::grant_role,
+            keys::{is_asymmetric, list_keys, roll_key},
+            utils::access_token_expiration_secs,
+        },
+    },
+    config::Config,
+    db::{
+        self,
+        migrate::{self, Migration},
+    },
+};
+
+fn usage() {
+    eprintln!("Usage:");
+    eprintln!("  no_framework_rust                     Start the server");
+    eprintln!("  no_framework_rust keys roll [ALG]     Generate a new signing key (RS256, ES256 or EdDSA)");
+    eprintln!("  no_framework_rust keys list           List signing keys");
+    eprintln!("  no_framework_rust users grant-role USERNAME ROLE");
+    eprintln!("                                        Give a user a role (user or admin)");
+    eprintln!("  no_framework_rust migrate [up] [--dry-run]");
+    eprintln!("                                        Apply pending migrations");
+    eprintln!("  no_framework_rust migrate down [N] [--dry-run]");
+    eprintln!("                                        Revert the last N migrations (default 1)");
+    eprintln!("  no_framework_rust migrate status      List migrations and when they were applied");
+    eprintln!();
+    eprintln!("Options, given before the command:");
+    eprintln!("  --config PATH                         TOML config file (default config.toml, or CONFIG_FILE)");
+    eprintln!("  --NAME VALUE                          Any setting, as its env var in kebab case");
+    eprintln!("                                        e.g. --database-url or --app-url");
+    eprintln!();
+    eprintln!("Flags override the environment, which overrides the config file.");
+}
+
+fn keys_command(config: &Config, args: &[String]) -> Result<(), String> {
+    config.check_keys().map_err(|error| error.to_string())?;
+    let dir = &config.jwt.keys_dir;
+    // Retired keys are kept for as long as the tokens they signed are valid
+    let token_lifetime = access_token_expiration_secs(&config.jwt);
+
+    match args.first().map(String::as_str) {
+        Some("roll") => {
+            let algorithm = match args.get(1) {
+                Some(alg) => Algorithm::from_str(alg).map_err(|error| error.to_string())?,
+                None => config.jwt.algorithm,
+            };
+            if !is_asymmetric(algorithm) {
+                return Err(format!(
+                    "{:?} keys cannot be rolled, pick RS256, ES256 or EdDSA",
+                    algorithm
+                ));
+            }
+
+            let key =
+                roll_key(dir, algorithm, token_lifetime).map_err(|error| error.to_string())?;
+            println!("Rolled {:?} key {} in {}", key.alg, key.kid, dir.display());
+            Ok(())
+        }
+        Some("list") => {
+            let (active, keys) = list_keys(dir).map_err(|error| error.to_string())?;
+            for key in keys {
+                let status = match (
+                    Some(&key.kid) == active.as_ref(),
+                    key.verifiable_until(token_lifetime),
+                ) {
+                    (true, _) => String::from("active"),
+                    (false, Some(until)) => format!("retired, verifiable until {}", until),
+                    (false, None) => String::from("inactive"),
+                };
+                println!(
+                    "{}  {:?}  created {}  {}",
+                    key.kid, key.alg, key.created_at, status
+                );
+            }
+            Ok(())
+        }
+        _ => Err(String::from("Unknown keys command")),
+    }
+}
+
+async fn users_command(config: &Arc<Config>, args: &[String]) -> Result<(), String> {
+    match (
+        args.first().map(String::as_str),
+        args.get(1),
+        args.get(2).map(String::as_str),
+    ) {
+        (Some("grant-role"), Some(username), Some(role)) => {
+            let role = Role::parse(role).ok_or_else(|| format!("Unknown role {}", role))?;
+            config.check_database().map_err(|error| error.to_string())?;
+            let db = db::open(&config.database).map_err(|error| error.to_string())?;
+
+            let user = grant_role(&user_repository(&db), username, role)
+                .await
+                .map_err(|error| error.to_string())?;
+            println!("{} now has roles {}", user.username, user.roles.join(", "));
+            Ok(())
+        }
+        _ => Err(String::from("Unknown users command")),
+    }
+}
+
+fn print_migrations(migrations: &[&Migration], down: bool, dry_run: bool) {
+    let (action, done) = if down {
+        ("revert", "Reverted")
+    } else {
+        ("apply", "Applied")
+    };
+    if migrations.is_empty() {
+        println!("Nothing to {}", action);
+    }
+
+    for migration in migrations {
+        if dry_run {
+            let sql = if down { migration.down } else { migration.up };
+            println!(
+                "-- Would {} {} ({})",
+                action, migration.version, migration.name
+            );
+            println!("{}", sql.trim_end());
+        } else {
+            println!("{} {} ({})", done, migration.version, migration.name);
+        }
+    }
+}
+
+async fn migrate_command(config: &Config, args: &[String]) -> Result<(), String> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| *arg != "--dry-run")
+        .collect();
+    config.check_database().map_err(|error| error.to_string())?;
+    let pool = db::open(&config.database).map_err(|error| error.to_string())?;
+
+    match args.as_slice() {
+        [] | ["up"] => {
+            let applied = migrate::migrate_up(&pool, dry_run)
+                .await
+                .map_err(|error| error.to_string())?;
+            print_migrations(&applied, false, dry_run);
+            Ok(())
+        }
+        ["down", rest @ ..] if rest.len() <= 1 => {
+            let steps = match rest.first() {
+                Some(steps) => steps
+                    .parse()
+                    .map_err(|_| format!("Invalid number of migrations {}", steps))?,
+                None => 1,
+            };
+            let reverted = migrate::migrate_down(&pool, steps, dry_run)
+                .await
+                .map_err(|error| error.to_string())?;
+            print_migrations(&reverted, true, dry_run);
+            Ok(())
+        }
+        ["status"] => {
+            let migrations = migrate::status(&pool)
+                .await
+                .map_err(|error| error.to_string())?;
+            for (migration, applied_at) in migrations {
+                let status = match applied_at {
+                    Some(applied_at) => format!("applied {}", applied_at),
+                    None => String::from("pending"),
+                };
+                println!("{:04}  {}  {}", migration.version, migration.name, status);
+            }
+            Ok(())
+        }
+        _ => Err(String::from("Unknown migrate command")),
+    }
+}
+
+// Runs an administrative command instead of the server. Returns `None` when
+// no command was given and the server should start. Each command checks the
+// settings it needs itself, `check_server` is only run for the server.
+pub async fn run(config: &Arc<Config>, args: &[String]) -> Option<Result<(), String>> {
+    match args.first().map(String::as_str) {
+        None => None,
+        Some("keys") => Some(keys_command(config, &args[1..])),
+        Some("users") => Some(users_command(config, &args[1..]).await),
+        Some("migrate") => Some(migrate_command(config, &args[1..]).await),
+        Some(_) => {
+            usage();
+            Some(Err(String::from("Unknown command")))
+        }
+    }
+}
@@ -0,0 +1,948 @@
+use argon2::Params;
+use dotenv::dotenv;
+use jsonwebtoken::Algorithm;
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use crate::{
+    app::services::{keys::is_asymmetric, lockout::LockoutPolicy, validation::BreachedPasswords},
+    db::is_sqlite_url,
+    http::{
+        cookie::{CookieOptions, SameSite},
+        cors::{CorsPolicy, OriginPattern},
+        rate_limit::{Bucket, RateLimitPolicy},
+    },
+    logging::{Level, LogConfig, LogFormat},
+};
+
+// Every setting, by the environment variable that sets it and its key in the
+// config file. On the command line it is the variable in kebab case, so
+// DATABASE_URL is `--database-url`.
+const SETTINGS: &[(&str, &str)] = &[
+    ("APP_URL", "server.app_url"),
+    ("AUTO_MIGRATE", "server.auto_migrate"),
+    ("DATABASE_URL", "database.url"),
+    ("JWT_SECRET", "jwt.secret"),
+    ("REFRESH_TOKEN_SECRET", "jwt.refresh_secret"),
+    ("EXP", "jwt.expiration_secs"),
+    ("JWT_ALGORITHM", "jwt.algorithm"),
+    ("JWT_KEYS_DIR", "jwt.keys_dir"),
+    ("JWKS_MAX_AGE", "jwt.jwks_max_age"),
+    ("JWT_ISSUER", "jwt.issuer"),
+    ("JWT_AUDIENCE", "jwt.audience"),
+    ("JWT_LEEWAY", "jwt.leeway"),
+    ("AUTH_TOKEN_MODE", "auth.token_mode"),
+    ("TOTP_ISSUER", "auth.totp_issuer"),
+    (
+        "PASSWORD_RESET_EXPIRATION_SECS",
+        "auth.password_reset_expiration_secs",
+    ),
+    ("PASSWORD_RESET_URL", "auth.password_reset_url"),
+    ("COOKIE_HTTP_ONLY", "cookies.http_only"),
+    ("COOKIE_SECURE", "cookies.secure"),
+    ("COOKIE_SAME_SITE", "cookies.same_site"),
+    ("COOKIE_PATH", "cookies.path"),
+    ("COOKIE_DOMAIN", "cookies.domain"),
+    ("COOKIE_SESSION", "cookies.session"),
+    ("LOGIN_ATTEMPT_STORE", "login.attempt_store"),
+    ("LOGIN_MAX_FAILURES", "login.max_failures"),
+    ("LOGIN_MAX_FAILURES_PER_IP", "login.max_failures_per_ip"),
+    ("LOGIN_LOCKOUT_SECS", "login.lockout_secs"),
+    ("LOGIN_FAILURE_WINDOW_SECS", "login.failure_window_secs"),
+    ("LOGIN_MAX_DELAY_SECS", "login.max_delay_secs"),
+    ("PASSWORD_MIN_LENGTH", "password.min_length"),
+    ("PASSWORD_MAX_LENGTH", "password.max_length"),
+    ("PASSWORD_BREACHED_LIST", "password.breached_list"),
+    ("PASSWORD_HASHER", "password.hasher"),
+    ("ARGON2_MEMORY_KIB", "password.argon2_memory_kib"),
+    ("ARGON2_ITERATIONS", "password.argon2_iterations"),
+    ("ARGON2_PARALLELISM", "password.argon2_parallelism"),
+    ("BCRYPT_COST", "password.bcrypt_cost"),
+    ("MAILER", "mail.mailer"),
+    ("MAIL_OUTBOX_PATH", "mail.outbox_path"),
+    ("MAIL_FROM", "mail.from"),
+    ("SMTP_HOST", "mail.smtp_host"),
+    ("SMTP_PORT", "mail.smtp_port"),
+    ("SMTP_USERNAME", "mail.smtp_username"),
+    ("SMTP_PASSWORD", "mail.smtp_password"),
+    ("CORS_ALLOWED_ORIGINS", "cors.allowed_origins"),
+    ("CORS_ALLOWED_METHODS", "cors.allowed_methods"),
+    ("CORS_ALLOWED_HEADERS", "cors.allowed_headers"),
+    ("CORS_EXPOSED_HEADERS", "cors.exposed_headers"),
+    ("CORS_ALLOW_CREDENTIALS", "cors.allow_credentials"),
+    ("CORS_MAX_AGE", "cors.max_age"),
+    ("LOG_LEVEL", "log.level"),
+    ("LOG_FORMAT", "log.format"),
+    ("METRICS_TOKEN", "metrics.token"),
+    ("RATE_LIMIT_ENABLED", "rate_limit.enabled"),
+    ("RATE_LIMIT_BUCKETS", "rate_limit.buckets"),
+];
+
+// Used when neither --config nor CONFIG_FILE name a file, it is fine for it to be missing
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+fn file_key(name: &str) -> &'static str {
+    SETTINGS
+        .iter()
+        .find(|(env_name, _)| *env_name == name)
+        .map(|(_, key)| *key)
+        .expect("Every setting is listed in SETTINGS")
+}
+
+fn flag_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace('_', "-")
+}
+
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for error in &self.0 {
+            write!(f, "\n  - {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Where issued tokens are handed to the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenMode {
+    // `HttpOnly` cookies, so scripts on the page never see the tokens
+    Cookie,
+    // Tokens in the response body, for clients that cannot use cookies
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttemptStoreKind {
+    Postgres,
+    Memory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HasherKind {
+    Argon2id,
+    Bcrypt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MailerKind {
+    Outbox,
+    File,
+    Smtp,
+}
+
+pub struct ServerConfig {
+    pub app_url: String,
+    pub auto_migrate: bool,
+}
+
+pub struct DatabaseConfig {
+    pub url: Option<String>,
+}
+
+pub struct JwtConfig {
+    pub secret: Option<String>,
+    pub refresh_secret: Option<String>,
+    pub expiration_secs: Option<usize>,
+    pub algorithm: Algorithm,
+    pub keys_dir: PathBuf,
+    pub jwks_max_age: usize,
+    pub issuer: String,
+    pub audience: String,
+    pub leeway: u64,
+}
+
+pub struct AuthConfig {
+    pub token_mode: TokenMode,
+    pub totp_issuer: String,
+    pub password_reset_expiration_secs: i64,
+    pub password_reset_url: String,
+}
+
+pub struct LoginConfig {
+    pub attempt_store: AttemptStoreKind,
+    pub lockout: LockoutPolicy,
+}
+
+pub struct PasswordConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub breached_list: Option<PathBuf>,
+    pub hasher: HasherKind,
+    pub argon2: Params,
+    pub bcrypt_cost: u32,
+    // A hash made with the hasher above on first use, checked in place of a
+    // missing account's so logins take as long whether the username exists
+    pub dummy_hash: OnceLock<String>,
+    // Read from `breached_list` on startup, or on first use
+    pub breached_passwords: OnceLock<BreachedPasswords>,
+}
+
+// The SMTP settings are only read when the `smtp` feature is compiled in
+#[cfg_attr(not(feature = "smtp"), allow(dead_code))]
+pub struct MailConfig {
+    pub mailer: MailerKind,
+    pub outbox_path: String,
+    pub from: String,
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+}
+
+pub struct MetricsConfig {
+    // When set, scrapers have to send it as a bearer token
+    pub token: Option<String>,
+}
+
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub jwt: JwtConfig,
+    pub auth: AuthConfig,
+    pub cookies: CookieOptions,
+    pub login: LoginConfig,
+    pub password: PasswordConfig,
+    pub mail: MailConfig,
+    pub cors: CorsPolicy,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+    pub rate_limit: RateLimitPolicy,
+}
+
+fn require(errors: &mut Vec<String>, name: &str, value: bool) {
+    if !value {
+        errors.push(format!("{} ({}) is not set", name, file_key(name)));
+    }
+}
+
+fn checked(errors: Vec<String>) -> Result<(), ConfigError> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError(errors))
+    }
+}
+
+// Flags given before the command, `--name value` or `--name=value`. Returns
+// them along with the arguments that are left for the command.
+fn parse_flags(args: &[String]) -> Result<(BTreeMap<String, String>, Vec<String>), ConfigError> {
+    let mut flags = BTreeMap::new();
+    let mut errors = Vec::new();
+    let mut args = args.iter();
+    let mut rest = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => {
+                rest.push(arg.clone());
+                rest.extend(args.cloned());
+                break;
+            }
+        };
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag.to_string(), value.clone()),
+                None => {
+                    errors.push(format!("--{} needs a value", flag));
+                    continue;
+                }
+            },
+        };
+
+        let known = name == "config"
+            || SETTINGS
+                .iter()
+                .any(|(env_name, _)| flag_name(env_name) == name);
+        if !known {
+            errors.push(format!("Unknown flag --{}", name));
+        }
+        flags.insert(name, value);
+    }
+
+    if errors.is_empty() {
+        Ok((flags, rest))
+    } else {
+        Err(ConfigError(errors))
+    }
+}
+
+// Flattens `[section] key = value` into `section.key`, every value kept as the
+// text it would have in the environment
+fn read_file(path: &Path) -> Result<BTreeMap<String, String>, Vec<String>> {
+    let name = path.display();
+    let contents = fs::read_to_string(path)
+        .map_err(|error| vec![format!("Could not read {}: {}", name, error)])?;
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|error| vec![format!("{} is not valid TOML: {}", name, error)])?;
+
+    let mut settings = BTreeMap::new();
+    let mut errors = Vec::new();
+    for (section, values) in table {
+        let values = match values {
+            toml::Value::Table(values) => values,
+            _ => {
+                errors.push(format!(
+                    "`{}` in {} has to be inside a section such as [server]",
+                    section, name
+                ));
+                continue;
+            }
+        };
+
+        for (key, value) in values {
+            let key = format!("{}.{}", section, key);
+            if !SETTINGS.iter().any(|(_, file_key)| *file_key == key) {
+                errors.push(format!("Unknown setting `{}` in {}", key, name));
+                continue;
+            }
+
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                // Lists are written the way they are in the environment, comma separated
+                toml::Value::Array(items) if items.iter().all(toml::Value::is_str) => items
+                    .iter()
+                    .filter_map(toml::Value::as_str)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                _ => {
+                    errors.push(format!(
+                        "`{}` in {} has to be a string, number, boolean or list of strings",
+                        key, name
+                    ));
+                    continue;
+                }
+            };
+            settings.insert(key, value);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(settings)
+    } else {
+        Err(errors)
+    }
+}
+
+// Looks settings up, later sources win: the config file, the environment
+// (`.env` included), then flags. Problems are collected so they can all be
+// reported at once.
+struct Reader {
+    file: BTreeMap<String, String>,
+    flags: BTreeMap<String, String>,
+    errors: Vec<String>,
+}
+
+impl Reader {
+    // Empty values count as unset, so `.env` can leave a setting blank and the
+    // config file or default still applies
+    fn string(&self, name: &str) -> Option<String> {
+        let set = |value: &String| !value.is_empty();
+        self.flags
+            .get(&flag_name(name))
+            .cloned()
+            .filter(set)
+            .or_else(|| env::var(name).ok().filter(set))
+            .or_else(|| self.file.get(file_key(name)).cloned().filter(set))
+    }
+
+    fn string_or(&self, name: &str, default: &str) -> String {
+        self.string(name).unwrap_or_else(|| default.to_string())
+    }
+
+    // Comma separated, `GET, POST`
+    fn list(&self, name: &str, default: &[String]) -> Vec<String> {
+        match self.string(name) {
+            Some(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect(),
+            None => default.to_vec(),
+        }
+    }
+
+    fn invalid(&mut self, name: &str, expected: &str, value: &str) {
+        self.errors.push(format!(
+            "{} ({}) must be {}, got `{}`",
+            name,
+            file_key(name),
+            expected,
+            value
+        ));
+    }
+
+    fn optional_number<T: FromStr>(&mut self, name: &str) -> Option<T> {
+        let value = self.string(name)?;
+        match value.parse() {
+            Ok(number) => Some(number),
+            Err(_) => {
+                self.invalid(name, "a whole number", &value);
+                None
+            }
+        }
+    }
+
+    fn number<T: FromStr>(&mut self, name: &str, default: T) -> T {
+        self.optional_number(name).unwrap_or(default)
+    }
+
+    fn flag(&mut self, name: &str, default: bool) -> bool {
+        let value = match self.string(name) {
+            Some(value) => value,
+            None => return default,
+        };
+
+        match value.to_ascii_lowercase().as_str() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => {
+                self.invalid(name, "true or false", &value);
+                default
+            }
+        }
+    }
+
+    fn choice<T: Copy>(&mut self, name: &str, default: T, choices: &[(&str, T)]) -> T {
+        let value = match self.string(name) {
+            Some(value) => value,
+            None => return default,
+        };
+
+        match choices
+            .iter()
+            .find(|(choice, _)| choice.eq_ignore_ascii_case(&value))
+        {
+            Some((_, choice)) => *choice,
+            None => {
+                let names: Vec<&str> = choices.iter().map(|(choice, _)| *choice).collect();
+                self.invalid(name, &format!("one of {}", names.join(", ")), &value);
+                default
+            }
+        }
+    }
+}
+
+impl Config {
+    // Reads the configuration from, lowest precedence first: the defaults, the
+    // TOML file given with --config or CONFIG_FILE (`config.toml` when neither
+    // is set and it exists), the environment and the flags before the command.
+    // Returns the arguments left for the command.
+    pub fn load(args: &[String]) -> Result<(Config, Vec<String>), ConfigError> {
+        dotenv().ok();
+
+        let (flags, rest) = parse_flags(args)?;
+        let path = flags
+            .get("config")
+            .cloned()
+            .or_else(|| env::var("CONFIG_FILE").ok());
+        let file = match path {
+            Some(path) => read_file(Path::new(&path)).map_err(ConfigError)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_FILE)).map_err(ConfigError)?
+            }
+            None => BTreeMap::new(),
+        };
+
+        let mut reader = Reader {
+            file,
+            flags,
+            errors: Vec::new(),
+        };
+        let config = Config::read(&mut reader);
+
+        if reader.errors.is_empty() {
+            Ok((config, rest))
+        } else {
+            Err(ConfigError(reader.errors))
+        }
+    }
+
+    fn read(reader: &mut Reader) -> Config {
+        let server = ServerConfig {
+            app_url: reader.string_or("APP_URL", "127.0.0.1:7878"),
+            auto_migrate: reader.flag("AUTO_MIGRATE", true),
+        };
+
+        let database = DatabaseConfig {
+            url: reader.string("DATABASE_URL"),
+        };
+
+        let expiration_secs = reader.optional_number("EXP");
+        if expiration_secs == Some(0) {
+            reader.invalid("EXP", "more than 0 seconds", "0");
+        }
+
+        // HS256 signs with JWT_SECRET, the others use the keys in JWT_KEYS_DIR
+        let algorithm = reader.choice(
+            "JWT_ALGORITHM",
+            Algorithm::HS256,
+            &[
+                ("HS256", Algorithm::HS256),
+                ("HS384", Algorithm::HS384),
+                ("HS512", Algorithm::HS512),
+                ("RS256", Algorithm::RS256),
+                ("ES256", Algorithm::ES256),
+                ("EdDSA", Algorithm::EdDSA),
+            ],
+        );
+
+        let jwt = JwtConfig {
+            secret: reader.string("JWT_SECRET"),
+            refresh_secret: reader.string("REFRESH_TOKEN_SECRET"),
+            expiration_secs,
+            algorithm,
+            keys_dir: PathBuf::from(reader.string_or("JWT_KEYS_DIR", "keys")),
+            jwks_max_age: reader.number("JWKS_MAX_AGE", 3600),
+            issuer: reader.string_or("JWT_ISSUER", "no_framework_rust"),
+            audience: reader.string_or("JWT_AUDIENCE", "no_framework_rust"),
+            leeway: reader.number("JWT_LEEWAY", 60),
+        };
+
+        let auth = AuthConfig {
+            token_mode: reader.choice(
+                "AUTH_TOKEN_MODE",
+                TokenMode::Cookie,
+                &[("cookie", TokenMode::Cookie), ("json", TokenMode::Json)],
+            ),
+            totp_issuer: reader.string_or("TOTP_ISSUER", "no_framework_rust"),
+            password_reset_expiration_secs: reader
+                .number::<u32>("PASSWORD_RESET_EXPIRATION_SECS", 3600)
+                as i64,
+            password_reset_url: reader
+                .string_or("PASSWORD_RESET_URL", "http://localhost:8080/reset-password"),
+        };
+
+        let defaults = CookieOptions::default();
+        let cookies = CookieOptions {
+            http_only: reader.flag("COOKIE_HTTP_ONLY", defaults.http_only),
+            secure: reader.flag("COOKIE_SECURE", defaults.secure),
+            same_site: reader.choice(
+                "COOKIE_SAME_SITE",
+                defaults.same_site,
+                &[
+                    ("Strict", SameSite::Strict),
+                    ("Lax", SameSite::Lax),
+                    ("None", SameSite::None),
+                ],
+            ),
+            path: reader.string_or("COOKIE_PATH", &defaults.path),
+            domain: reader.string("COOKIE_DOMAIN"),
+            session: reader.flag("COOKIE_SESSION", defaults.session),
+        };
+
+        let login = LoginConfig {
+            attempt_store: reader.choice(
+                "LOGIN_ATTEMPT_STORE",
+                AttemptStoreKind::Postgres,
+                &[
+                    ("postgres", AttemptStoreKind::Postgres),
+                    ("memory", AttemptStoreKind::Memory),
+                ],
+            ),
+            lockout: LockoutPolicy {
+                max_failures_per_user: reader.number::<u16>("LOGIN_MAX_FAILURES", 5) as i32,
+                max_failures_per_ip: reader.number::<u16>("LOGIN_MAX_FAILURES_PER_IP", 20) as i32,
+                lockout_secs: reader.number::<u32>("LOGIN_LOCKOUT_SECS", 900) as i64,
+                window_secs: reader.number::<u32>("LOGIN_FAILURE_WINDOW_SECS", 900) as i64,
+                max_delay_secs: reader.number::<u32>("LOGIN_MAX_DELAY_SECS", 30) as i64,
+            },
+        };
+
+        // A SQLite database serves a single instance and has no attempts table,
+        // failed logins are always counted in memory there
+        let is_sqlite = database.url.as_deref().is_some_and(is_sqlite_url);
+        if is_sqlite && login.attempt_store == AttemptStoreKind::Postgres {
+            if let Some(value) = reader.string("LOGIN_ATTEMPT_STORE") {
+                reader.invalid(
+                    "LOGIN_ATTEMPT_STORE",
+                    "memory or unset when DATABASE_URL is a SQLite URL",
+                    &value,
+                );
+            }
+        }
+
+        let min_length = reader.number("PASSWORD_MIN_LENGTH", 8);
+        let max_length = reader.number("PASSWORD_MAX_LENGTH", 128);
+        if min_length == 0 || min_length > max_length {
+            reader.errors.push(format!(
+                "PASSWORD_MIN_LENGTH ({}) must be at least 1 and at most PASSWORD_MAX_LENGTH ({}), got {} and {}",
+                file_key("PASSWORD_MIN_LENGTH"),
+                file_key("PASSWORD_MAX_LENGTH"),
+                min_length,
+                max_length
+            ));
+        }
+
+        // OWASP's minimum recommendation for Argon2id
+        let argon2 = Params::new(
+            reader.number("ARGON2_MEMORY_KIB", 19456),
+            reader.number("ARGON2_ITERATIONS", 2),
+            reader.number("ARGON2_PARALLELISM", 1),
+            None,
+        )
+        .unwrap_or_else(|error| {
+            reader.errors.push(format!(
+                "ARGON2_MEMORY_KIB, ARGON2_ITERATIONS and ARGON2_PARALLELISM are not usable together: {}",
+                error
+            ));
+            Params::default()
+        });
+
+        let bcrypt_cost = reader.number("BCRYPT_COST", bcrypt::DEFAULT_COST);
+        if !(4..=31).contains(&bcrypt_cost) {
+            reader.invalid("BCRYPT_COST", "between 4 and 31", &bcrypt_cost.to_string());
+        }
+
+        let password = PasswordConfig {
+            min_length,
+            max_length,
+            breached_list: reader.string("PASSWORD_BREACHED_LIST").map(PathBuf::from),
+            hasher: reader.choice(
+                "PASSWORD_HASHER",
+                HasherKind::Argon2id,
+                &[
+                    ("argon2id", HasherKind::Argon2id),
+                    ("bcrypt", HasherKind::Bcrypt),
+                ],
+            ),
+            argon2,
+            bcrypt_cost,
+            dummy_hash: OnceLock::new(),
+            breached_passwords: OnceLock::new(),
+        };
+
+        let mail = MailConfig {
+            mailer: reader.choice(
+                "MAILER",
+                MailerKind::Outbox,
+                &[
+                    ("outbox", MailerKind::Outbox),
+                    ("file", MailerKind::File),
+                    ("smtp", MailerKind::Smtp),
+                ],
+            ),
+            outbox_path: reader.string_or("MAIL_OUTBOX_PATH", "outbox.jsonl"),
+            from: reader.string_or("MAIL_FROM", "no-reply@localhost"),
+            smtp_host: reader.string("SMTP_HOST"),
+            smtp_port: reader.number("SMTP_PORT", 587),
+            smtp_username: reader.string("SMTP_USERNAME"),
+            smtp_password: reader.string("SMTP_PASSWORD"),
+        };
+
+        let defaults = CorsPolicy::default();
+        let allowed_origins = match reader.string("CORS_ALLOWED_ORIGINS") {
+            Some(_) => reader
+                .list("CORS_ALLOWED_ORIGINS", &[])
+                .iter()
+                .filter_map(|origin| match OriginPattern::parse(origin) {
+                    Ok(pattern) => Some(pattern),
+                    Err(error) => {
+                        reader.errors.push(format!(
+                            "CORS_ALLOWED_ORIGINS ({}): {}",
+                            file_key("CORS_ALLOWED_ORIGINS"),
+                            error
+                        ));
+                        None
+                    }
+                })
+                .collect(),
+            None => defaults.allowed_origins,
+        };
+
+        let cors = CorsPolicy {
+            allowed_origins,
+            // Methods are case-sensitive, and always upper case for the ones browsers check
+            allowed_methods: reader
+                .list("CORS_ALLOWED_METHODS", &defaults.allowed_methods)
+                .iter()
+                .map(|method| method.to_ascii_uppercase())
+                .collect(),
+            allowed_headers: reader.list("CORS_ALLOWED_HEADERS", &defaults.allowed_headers),
+            exposed_headers: reader.list("CORS_EXPOSED_HEADERS", &defaults.exposed_headers),
+            allow_credentials: reader.flag("CORS_ALLOW_CREDENTIALS", defaults.allow_credentials),
+            max_age: reader.number("CORS_MAX_AGE", defaults.max_age),
+        };
+
+        // Browsers reject a `*` origin on requests with credentials, and
+        // reflecting any origin instead would let every site act as the user
+        let allows_any = cors
+            .allowed_origins
+            .iter()
+            .any(|pattern| matches!(pattern, OriginPattern::Any));
+        if allows_any && cors.allow_credentials {
+            reader.errors.push(String::from(
+                "CORS_ALLOWED_ORIGINS cannot be * while CORS_ALLOW_CREDENTIALS is true, list the origins instead",
+            ));
+        }
+
+        let log = LogConfig {
+            level: reader.choice(
+                "LOG_LEVEL",
+                Level::Info,
+                &[
+                    ("error", Level::Error),
+                    ("warn", Level::Warn),
+                    ("info", Level::Info),
+                    ("debug", Level::Debug),
+                ],
+            ),
+            format: reader.choice(
+                "LOG_FORMAT",
+                LogFormat::Logfmt,
+                &[("logfmt", LogFormat::Logfmt), ("json", LogFormat::Json)],
+            ),
+        };
+
+        let metrics = MetricsConfig {
+            token: reader.string("METRICS_TOKEN"),
+        };
+
+        // `group=30/60` or `group=off`, on top of the default buckets
+        let mut rate_limit = RateLimitPolicy::default();
+        rate_limit.enabled = reader.flag("RATE_LIMIT_ENABLED", rate_limit.enabled);
+        for entry in reader.list("RATE_LIMIT_BUCKETS", &[]) {
+            let pair = entry
+                .split_once('=')
+                .map(|(group, bucket)| (group.trim(), bucket.trim()));
+            let parsed = match pair {
+                Some((group, "off")) => Ok((group, None)),
+                Some((group, bucket)) => Bucket::parse(bucket).map(|bucket| (group, Some(bucket))),
+                None => Err(format!(
+                    "`{}` is not a group=bucket pair such as auth=30/60",
+                    entry
+                )),
+            };
+            match parsed {
+                Ok((group, bucket)) => {
+                    rate_limit.buckets.insert(group.to_string(), bucket);
+                }
+                Err(error) => reader.errors.push(format!(
+                    "RATE_LIMIT_BUCKETS ({}): {}",
+                    file_key("RATE_LIMIT_BUCKETS"),
+                    error
+                )),
+            }
+        }
+
+        Config {
+            server,
+            database,
+            jwt,
+            auth,
+            cookies,
+            login,
+            password,
+            mail,
+            cors,
+            log,
+            metrics,
+            rate_limit,
+        }
+    }
+
+    // Settings the server cannot run without. Commands only need some of them,
+    // they are checked with `check_keys` and `check_database` instead.
+    pub fn check_server(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        require(&mut errors, "DATABASE_URL", self.database.url.is_some());
+        require(&mut errors, "EXP", self.jwt.expiration_secs.is_some());
+        require(
+            &mut errors,
+            "REFRESH_TOKEN_SECRET",
+            self.jwt.refresh_secret.is_some(),
+        );
+        if !is_asymmetric(self.jwt.algorithm) {
+            require(&mut errors, "JWT_SECRET", self.jwt.secret.is_some());
+        }
+
+        if self.mail.mailer == MailerKind::Smtp {
+            require(&mut errors, "SMTP_HOST", self.mail.smtp_host.is_some());
+
+            #[cfg(feature = "smtp")]
+            if self.mail.from.parse::<lettre::message::Mailbox>().is_err() {
+                errors.push(format!(
+                    "MAIL_FROM ({}) must be an email address, got `{}`",
+                    file_key("MAIL_FROM"),
+                    self.mail.from
+                ));
+            }
+            #[cfg(not(feature = "smtp"))]
+            errors.push(String::from(
+                "MAILER is smtp, but SMTP support is not compiled in, build with the `smtp` feature",
+            ));
+        }
+
+        checked(errors)
+    }
+
+    // For `keys`, retired keys are kept for as long as the tokens they signed
+    // are valid
+    pub fn check_keys(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        require(&mut errors, "EXP", self.jwt.expiration_secs.is_some());
+        checked(errors)
+    }
+
+    // For `users` and `migrate`
+    pub fn check_database(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        require(&mut errors, "DATABASE_URL", self.database.url.is_some());
+        checked(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|flag| flag.to_string()).collect()
+    }
+
+    fn errors(flags: &[&str]) -> Vec<String> {
+        match Config::load(&args(flags)) {
+            Ok(_) => vec![],
+            Err(ConfigError(errors)) => errors,
+        }
+    }
+
+    #[test]
+    fn flags_win_over_the_environment_and_the_environment_over_the_file() {
+        let path = env::temp_dir().join(format!("config-{}.toml", std::process::id()));
+        fs::write(&path, "[auth]\ntotp_issuer = \"from the file\"\n").unwrap();
+        let config_flag = format!("--config={}", path.display());
+
+        // (environment, flag, issuer)
+        let cases = [
+            (None, None, "from the file"),
+            (Some("from the environment"), None, "from the environment"),
+            (None, Some("--totp-issuer=from a flag"), "from a flag"),
+            (
+                Some("from the environment"),
+                Some("--totp-issuer=from a flag"),
+                "from a flag",
+            ),
+            // Empty values count as unset
+            (Some(""), None, "from the file"),
+        ];
+
+        for (environment, flag, issuer) in cases {
+            match environment {
+                Some(value) => env::set_var("TOTP_ISSUER", value),
+                None => env::remove_var("TOTP_ISSUER"),
+            }
+            let mut flags = vec![config_flag.as_str()];
+            flags.extend(flag);
+
+            let (config, _) = Config::load(&args(&flags)).unwrap();
+            assert_eq!(
+                config.auth.totp_issuer, issuer,
+                "{:?}, {:?}",
+                environment, flag
+            );
+        }
+
+        env::remove_var("TOTP_ISSUER");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn arguments_after_the_flags_are_left_for_the_command() {
+        let (_, rest) = Config::load(&args(&["--exp=900", "keys", "roll", "--exp=1"])).unwrap();
+
+        assert_eq!(rest, args(&["keys", "roll", "--exp=1"]));
+    }
+
+    #[test]
+    fn invalid_settings_are_reported() {
+        // (flags, start of the error)
+        let cases: [(&[&str], &str); 9] = [
+            (&["--exp=0"], "EXP (jwt.expiration_secs) must be more than 0 seconds"),
+            (&["--exp=soon"], "EXP (jwt.expiration_secs) must be a whole number"),
+            (&["--no-such-setting=1"], "Unknown flag --no-such-setting"),
+            (&["--jwt-algorithm=HS1024"], "JWT_ALGORITHM (jwt.algorithm) must be"),
+            (&["--rate-limit-buckets=auth=fast"], "RATE_LIMIT_BUCKETS (rate_limit.buckets): `fast` is not a bucket"),
+            (&["--rate-limit-buckets=auth"], "RATE_LIMIT_BUCKETS (rate_limit.buckets): `auth` is not a group=bucket pair"),
+            (
+                &["--password-min-length=20", "--password-max-length=10"],
+                "PASSWORD_MIN_LENGTH (password.min_length) must be at least 1 and at most PASSWORD_MAX_LENGTH",
+            ),
+            (
+                &["--cors-allowed-origins=*", "--cors-allow-credentials=true"],
+                "CORS_ALLOWED_ORIGINS cannot be * while CORS_ALLOW_CREDENTIALS is true",
+            ),
+            (
+                &["--database-url=sqlite:app.db", "--login-attempt-store=postgres"],
+                "LOGIN_ATTEMPT_STORE (login.attempt_store) must be memory or unset",
+            ),
+        ];
+
+        for (flags, error) in cases {
+            let errors = errors(flags);
+            assert!(
+                errors.iter().any(|reported| reported.starts_with(error)),
+                "{:?} reported {:?}",
+                flags,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let errors = errors(&["--exp=0", "--bcrypt-cost=2", "--log-level=loud"]);
+
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+    }
+
+    #[test]
+    fn the_server_needs_the_secrets_its_algorithm_signs_with() {
+        let required = [
+            "--database-url=postgres://localhost/app",
+            "--exp=900",
+            "--refresh-token-secret=refresh",
+        ];
+
+        // (flags on top of the required ones, missing settings)
+        let cases: [(&[&str], &[&str]); 3] = [
+            (
+                &["--jwt-algorithm=HS256"],
+                &["JWT_SECRET (jwt.secret) is not set"],
+            ),
+            (&["--jwt-algorithm=HS256", "--jwt-secret=secret"], &[]),
+            // The asymmetric algorithms sign with the keys in JWT_KEYS_DIR
+            (&["--jwt-algorithm=EdDSA"], &[]),
+        ];
+
+        for (flags, missing) in cases {
+            let mut all_flags = required.to_vec();
+            all_flags.extend(flags);
+            let (config, _) = Config::load(&args(&all_flags)).unwrap();
+
+            let errors = match config.check_server() {
+                Ok(_) => vec![],
+                Err(ConfigError(errors)) => errors,
+            };
+            assert_eq!(errors, missing.to_vec(), "{:?}", flags);
+        }
+    }
+}
@@ -0,0 +1,580 @@
+use sha2::{Digest, Sha256};
+use sqlx::{pool::PoolConnection, Connection, Executor, Postgres, Row};
+use std::fmt;
+
+#[cfg(feature = "sqlite")]
+use sqlx::Sqlite;
+
+use crate::{app::services::utils::current_timestamp, logging};
+
+use super::DatabasePool;
+
+// A versioned change to the schema. The SQL lives in `migrations/` and is
+// compiled into the binary, so a deployed binary always carries its schema.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+impl Migration {
+    // Applied migrations are compared by checksum, so editing one after it has
+    // shipped is caught instead of silently diverging between databases
+    pub fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.up.as_bytes()))
+    }
+}
+
+macro_rules! migration {
+    ($dir:literal, $version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../../migrations/", $dir, $name, ".up.sql")),
+            down: include_str!(concat!("../../migrations/", $dir, $name, ".down.sql")),
+        }
+    };
+}
+
+// NOTE: Add new migrations at the end, with the next version
+pub static MIGRATIONS: &[Migration] = &[
+    migration!("", 1, "0001_create_users"),
+    migration!("", 2, "0002_create_sessions"),
+    migration!("", 3, "0003_add_user_roles"),
+    migration!("", 4, "0004_create_api_keys"),
+    migration!("", 5, "0005_add_totp"),
+    migration!("", 6, "0006_create_login_attempts_and_audit_log"),
+    migration!("", 7, "0007_create_password_resets"),
+    migration!("", 8, "0008_add_username_key"),
+];
+
+// SQLite only keeps users and sessions, so it has its own, shorter history.
+// Schema changes to those tables need a migration in both lists.
+#[cfg(feature = "sqlite")]
+pub static SQLITE_MIGRATIONS: &[Migration] =
+    &[migration!("sqlite/", 1, "0001_create_users_and_sessions")];
+
+// Arbitrary, but fixed, key of the advisory lock that keeps two instances
+// from migrating the same database at once
+const MIGRATION_LOCK_KEY: i64 = 7_180_424_171;
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Database(sqlx::Error),
+    // An applied migration no longer matches the SQL in the binary
+    ChecksumMismatch(i64, &'static str),
+    // The database has a migration this binary does not know, it is newer than the binary
+    UnknownVersion(i64),
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrateError::Database(error) => write!(f, "{}", error),
+            MigrateError::ChecksumMismatch(version, name) => write!(
+                f,
+                "Migration {} ({}) was changed after it was applied",
+                version, name
+            ),
+            MigrateError::UnknownVersion(version) => write!(
+                f,
+                "Database has migration {} applied, which this binary does not know",
+                version
+            ),
+        }
+    }
+}
+
+impl From<sqlx::Error> for MigrateError {
+    fn from(error: sqlx::Error) -> Self {
+        MigrateError::Database(error)
+    }
+}
+
+#[derive(Debug)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub checksum: String,
+    pub applied_at: i64,
+}
+
+// What the runner needs from a database, the SQL of these differs between backends
+trait MigrationTarget {
+    fn migrations(&self) -> &'static [Migration];
+
+    // Keeps two instances from migrating the same database at once
+    async fn lock(&mut self) -> Result<(), sqlx::Error>;
+
+    async fn unlock(&mut self) -> Result<(), sqlx::Error>;
+
+    async fn has_history_table(&mut self) -> Result<bool, sqlx::Error>;
+
+    async fn ensure_history_table(&mut self) -> Result<(), sqlx::Error>;
+
+    async fn applied_migrations(&mut self) -> Result<Vec<AppliedMigration>, sqlx::Error>;
+
+    // Runs the up or down SQL of a migration and updates the history in one transaction
+    async fn run(&mut self, migration: &Migration, up: bool) -> Result<(), sqlx::Error>;
+}
+
+const CREATE_HISTORY_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY, name TEXT NOT NULL, checksum TEXT NOT NULL, applied_at BIGINT NOT NULL)";
+
+macro_rules! history_queries {
+    () => {
+        async fn ensure_history_table(&mut self) -> Result<(), sqlx::Error> {
+            self.execute(CREATE_HISTORY_TABLE).await?;
+            Ok(())
+        }
+
+        async fn applied_migrations(&mut self) -> Result<Vec<AppliedMigration>, sqlx::Error> {
+            let query =
+                "SELECT version, checksum, applied_at FROM schema_migrations ORDER BY version";
+            let rows = sqlx::query(query).fetch_all(&mut **self).await?;
+
+            rows.iter()
+                .map(|row| {
+                    Ok(AppliedMigration {
+                        version: row.try_get("version")?,
+                        checksum: row.try_get("checksum")?,
+                        applied_at: row.try_get("applied_at")?,
+                    })
+                })
+                .collect()
+        }
+
+        async fn run(&mut self, migration: &Migration, up: bool) -> Result<(), sqlx::Error> {
+            let mut tx = self.begin().await?;
+
+            if up {
+                tx.execute(migration.up).await?;
+
+                let query = "INSERT INTO schema_migrations (version, name, checksum, applied_at) VALUES ($1, $2, $3, $4)";
+                sqlx::query(query)
+                    .bind(migration.version)
+                    .bind(migration.name)
+                    .bind(migration.checksum())
+                    .bind(current_timestamp() as i64)
+                    .execute(&mut *tx)
+                    .await?;
+            } else {
+                tx.execute(migration.down).await?;
+
+                sqlx::query("DELETE FROM schema_migrations WHERE version = $1")
+                    .bind(migration.version)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+
+            tx.commit().await
+        }
+    };
+}
+
+impl MigrationTarget for PoolConnection<Postgres> {
+    fn migrations(&self) -> &'static [Migration] {
+        MIGRATIONS
+    }
+
+    async fn lock(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT pg_advisory_lock($1)")
+            .bind(MIGRATION_LOCK_KEY)
+            .execute(&mut **self)
+            .await?;
+        Ok(())
+    }
+
+    async fn unlock(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT pg_advisory_unlock($1)")
+            .bind(MIGRATION_LOCK_KEY)
+            .execute(&mut **self)
+            .await?;
+        Ok(())
+    }
+
+    async fn has_history_table(&mut self) -> Result<bool, sqlx::Error> {
+        let query = "SELECT to_regclass('schema_migrations') IS NOT NULL AS exists";
+        sqlx::query(query)
+            .fetch_one(&mut **self)
+            .await?
+            .try_get("exists")
+    }
+
+    history_queries!();
+}
+
+#[cfg(feature = "sqlite")]
+impl MigrationTarget for PoolConnection<Sqlite> {
+    fn migrations(&self) -> &'static [Migration] {
+        SQLITE_MIGRATIONS
+    }
+
+    // SQLite has no advisory locks. It only lets one writer in at a time, so a
+    // second instance fails on the history entry instead of applying twice.
+    async fn lock(&mut self) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    async fn unlock(&mut self) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    async fn has_history_table(&mut self) -> Result<bool, sqlx::Error> {
+        let query = "SELECT count(*) > 0 AS present FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'";
+        sqlx::query(query)
+            .fetch_one(&mut **self)
+            .await?
+            .try_get("present")
+    }
+
+    history_queries!();
+}
+
+// Reads the history without creating it, so a dry run leaves the database untouched
+async fn applied_migrations<T: MigrationTarget>(
+    target: &mut T,
+) -> Result<Vec<AppliedMigration>, MigrateError> {
+    if !target.has_history_table().await? {
+        return Ok(Vec::new());
+    }
+
+    Ok(target.applied_migrations().await?)
+}
+
+fn verify(
+    migrations: &'static [Migration],
+    applied: &[AppliedMigration],
+) -> Result<(), MigrateError> {
+    for applied in applied {
+        let migration = migrations
+            .iter()
+            .find(|migration| migration.version == applied.version)
+            .ok_or(MigrateError::UnknownVersion(applied.version))?;
+
+        if migration.checksum() != applied.checksum {
+            return Err(MigrateError::ChecksumMismatch(
+                migration.version,
+                migration.name,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn pending(
+    migrations: &'static [Migration],
+    applied: &[AppliedMigration],
+) -> Vec<&'static Migration> {
+    migrations
+        .iter()
+        .filter(|migration| {
+            !applied
+                .iter()
+                .any(|applied| applied.version == migration.version)
+        })
+        .collect()
+}
+
+// Runs `f` while holding the migration lock. The lock goes with the connection
+// once it closes, so failing to release it is only logged and never hides what
+// `f` did.
+async fn with_lock<T: MigrationTarget, R>(
+    target: &mut T,
+    f: impl AsyncFnOnce(&mut T) -> Result<R, MigrateError>,
+) -> Result<R, MigrateError> {
+    target.lock().await?;
+    let result = f(target).await;
+    if let Err(error) = target.unlock().await {
+        logging::warn(
+            "Could not release the migration lock",
+            &[("error", error.to_string().into())],
+        );
+    }
+
+    result
+}
+
+async fn up<T: MigrationTarget>(
+    target: &mut T,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, MigrateError> {
+    with_lock(target, async |target| {
+        let migrations = target.migrations();
+        let applied = applied_migrations(target).await?;
+        verify(migrations, &applied)?;
+
+        let pending = pending(migrations, &applied);
+        if dry_run {
+            return Ok(pending);
+        }
+
+        target.ensure_history_table().await?;
+        for migration in &pending {
+            target.run(migration, true).await?;
+        }
+
+        Ok(pending)
+    })
+    .await
+}
+
+async fn down<T: MigrationTarget>(
+    target: &mut T,
+    steps: usize,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, MigrateError> {
+    with_lock(target, async |target| {
+        let migrations = target.migrations();
+        let applied = applied_migrations(target).await?;
+        verify(migrations, &applied)?;
+
+        let reverted: Vec<&'static Migration> = applied
+            .iter()
+            .rev()
+            .take(steps)
+            .filter_map(|applied| {
+                migrations
+                    .iter()
+                    .find(|migration| migration.version == applied.version)
+            })
+            .collect();
+        if dry_run {
+            return Ok(reverted);
+        }
+
+        for migration in &reverted {
+            target.run(migration, false).await?;
+        }
+
+        Ok(reverted)
+    })
+    .await
+}
+
+async fn list<T: MigrationTarget>(
+    target: &mut T,
+) -> Result<Vec<(&'static Migration, Option<i64>)>, MigrateError> {
+    let migrations = target.migrations();
+    let applied = applied_migrations(target).await?;
+    verify(migrations, &applied)?;
+
+    Ok(migrations
+        .iter()
+        .map(|migration| {
+            let applied_at = applied
+                .iter()
+                .find(|applied| applied.version == migration.version)
+                .map(|applied| applied.applied_at);
+            (migration, applied_at)
+        })
+        .collect())
+}
+
+// Applies every pending migration, each in its own transaction together with its
+// history entry. Returns the migrations that were, or with `dry_run` would be, applied.
+pub async fn migrate_up(
+    pool: &DatabasePool,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, MigrateError> {
+    match pool {
+        DatabasePool::Postgres(pool) => up(&mut pool.acquire().await?, dry_run).await,
+        #[cfg(feature = "sqlite")]
+        DatabasePool::Sqlite(pool) => up(&mut pool.acquire().await?, dry_run).await,
+    }
+}
+
+// Reverts the latest `steps` applied migrations, newest first
+pub async fn migrate_down(
+    pool: &DatabasePool,
+    steps: usize,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, MigrateError> {
+    match pool {
+        DatabasePool::Postgres(pool) => down(&mut pool.acquire().await?, steps, dry_run).await,
+        #[cfg(feature = "sqlite")]
+        DatabasePool::Sqlite(pool) => down(&mut pool.acquire().await?, steps, dry_run).await,
+    }
+}
+
+// Every known migration with the time it was applied, if it has been
+pub async fn status(
+    pool: &DatabasePool,
+) -> Result<Vec<(&'static Migration, Option<i64>)>, MigrateError> {
+    match pool {
+        DatabasePool::Postgres(pool) => list(&mut pool.acquire().await?).await,
+        #[cfg(feature = "sqlite")]
+        DatabasePool::Sqlite(pool) => list(&mut pool.acquire().await?).await,
+    }
+}
+
+// Brings the schema up to date before the server starts. With AUTO_MIGRATE=false
+// pending migrations are only reported and have to be applied with `migrate up`.
+pub async fn migrate_on_startup(
+    pool: &DatabasePool,
+    auto_migrate: bool,
+) -> Result<(), MigrateError> {
+    let migrations = migrate_up(pool, !auto_migrate).await?;
+    for migration in migrations {
+        let fields = [
+            ("version", migration.version.into()),
+            ("name", migration.name.into()),
+        ];
+        if auto_migrate {
+            logging::info("Applied migration", &fields);
+        } else {
+            logging::warn(
+                "Migration is pending, run `migrate up` to apply it",
+                &fields,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "0001_first",
+            up: "CREATE TABLE first (id INTEGER)",
+            down: "DROP TABLE first",
+        },
+        Migration {
+            version: 2,
+            name: "0002_second",
+            up: "CREATE TABLE second (id INTEGER)",
+            down: "DROP TABLE second",
+        },
+        Migration {
+            version: 3,
+            name: "0003_third",
+            up: "CREATE TABLE third (id INTEGER)",
+            down: "DROP TABLE third",
+        },
+    ];
+
+    fn applied(version: i64) -> AppliedMigration {
+        let migration = &TEST_MIGRATIONS[version as usize - 1];
+        AppliedMigration {
+            version,
+            checksum: migration.checksum(),
+            applied_at: 0,
+        }
+    }
+
+    fn versions(migrations: &[&Migration]) -> Vec<i64> {
+        migrations
+            .iter()
+            .map(|migration| migration.version)
+            .collect()
+    }
+
+    #[test]
+    fn applied_migrations_are_verified() {
+        assert!(verify(TEST_MIGRATIONS, &[]).is_ok());
+        assert!(verify(TEST_MIGRATIONS, &[applied(1), applied(2)]).is_ok());
+
+        let mut changed = applied(2);
+        changed.checksum = String::from("edited");
+        assert!(matches!(
+            verify(TEST_MIGRATIONS, &[applied(1), changed]),
+            Err(MigrateError::ChecksumMismatch(2, "0002_second"))
+        ));
+
+        let newer = AppliedMigration {
+            version: 4,
+            checksum: String::new(),
+            applied_at: 0,
+        };
+        assert!(matches!(
+            verify(TEST_MIGRATIONS, &[applied(1), newer]),
+            Err(MigrateError::UnknownVersion(4))
+        ));
+    }
+
+    #[test]
+    fn pending_migrations_keep_their_order() {
+        assert_eq!(versions(&pending(TEST_MIGRATIONS, &[])), vec![1, 2, 3]);
+        assert_eq!(
+            versions(&pending(TEST_MIGRATIONS, &[applied(1)])),
+            vec![2, 3]
+        );
+        // A migration left out in between, say from a merged branch, still runs
+        assert_eq!(
+            versions(&pending(TEST_MIGRATIONS, &[applied(1), applied(3)])),
+            vec![2]
+        );
+        assert!(pending(TEST_MIGRATIONS, &[applied(1), applied(2), applied(3)]).is_empty());
+    }
+
+    #[test]
+    fn every_migration_has_the_next_version() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i64 + 1, "{}", migration.name);
+        }
+    }
+
+    // Fails whatever it is asked to do once the lock is taken
+    struct BrokenTarget {
+        unlocked: bool,
+    }
+
+    impl MigrationTarget for BrokenTarget {
+        fn migrations(&self) -> &'static [Migration] {
+            TEST_MIGRATIONS
+        }
+
+        async fn lock(&mut self) -> Result<(), sqlx::Error> {
+            Ok(())
+        }
+
+        async fn unlock(&mut self) -> Result<(), sqlx::Error> {
+            self.unlocked = true;
+            Err(sqlx::Error::PoolClosed)
+        }
+
+        async fn has_history_table(&mut self) -> Result<bool, sqlx::Error> {
+            Ok(true)
+        }
+
+        async fn ensure_history_table(&mut self) -> Result<(), sqlx::Error> {
+            Ok(())
+        }
+
+        async fn applied_migrations(&mut self) -> Result<Vec<AppliedMigration>, sqlx::Error> {
+            Ok(vec![applied(1)])
+        }
+
+        async fn run(&mut self, _: &Migration, _: bool) -> Result<(), sqlx::Error> {
+            Err(sqlx::Error::RowNotFound)
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_unlock_does_not_hide_the_migration_error() {
+        let mut target = BrokenTarget { unlocked: false };
+
+        let result = up(&mut target, false).await;
+
+        assert!(target.unlocked);
+        assert!(matches!(
+            result,
+            Err(MigrateError::Database(sqlx::Error::RowNotFound))
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_failed_unlock_does_not_fail_a_dry_run() {
+        let mut target = BrokenTarget { unlocked: false };
+
+        let pending = up(&mut target, true).await.unwrap();
+
+        assert_eq!(versions(&pending), vec![2, 3]);
+    }
+}
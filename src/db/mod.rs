@@ -0,0 +1,115 @@
+use sqlx::postgres::PgPool;
+
+#[cfg(feature = "sqlite")]
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool};
+#[cfg(feature = "sqlite")]
+use std::str::FromStr;
+
+use crate::config::DatabaseConfig;
+
+pub mod migrate;
+
+// The database DATABASE_URL points to, picked by the scheme of the URL. SQLite
+// (`sqlite:app.db`) needs the `sqlite` feature and only backs users and sessions,
+// everything else needs Postgres.
+#[derive(Clone)]
+pub enum DatabasePool {
+    Postgres(PgPool),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqlitePool),
+}
+
+impl DatabasePool {
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        match self {
+            DatabasePool::Postgres(pool) => {
+                sqlx::query("SELECT 1").execute(pool).await?;
+            }
+            #[cfg(feature = "sqlite")]
+            DatabasePool::Sqlite(pool) => {
+                sqlx::query("SELECT 1").execute(pool).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // For what is only kept in Postgres
+    pub fn postgres(&self) -> Result<PgPool, sqlx::Error> {
+        match self {
+            DatabasePool::Postgres(pool) => Ok(pool.clone()),
+            #[cfg(feature = "sqlite")]
+            DatabasePool::Sqlite(_) => Err(sqlx::Error::Configuration(
+                "Only users and sessions can be kept in SQLite, this needs Postgres".into(),
+            )),
+        }
+    }
+
+    pub fn backend(&self) -> &'static str {
+        match self {
+            DatabasePool::Postgres(_) => "postgres",
+            #[cfg(feature = "sqlite")]
+            DatabasePool::Sqlite(_) => "sqlite",
+        }
+    }
+
+    // Connections open, idle or in use, along with the most the pool opens
+    pub fn connections(&self) -> PoolConnections {
+        match self {
+            DatabasePool::Postgres(pool) => PoolConnections {
+                open: pool.size(),
+                idle: pool.num_idle(),
+                max: pool.options().get_max_connections(),
+            },
+            #[cfg(feature = "sqlite")]
+            DatabasePool::Sqlite(pool) => PoolConnections {
+                open: pool.size(),
+                idle: pool.num_idle(),
+                max: pool.options().get_max_connections(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConnections {
+    pub open: u32,
+    pub idle: usize,
+    pub max: u32,
+}
+
+pub fn database_url(database: &DatabaseConfig) -> Result<&str, sqlx::Error> {
+    database
+        .url
+        .as_deref()
+        .ok_or_else(|| sqlx::Error::Configuration("DATABASE_URL is not set".into()))
+}
+
+pub fn is_sqlite_url(url: &str) -> bool {
+    url.starts_with("sqlite:")
+}
+
+// Opens the pool the server shares between all requests. Connections are only
+// made once they are needed, so the server starts while the database is still
+// unreachable and `/readyz` tells it is not ready.
+pub fn open(database: &DatabaseConfig) -> Result<DatabasePool, sqlx::Error> {
+    let database_url = database_url(database)?;
+    if !is_sqlite_url(database_url) {
+        return Ok(DatabasePool::Postgres(PgPool::connect_lazy(database_url)?));
+    }
+
+    #[cfg(feature = "sqlite")]
+    {
+        // WAL lets readers carry on while a request writes
+        let options = SqliteConnectOptions::from_str(database_url)?
+            .create_if_missing(true)
+            .foreign_keys(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        Ok(DatabasePool::Sqlite(SqlitePool::connect_lazy_with(options)))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    Err(sqlx::Error::Configuration(
+        "DATABASE_URL is a SQLite URL, but SQLite support is not built in, build with --features sqlite".into(),
+    ))
+}
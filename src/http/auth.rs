@@ -0,0 +1,162 @@
+use jsonwebtoken::errors::ErrorKind;
+
+use crate::app::{
+    models::{api_key::ApiKeyCaller, claims::Claims},
+    services::{api_key::ApiKeyService, error::AuthError, utils::verify_token},
+    state::AppState,
+};
+
+use super::{
+    request::Request,
+    utils::{
+        error_response_with_headers, extract_cookie, needs_postgres_response, something_went_wrong,
+    },
+};
+
+const REALM: &str = "api";
+
+// Why a request could not be authenticated, mapped onto the RFC 6750 error codes
+#[derive(Debug, PartialEq)]
+pub enum AuthFailure {
+    // No credentials at all, the challenge carries no error code
+    MissingCredentials,
+    // The `Authorization` header is present but is not a well formed bearer token
+    MalformedHeader,
+    InvalidToken,
+    ExpiredToken,
+    // An API key was presented but is unknown, expired or revoked
+    InvalidApiKey,
+}
+
+impl AuthFailure {
+    fn status_code(&self) -> u16 {
+        match self {
+            AuthFailure::MalformedHeader => 400,
+            _ => 401,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            AuthFailure::MissingCredentials => "Authentication is required",
+            AuthFailure::MalformedHeader => "Authorization header is not a valid bearer token",
+            AuthFailure::InvalidToken => "Access token is invalid",
+            AuthFailure::ExpiredToken => "Access token has expired",
+            AuthFailure::InvalidApiKey => "API key is invalid, expired or revoked",
+        }
+    }
+
+    pub fn challenge(&self) -> String {
+        let error = match self {
+            AuthFailure::MissingCredentials => return format!("Bearer realm=\"{}\"", REALM),
+            AuthFailure::InvalidApiKey => {
+                return format!(
+                    "ApiKey realm=\"{}\", error=\"invalid_key\", error_description=\"{}\"",
+                    REALM,
+                    self.description()
+                )
+            }
+            AuthFailure::MalformedHeader => "invalid_request",
+            AuthFailure::InvalidToken | AuthFailure::ExpiredToken => "invalid_token",
+        };
+
+        format!(
+            "Bearer realm=\"{}\", error=\"{}\", error_description=\"{}\"",
+            REALM,
+            error,
+            self.description()
+        )
+    }
+
+    pub fn response(&self) -> String {
+        error_response_with_headers(
+            self.status_code(),
+            self.description(),
+            &[("WWW-Authenticate", self.challenge())],
+        )
+    }
+}
+
+// Pulls the token out of `Authorization: Bearer <token>`. The scheme is
+// case-insensitive and the token has to be a single token68 value.
+fn extract_bearer_token(header: &str) -> Result<Option<&str>, AuthFailure> {
+    let (scheme, token) = match header.trim().split_once(' ') {
+        Some((scheme, token)) => (scheme, token.trim()),
+        None => (header.trim(), ""),
+    };
+
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        // Some other scheme, which is not ours to handle
+        return Ok(None);
+    }
+
+    let is_token68 = !token.is_empty()
+        && token.chars().all(|c| {
+            c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~' | '+' | '/' | '=')
+        });
+
+    if is_token68 {
+        Ok(Some(token))
+    } else {
+        Err(AuthFailure::MalformedHeader)
+    }
+}
+
+// Resolves the caller of a request. A bearer token in the `Authorization`
+// header takes precedence, otherwise the `token` session cookie is used.
+pub fn authenticate(state: &AppState, request: &Request) -> Result<Claims, AuthFailure> {
+    let bearer_token = match request.authorization_header() {
+        Some(header) => extract_bearer_token(header)?.map(String::from),
+        None => None,
+    };
+
+    let token = bearer_token
+        .or_else(|| extract_cookie(request.cookies.as_ref(), "token"))
+        .ok_or(AuthFailure::MissingCredentials)?;
+
+    match verify_token(&state.config.jwt, &token) {
+        Ok(token_data) => Ok(token_data.claims),
+        Err(error) => match error.kind() {
+            ErrorKind::ExpiredSignature => Err(AuthFailure::ExpiredToken),
+            _ => Err(AuthFailure::InvalidToken),
+        },
+    }
+}
+
+// Pulls an API key out of `X-API-Key: <key>` or `Authorization: ApiKey <key>`
+pub fn extract_api_key<'a>(request: &Request<'a>) -> Option<&'a str> {
+    if let Some(key) = request.header("X-API-Key") {
+        return Some(key.trim());
+    }
+
+    let (scheme, key) = request.authorization_header()?.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("ApiKey") {
+        Some(key.trim())
+    } else {
+        None
+    }
+}
+
+// Resolves the owner of an API key, if the request carries one. Unlike bearer
+// tokens a presented key that does not check out is rejected straight away,
+// so the error is the response to send back.
+pub async fn authenticate_api_key(
+    state: &AppState,
+    request: &Request<'_>,
+) -> Result<Option<ApiKeyCaller>, String> {
+    let key = match extract_api_key(request) {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+
+    let pool = state
+        .db
+        .postgres()
+        .map_err(|_| needs_postgres_response("API keys"))?;
+
+    match ApiKeyService::new(pool).authenticate(key).await {
+        Ok(caller) => Ok(Some(caller)),
+        Err(AuthError::Database(error)) => Err(something_went_wrong(error.to_string())),
+        Err(_) => Err(AuthFailure::InvalidApiKey.response()),
+    }
+}
@@ -1,34 +1,130 @@
-use dotenv::dotenv;
+use serde_json::Value;
 use std::{
     io::{Read, Write},
     net::{Shutdown, TcpStream},
     sync::mpsc,
     thread,
+    time::Instant,
 };
 
-use crate::app::router::app::Router;
-
-use super::utils::{
-    extract_request, extract_token_from_auth, extract_token_from_cookies, is_token_expired,
-    refresh_access_token, should_require_token_verification, unauthorized_response,
+use crate::{
+    app::{router::app::Router, services::utils::random_hex, state::AppState},
+    logging, metrics,
 };
 
-fn handle_options_request(stream: &mut TcpStream) {
-    let response = "HTTP/1.1 200 OK\r\n\
-    Access-Control-Allow-Origin: *\r\n\
-    Access-Control-Allow-Methods: GET, POST, PUT, DELETE, PATCH, OPTIONS\r\n\
-    Access-Control-Allow-Headers: Content-Type, Authorization, Cookie\r\n\
-    Content-Length: 0\r\n\
-    \r\n";
+use super::auth::{authenticate, authenticate_api_key, extract_api_key};
+use super::request::Request;
+use super::utils::insert_headers;
+
+// Taken from `X-Request-Id` when a client or proxy in front already set a
+// usable one, so a request can be followed across services
+fn request_id(request: &Request) -> String {
+    let is_usable = |id: &str| {
+        (1..=128).contains(&id.len())
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+    };
+
+    match request.header("X-Request-Id") {
+        Some(id) if is_usable(id) => id.to_string(),
+        _ => random_hex(16),
+    }
+}
+
+// Everything between parsing the request and writing the response
+async fn respond(state: &AppState, request: &mut Request<'_>, app_router: &Router) -> String {
+    let cors = &state.config.cors;
+
+    // Preflights carry no credentials, so they are answered before authenticating
+    if request.method == "OPTIONS" {
+        return cors.preflight(request, app_router.allowed_methods(&request.uri));
+    }
+
+    // An API key costs a query to look up, so requests presenting one first take
+    // from the client IP's bucket
+    let limits = &state.config.rate_limit;
+    let mut rate_limit_headers = vec![];
+    if extract_api_key(request).is_some() {
+        let group = Router::prefix(&request.uri);
+        rate_limit_headers = match limits.check_client(group, request).await {
+            Ok(headers) => headers,
+            Err(response) => return cors.apply(request, response),
+        };
+    }
+
+    // Resolve the caller for every route so public routes can still tell who is
+    // signed in. Whether a route needs a caller at all is decided by the router.
+    // Expired access tokens are not refreshed here, clients are expected to
+    // call `/auth/refresh` themselves.
+    let api_key_error = match authenticate_api_key(state, request).await {
+        Ok(Some(caller)) => {
+            request.api_key = Some(caller);
+            None
+        }
+        Ok(None) => {
+            if let Ok(claims) = authenticate(state, request) {
+                request.claims = Some(claims);
+            }
+            None
+        }
+        Err(response) => Some(response),
+    };
 
-    if let Err(err) = stream.write_all(response.as_bytes()) {
-        eprintln!("Error writing OPTIONS response to stream: {:?}", err);
+    // A rejected API key has only taken from the client IP's bucket
+    if let Some(response) = api_key_error {
+        return cors.apply(request, insert_headers(response, &rate_limit_headers));
     }
+
+    // Limited once the caller is known, so callers get a bucket of their own
+    let group = Router::prefix(&request.uri);
+    let rate_limit_headers = match limits.check(group, request).await {
+        Ok(headers) => headers,
+        Err(response) => return cors.apply(request, response),
+    };
+
+    let response = app_router.route(request).await;
+    cors.apply(request, insert_headers(response, &rate_limit_headers))
+}
+
+// The status code from the response's status line
+fn status(response: &str) -> u16 {
+    response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_default()
 }
 
-pub async fn handle_connection(mut stream: TcpStream) {
-    dotenv().ok();
+fn log_access(request: &Request, response: &str, started: Instant) {
+    let status = status(response);
+    let duration_ms = (started.elapsed().as_secs_f64() * 1e6).round() / 1e3;
+
+    let mut fields: Vec<(&str, Value)> = vec![
+        ("method", request.method.as_str().into()),
+        ("path", request.uri.as_str().into()),
+        ("status", status.into()),
+        ("bytes", response.len().into()),
+        ("duration_ms", duration_ms.into()),
+    ];
+    if let Some(peer) = request.client_ip() {
+        fields.push(("peer", peer.into()));
+    }
+    let user_id = match (&request.claims, &request.api_key) {
+        (Some(claims), _) => Some(claims.uid),
+        (None, Some(caller)) => Some(caller.uid),
+        (None, None) => None,
+    };
+    if let Some(user_id) = user_id {
+        fields.push(("user_id", user_id.into()));
+    }
+
+    logging::info("request", &fields);
+}
 
+pub async fn handle_connection(mut stream: TcpStream, state: AppState) {
+    let started = Instant::now();
+    let _connection = metrics::connection_started();
     let mut buffer = Vec::new();
 
     loop {
@@ -51,91 +147,67 @@ pub async fn handle_connection(mut stream: TcpStream) {
         }
     }
 
-    let request = String::from_utf8_lossy(&buffer);
-    let (uri, method, authorization_header, body, cookies) = extract_request(&request);
-
-    let mut request_cookies = cookies.clone();
-    let session_token = cookies
-        .clone()
-        .unwrap_or_else(|| extract_token_from_auth(authorization_header.unwrap_or_else(|| "")));
-
-    // NOTE: Add the public routes in this function
-    let is_token_verification_required = should_require_token_verification(&uri);
-
-    if method == "OPTIONS" {
-        handle_options_request(&mut stream);
-        return;
-    }
-
-    // Verify access token before moving forward
-    if is_token_verification_required {
-        match extract_token_from_cookies(Some(session_token.clone())) {
-            Some(access_token) => {
-                if is_token_expired(&access_token, Some(session_token.clone())) {
-                    let access_token = refresh_access_token(Some(session_token.clone()));
-
-                    match access_token {
-                        Ok(token) => {
-                            if let Some(modified_cookies) = request_cookies.as_mut() {
-                                for (key, value) in modified_cookies.iter_mut() {
-                                    if *key == "token" {
-                                        *value = &token;
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            let response = unauthorized_response("Could not verify access token");
-                            stream.write(response.as_bytes()).unwrap();
-                            stream.flush().unwrap();
-                            return;
-                        }
-                    }
-                }
-            }
-            None => {
-                let response = unauthorized_response("Could not extract access token");
-                stream.write(response.as_bytes()).unwrap();
-                stream.flush().unwrap();
-                return;
-            }
-        }
-    }
+    let raw_request = String::from_utf8_lossy(&buffer);
+    let peer_addr = stream.peer_addr().ok().map(|addr| addr.ip());
+    let mut request = Request::parse(&raw_request, peer_addr);
+    let request_id = request_id(&request);
 
     // For communicating between threads
     let (sender, receiver) = mpsc::channel::<String>();
 
-    let app_router: Router = Router::new(sender.clone());
-    let response = app_router
-        .route(
-            method.as_str(),
-            uri.as_str(),
-            authorization_header,
-            body,
-            Some(session_token),
-        )
-        .await;
+    let app_router: Router = Router::new(sender.clone(), state.clone());
+
+    // Every line logged while handling the request carries its ID
+    let response = logging::with_request_id(request_id.clone(), async {
+        let response = respond(&state, &mut request, &app_router).await;
+        let response = insert_headers(response, &[("X-Request-Id", request_id)]);
+        log_access(&request, &response, started);
+        metrics::observe_request(
+            &request.method,
+            app_router.route_template(&request.uri),
+            status(&response),
+            started.elapsed(),
+        );
+        response
+    })
+    .await;
 
     // Write the response to stream
-    let _write = stream.write(response.as_bytes()).unwrap();
+    stream.write_all(response.as_bytes()).unwrap();
     stream.flush().unwrap();
 
     thread::spawn(move || {
+        // Counted as a subscriber from the first event pushed to it
+        let mut subscriber = None;
+
         // Reading data that's being sent to the receiver
         for data in receiver {
+            subscriber.get_or_insert_with(metrics::sse_subscribed);
             if let Err(err) = stream.write(data.as_bytes()) {
-                eprintln!("Error writing to stream: {:?}", err);
+                logging::warn(
+                    "Error writing to stream",
+                    &[("error", err.to_string().into())],
+                );
                 // Attempt to gracefully close the stream and break the loop
                 if let Err(close_err) = stream.shutdown(Shutdown::Both) {
-                    eprintln!("Error shutting down stream: {:?}", close_err);
+                    logging::warn(
+                        "Error shutting down stream",
+                        &[("error", close_err.to_string().into())],
+                    );
                 }
                 break;
             }
             if let Err(err) = stream.flush() {
-                eprintln!("Error flushing stream: {:?}", err);
+                logging::warn(
+                    "Error flushing stream",
+                    &[("error", err.to_string().into())],
+                );
                 // Attempt to gracefully close the stream and break the loop
                 if let Err(close_err) = stream.shutdown(Shutdown::Both) {
-                    eprintln!("Error shutting down stream after flushing: {:?}", close_err);
+                    logging::warn(
+                        "Error shutting down stream after flushing",
+                        &[("error", close_err.to_string().into())],
+                    );
                 }
                 break;
             }
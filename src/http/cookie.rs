@@ -0,0 +1,195 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+// Builds the value of a `Set-Cookie` header
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<i64>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            http_only: false,
+            secure: false,
+            same_site: None,
+            path: None,
+            domain: None,
+            max_age: None,
+        }
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+
+            // Older clients ignore Max-Age, so removals also carry a date in the past
+            if max_age <= 0 {
+                write!(f, "; Expires=Thu, 01 Jan 1970 00:00:00 GMT")?;
+            }
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        // Browsers reject `SameSite=None` cookies that are not also `Secure`
+        if self.secure || self.same_site == Some(SameSite::None) {
+            write!(f, "; Secure")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Attributes shared by every cookie the server sets, configured with
+// COOKIE_HTTP_ONLY, COOKIE_SECURE, COOKIE_SAME_SITE, COOKIE_PATH, COOKIE_DOMAIN
+// and COOKIE_SESSION
+#[derive(Debug, Clone)]
+pub struct CookieOptions {
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub path: String,
+    pub domain: Option<String>,
+    // Session cookies carry no Max-Age, so the browser drops them when it closes
+    // instead of keeping them for as long as the token they hold is valid
+    pub session: bool,
+}
+
+impl Default for CookieOptions {
+    fn default() -> Self {
+        CookieOptions {
+            http_only: true,
+            secure: true,
+            same_site: SameSite::Lax,
+            path: String::from("/"),
+            domain: None,
+            session: false,
+        }
+    }
+}
+
+impl CookieOptions {
+    pub fn build(&self, name: &str, value: &str, max_age: i64) -> Cookie {
+        let cookie = Cookie::new(name, value)
+            .http_only(self.http_only)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .path(&self.path);
+        let cookie = if self.session && max_age > 0 {
+            cookie
+        } else {
+            cookie.max_age(max_age)
+        };
+
+        match &self.domain {
+            Some(domain) => cookie.domain(domain),
+            None => cookie,
+        }
+    }
+
+    // A removal has to match the path and domain of the cookie it replaces
+    pub fn removal(&self, name: &str) -> Cookie {
+        self.build(name, "", 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_last_as_long_as_their_max_age() {
+        let cookie = CookieOptions::default().build("token", "abc", 900);
+
+        assert_eq!(
+            cookie.to_string(),
+            "token=abc; Path=/; Max-Age=900; HttpOnly; Secure; SameSite=Lax"
+        );
+    }
+
+    #[test]
+    fn session_cookies_have_no_max_age() {
+        let options = CookieOptions {
+            session: true,
+            ..CookieOptions::default()
+        };
+
+        assert_eq!(
+            options.build("token", "abc", 900).to_string(),
+            "token=abc; Path=/; HttpOnly; Secure; SameSite=Lax"
+        );
+        // Removals still expire the cookie right away
+        assert!(options
+            .removal("token")
+            .to_string()
+            .contains("Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"));
+    }
+}
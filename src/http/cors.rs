@@ -0,0 +1,464 @@
+use regex::Regex;
+
+use super::{
+    request::Request,
+    utils::{empty_response_with_headers, forbidden_response, insert_headers, not_found_response},
+};
+
+// One entry of CORS_ALLOWED_ORIGINS
+#[derive(Debug, Clone)]
+pub enum OriginPattern {
+    // `*`, any origin. Cannot be combined with credentials.
+    Any,
+    // `https://app.example.com`
+    Exact(String),
+    // `https://*.example.com`, any subdomain of example.com but not example.com itself
+    Subdomain { scheme: String, domain: String },
+    // `regex:^https://pr-[0-9]+\.example\.com$`, matched against the whole origin
+    Regex(Regex),
+}
+
+impl OriginPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        if pattern == "*" {
+            return Ok(OriginPattern::Any);
+        }
+
+        if let Some(regex) = pattern.strip_prefix("regex:") {
+            return Regex::new(&format!("^(?:{})$", regex))
+                .map(OriginPattern::Regex)
+                .map_err(|error| format!("`{}` is not a valid regex: {}", regex, error));
+        }
+
+        // Origins never have a path, not even `/`
+        let origin = pattern.trim_end_matches('/');
+        let (scheme, host) = origin
+            .split_once("://")
+            .filter(|(scheme, host)| !scheme.is_empty() && !host.is_empty() && !host.contains('/'))
+            .ok_or_else(|| format!("`{}` is not an origin such as https://example.com", pattern))?;
+
+        match host.strip_prefix("*.") {
+            Some(domain) if !domain.is_empty() && !domain.contains('*') => {
+                Ok(OriginPattern::Subdomain {
+                    scheme: scheme.to_ascii_lowercase(),
+                    domain: domain.to_ascii_lowercase(),
+                })
+            }
+            None if !host.contains('*') => Ok(OriginPattern::Exact(origin.to_ascii_lowercase())),
+            _ => Err(format!(
+                "`{}` can only have a wildcard for the subdomain, as in https://*.example.com",
+                pattern
+            )),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => exact.eq_ignore_ascii_case(origin),
+            OriginPattern::Subdomain { scheme, domain } => {
+                let origin = origin.to_ascii_lowercase();
+                match origin.split_once("://") {
+                    Some((origin_scheme, host)) => {
+                        origin_scheme == scheme
+                            && host.strip_suffix(domain.as_str()).is_some_and(|subdomain| {
+                                subdomain.len() > 1 && subdomain.ends_with('.')
+                            })
+                    }
+                    None => false,
+                }
+            }
+            OriginPattern::Regex(regex) => regex.is_match(origin),
+        }
+    }
+}
+
+// Which cross-origin requests browsers may make, configured with
+// CORS_ALLOWED_ORIGINS, CORS_ALLOWED_METHODS, CORS_ALLOWED_HEADERS,
+// CORS_EXPOSED_HEADERS, CORS_ALLOW_CREDENTIALS and CORS_MAX_AGE
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    pub allowed_origins: Vec<OriginPattern>,
+    pub allowed_methods: Vec<String>,
+    // `*` allows whatever headers the preflight asks for
+    pub allowed_headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: u64,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            allowed_origins: vec![OriginPattern::Exact(String::from("http://localhost:8080"))],
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            allowed_headers: ["Content-Type", "Authorization", "X-API-Key"]
+                .map(String::from)
+                .to_vec(),
+            // Scripts on other origins only see the CORS-safelisted response
+            // headers unless told otherwise, these are the ones they act on
+            exposed_headers: [
+                "X-Request-Id",
+                "RateLimit-Limit",
+                "RateLimit-Remaining",
+                "RateLimit-Reset",
+                "RateLimit-Policy",
+                "Retry-After",
+            ]
+            .map(String::from)
+            .to_vec(),
+            allow_credentials: true,
+            max_age: 600,
+        }
+    }
+}
+
+impl CorsPolicy {
+    // The request's `Origin` when it is one of the allowed ones
+    fn allowed_origin<'a>(&self, request: &Request<'a>) -> Option<&'a str> {
+        let origin = request.header("Origin")?;
+        self.allowed_origins
+            .iter()
+            .any(|pattern| pattern.matches(origin))
+            .then_some(origin)
+    }
+
+    // Headers every response to an allowed origin carries. The origin is sent
+    // back as it came, as `*` is not accepted for requests with credentials.
+    fn origin_headers(&self, origin: &str) -> Vec<(&'static str, String)> {
+        let allows_any = self
+            .allowed_origins
+            .iter()
+            .any(|pattern| matches!(pattern, OriginPattern::Any));
+
+        let mut headers = vec![if allows_any && !self.allow_credentials {
+            ("Access-Control-Allow-Origin", String::from("*"))
+        } else {
+            ("Access-Control-Allow-Origin", origin.to_string())
+        }];
+        if self.allow_credentials {
+            headers.push(("Access-Control-Allow-Credentials", String::from("true")));
+        }
+
+        headers
+    }
+
+    // Adds the CORS headers to the response of an actual request. Responses
+    // differ by `Origin`, so caches are told to keep them apart.
+    pub fn apply(&self, request: &Request, response: String) -> String {
+        let mut headers = vec![("Vary", String::from("Origin"))];
+
+        if let Some(origin) = self.allowed_origin(request) {
+            headers.extend(self.origin_headers(origin));
+            if !self.exposed_headers.is_empty() {
+                headers.push((
+                    "Access-Control-Expose-Headers",
+                    self.exposed_headers.join(", "),
+                ));
+            }
+        }
+
+        insert_headers(response, &headers)
+    }
+
+    // Answers an `OPTIONS` request for a route that accepts `methods`. Only
+    // the methods both the route and the policy allow are offered.
+    pub fn preflight(&self, request: &Request, methods: &[&str]) -> String {
+        if methods.is_empty() {
+            return self.apply(request, not_found_response());
+        }
+
+        // Not a preflight, just a client asking what the route supports
+        let requested_method = match request.header("Access-Control-Request-Method") {
+            Some(method) => method,
+            None => {
+                let allow = [("Allow", [methods, &["OPTIONS"]].concat().join(", "))];
+                return self.apply(request, empty_response_with_headers(204, &allow));
+            }
+        };
+
+        let methods: Vec<&str> = methods
+            .iter()
+            .copied()
+            .filter(|method| self.allowed_methods.iter().any(|allowed| allowed == method))
+            .collect();
+
+        let origin = match self.allowed_origin(request) {
+            Some(origin) => origin,
+            None => {
+                return insert_headers(
+                    forbidden_response("Origin is not allowed"),
+                    &[("Vary", String::from("Origin"))],
+                )
+            }
+        };
+
+        if !methods.contains(&requested_method) {
+            return self.apply(
+                request,
+                forbidden_response(&format!(
+                    "{} is not allowed for this route",
+                    requested_method
+                )),
+            );
+        }
+
+        let allowed_headers = if self.allowed_headers.iter().any(|header| header == "*") {
+            request
+                .header("Access-Control-Request-Headers")
+                .unwrap_or_default()
+                .to_string()
+        } else {
+            self.allowed_headers.join(", ")
+        };
+
+        let mut headers = vec![
+            (
+                "Vary",
+                String::from(
+                    "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+                ),
+            ),
+            ("Access-Control-Allow-Methods", methods.join(", ")),
+            ("Access-Control-Max-Age", self.max_age.to_string()),
+        ];
+        if !allowed_headers.is_empty() {
+            headers.push(("Access-Control-Allow-Headers", allowed_headers));
+        }
+        headers.extend(self.origin_headers(origin));
+
+        empty_response_with_headers(204, &headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str], allow_credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: origins
+                .iter()
+                .map(|origin| OriginPattern::parse(origin).unwrap())
+                .collect(),
+            allow_credentials,
+            ..CorsPolicy::default()
+        }
+    }
+
+    fn request(raw: &str) -> Request<'_> {
+        Request::parse(raw, None)
+    }
+
+    fn status(response: &str) -> u16 {
+        response[9..12].parse().unwrap()
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        response
+            .split("\r\n\r\n")
+            .next()
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim_end())
+    }
+
+    #[test]
+    fn origins_are_parsed() {
+        assert!(matches!(OriginPattern::parse("*"), Ok(OriginPattern::Any)));
+        assert!(matches!(
+            OriginPattern::parse("https://App.example.com/"),
+            Ok(OriginPattern::Exact(origin)) if origin == "https://app.example.com"
+        ));
+        assert!(matches!(
+            OriginPattern::parse("https://*.example.com"),
+            Ok(OriginPattern::Subdomain { .. })
+        ));
+
+        for pattern in [
+            "null",
+            "example.com",
+            "https://",
+            "https://example.com/path",
+            "https://app.*.com",
+            "https://*.",
+            "regex:(",
+        ] {
+            assert!(
+                OriginPattern::parse(pattern).is_err(),
+                "pattern {:?}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn origins_match_exactly_by_subdomain_or_regex() {
+        // (pattern, origin, matches)
+        let cases = [
+            ("https://app.example.com", "https://app.example.com", true),
+            ("https://app.example.com", "HTTPS://APP.EXAMPLE.COM", true),
+            ("https://app.example.com", "http://app.example.com", false),
+            (
+                "https://app.example.com",
+                "https://app.example.com:8443",
+                false,
+            ),
+            ("https://*.example.com", "https://pr-1.example.com", true),
+            ("https://*.example.com", "https://a.b.example.com", true),
+            ("https://*.example.com", "https://example.com", false),
+            ("https://*.example.com", "https://evilexample.com", false),
+            ("https://*.example.com", "http://pr-1.example.com", false),
+            (
+                r"regex:https://pr-[0-9]+\.example\.com",
+                "https://pr-12.example.com",
+                true,
+            ),
+            (
+                r"regex:https://pr-[0-9]+\.example\.com",
+                "https://pr-12.example.com.evil",
+                false,
+            ),
+            ("*", "https://anything.test", true),
+        ];
+
+        for (pattern, origin, matches) in cases {
+            assert_eq!(
+                OriginPattern::parse(pattern).unwrap().matches(origin),
+                matches,
+                "{} against {}",
+                pattern,
+                origin
+            );
+        }
+    }
+
+    #[test]
+    fn null_origins_are_only_allowed_by_a_wildcard() {
+        let raw = "GET /auth/me HTTP/1.1\r\nOrigin: null\r\n\r\n";
+
+        let response = policy(&["https://app.example.com"], true)
+            .apply(&request(raw), String::from("HTTP/1.1 200 OK\r\n\r\n"));
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+
+        let response =
+            policy(&["*"], false).apply(&request(raw), String::from("HTTP/1.1 200 OK\r\n\r\n"));
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some("*"));
+    }
+
+    #[test]
+    fn allowed_origins_are_echoed_with_credentials() {
+        let raw = "GET /auth/me HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n";
+        let response = policy(&["https://app.example.com"], true)
+            .apply(&request(raw), String::from("HTTP/1.1 200 OK\r\n\r\n"));
+
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(header(&response, "Vary"), Some("Origin"));
+        assert!(header(&response, "Access-Control-Expose-Headers")
+            .unwrap()
+            .contains("X-Request-Id"));
+    }
+
+    #[test]
+    fn a_wildcard_with_credentials_echoes_the_origin() {
+        // Browsers refuse `*` on requests with credentials
+        let raw = "GET /auth/me HTTP/1.1\r\nOrigin: https://other.test\r\n\r\n";
+        let response =
+            policy(&["*"], true).apply(&request(raw), String::from("HTTP/1.1 200 OK\r\n\r\n"));
+
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Origin"),
+            Some("https://other.test")
+        );
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn other_origins_get_no_cors_headers() {
+        let raw = "GET /auth/me HTTP/1.1\r\nOrigin: https://evil.test\r\n\r\n";
+        let response = policy(&["https://app.example.com"], true)
+            .apply(&request(raw), String::from("HTTP/1.1 200 OK\r\n\r\n"));
+
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&response, "Access-Control-Allow-Credentials"), None);
+        assert_eq!(header(&response, "Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn preflights_offer_the_methods_the_route_and_policy_allow() {
+        let raw = "OPTIONS /auth/totp HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: DELETE\r\n\r\n";
+        let response = policy(&["https://app.example.com"], true)
+            .preflight(&request(raw), &["POST", "DELETE", "TRACE"]);
+
+        assert_eq!(status(&response), 204);
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Methods"),
+            Some("POST, DELETE")
+        );
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Headers"),
+            Some("Content-Type, Authorization, X-API-Key")
+        );
+        assert_eq!(header(&response, "Access-Control-Max-Age"), Some("600"));
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+    }
+
+    #[test]
+    fn preflights_are_refused_for_other_origins_and_methods() {
+        let policy = policy(&["https://app.example.com"], true);
+
+        let raw = "OPTIONS /auth/totp HTTP/1.1\r\nOrigin: https://evil.test\r\nAccess-Control-Request-Method: POST\r\n\r\n";
+        let response = policy.preflight(&request(raw), &["POST"]);
+        assert_eq!(status(&response), 403);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+
+        let raw = "OPTIONS /auth/totp HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: PUT\r\n\r\n";
+        let response = policy.preflight(&request(raw), &["POST"]);
+        assert_eq!(status(&response), 403);
+
+        let response = policy.preflight(&request(raw), &[]);
+        assert_eq!(status(&response), 404);
+    }
+
+    #[test]
+    fn options_without_a_preflight_lists_the_route_methods() {
+        let raw = "OPTIONS /auth/totp HTTP/1.1\r\n\r\n";
+        let response = policy(&["https://app.example.com"], true)
+            .preflight(&request(raw), &["POST", "DELETE"]);
+
+        assert_eq!(status(&response), 204);
+        assert_eq!(header(&response, "Allow"), Some("POST, DELETE, OPTIONS"));
+    }
+
+    #[test]
+    fn preflights_can_allow_any_requested_header() {
+        let raw = "OPTIONS /auth/me HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: GET\r\nAccess-Control-Request-Headers: X-Custom, X-Other\r\n\r\n";
+        let policy = CorsPolicy {
+            allowed_headers: vec![String::from("*")],
+            ..policy(&["https://app.example.com"], true)
+        };
+        let response = policy.preflight(&request(raw), &["GET"]);
+
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Headers"),
+            Some("X-Custom, X-Other")
+        );
+    }
+}
@@ -1,3 +1,8 @@
+pub mod auth;
 pub mod connection;
+pub mod cookie;
+pub mod cors;
+pub mod rate_limit;
+pub mod request;
 pub mod thread_pool;
 pub mod utils;
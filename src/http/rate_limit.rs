@@ -0,0 +1,427 @@
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::logging;
+
+use super::{request::Request, utils::error_response_with_headers};
+
+// `30/60`, up to 30 requests at once and 30 more for every 60 seconds after that
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub capacity: u32,
+    pub period_secs: u64,
+}
+
+impl Bucket {
+    pub fn parse(bucket: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "`{}` is not a bucket such as 30/60, that is requests per seconds",
+                bucket
+            )
+        };
+
+        let (capacity, period_secs) = bucket.split_once('/').ok_or_else(invalid)?;
+        let capacity: u32 = capacity.trim().parse().map_err(|_| invalid())?;
+        let period_secs: u64 = period_secs.trim().parse().map_err(|_| invalid())?;
+        if capacity == 0 || period_secs == 0 {
+            return Err(invalid());
+        }
+
+        Ok(Bucket {
+            capacity,
+            period_secs,
+        })
+    }
+
+    fn tokens_per_ms(&self) -> f64 {
+        self.capacity as f64 / (self.period_secs * 1000) as f64
+    }
+}
+
+// Tokens left in one bucket, as of `updated_at` in milliseconds since the epoch
+#[derive(Debug, Clone, Copy)]
+pub struct BucketState {
+    pub tokens: f64,
+    pub updated_at: u64,
+}
+
+impl BucketState {
+    fn full(bucket: &Bucket, now: u64) -> Self {
+        BucketState {
+            tokens: bucket.capacity as f64,
+            updated_at: now,
+        }
+    }
+
+    // Tops the bucket up for the time since it was last updated
+    fn refilled(&self, bucket: &Bucket, now: u64) -> Self {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        BucketState {
+            tokens: (self.tokens + elapsed * bucket.tokens_per_ms()).min(bucket.capacity as f64),
+            updated_at: now.max(self.updated_at),
+        }
+    }
+
+    fn ms_until(&self, bucket: &Bucket, tokens: f64) -> u64 {
+        // Multiplies rather than divides by the rate, which would round up
+        // exact waits by a millisecond
+        let missing = (tokens - self.tokens).max(0.0);
+        (missing * (bucket.period_secs * 1000) as f64 / bucket.capacity as f64).ceil() as u64
+    }
+}
+
+// Whether a request may go through, and what is left in its bucket after it
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub state: BucketState,
+}
+
+// Where buckets are kept. The in-memory store only limits the instance it runs
+// in, a store shared between instances (Redis, Postgres) implements this trait
+// and makes `take` a single atomic step so concurrent requests are all counted.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    // Refills the bucket at `key` and takes a token from it when there is one
+    async fn take(&self, key: &str, bucket: &Bucket, now: u64) -> Result<Decision, String>;
+}
+
+// Next to each bucket is the time it is full again
+struct MemoryBuckets {
+    buckets: BTreeMap<String, (BucketState, u64)>,
+    pruned_at: u64,
+}
+
+impl MemoryBuckets {
+    // Drops the buckets that have filled up again, a full bucket is the same as
+    // no bucket at all. It looks at every key, so it runs at most once per
+    // MEMORY_BUCKETS_PRUNE_EVERY_MS and only once there are many of them.
+    fn prune(&mut self, now: u64) {
+        if self.buckets.len() < MEMORY_BUCKETS_PRUNE_AT
+            || now < self.pruned_at + MEMORY_BUCKETS_PRUNE_EVERY_MS
+        {
+            return;
+        }
+
+        self.buckets.retain(|_, (_, full_at)| *full_at > now);
+        self.pruned_at = now;
+    }
+}
+
+// `rate_limit_store` hands out a new store for every request, so the buckets
+// live in a static that outlasts them
+static MEMORY_BUCKETS: Mutex<MemoryBuckets> = Mutex::new(MemoryBuckets {
+    buckets: BTreeMap::new(),
+    pruned_at: 0,
+});
+
+const MEMORY_BUCKETS_PRUNE_AT: usize = 10_000;
+const MEMORY_BUCKETS_PRUNE_EVERY_MS: u64 = 60_000;
+
+pub struct MemoryRateLimitStore;
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn take(&self, key: &str, bucket: &Bucket, now: u64) -> Result<Decision, String> {
+        let mut memory = MEMORY_BUCKETS.lock().unwrap();
+        memory.prune(now);
+
+        let state = memory
+            .buckets
+            .get(key)
+            .map(|(state, _)| state.refilled(bucket, now))
+            .unwrap_or_else(|| BucketState::full(bucket, now));
+
+        let allowed = state.tokens >= 1.0;
+        let state = BucketState {
+            tokens: if allowed {
+                state.tokens - 1.0
+            } else {
+                state.tokens
+            },
+            ..state
+        };
+        let full_at = now + state.ms_until(bucket, bucket.capacity as f64);
+        memory.buckets.insert(key.to_string(), (state, full_at));
+
+        Ok(Decision { allowed, state })
+    }
+}
+
+pub fn rate_limit_store() -> Box<dyn RateLimitStore> {
+    Box::new(MemoryRateLimitStore)
+}
+
+// Set with RATE_LIMIT_ENABLED and RATE_LIMIT_BUCKETS. Buckets are per route
+// group, the first segment of the path, with `default` for groups that have
+// none of their own. `None` leaves a group unlimited.
+#[derive(Debug, Clone)]
+pub struct RateLimitPolicy {
+    pub enabled: bool,
+    pub buckets: BTreeMap<String, Option<Bucket>>,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        let per_minute = |capacity| {
+            Some(Bucket {
+                capacity,
+                period_secs: 60,
+            })
+        };
+        let buckets = [
+            ("default", per_minute(300)),
+            // Logins have their own lockout, this also covers registering and resets
+            ("auth", per_minute(30)),
+            // Probes and scrapers poll on a schedule
+            ("healthz", None),
+            ("readyz", None),
+            ("metrics", None),
+        ]
+        .into_iter()
+        .map(|(group, bucket)| (group.to_string(), bucket))
+        .collect();
+
+        RateLimitPolicy {
+            enabled: true,
+            buckets,
+        }
+    }
+}
+
+impl RateLimitPolicy {
+    fn bucket(&self, group: &str) -> Option<&Bucket> {
+        match self.buckets.get(group) {
+            Some(bucket) => bucket.as_ref(),
+            None => self.buckets.get("default").and_then(Option::as_ref),
+        }
+    }
+
+    fn client_key(group: &str, request: &Request) -> String {
+        format!("{}:ip:{}", group, request.client_ip().unwrap_or_default())
+    }
+
+    // Whose bucket a request is taken from, the API key or signed in user when
+    // there is one, otherwise the client's IP
+    fn key(group: &str, request: &Request) -> String {
+        match (&request.api_key, &request.claims) {
+            (Some(caller), _) => format!("{}:key:{}", group, caller.key_id),
+            (None, Some(claims)) => format!("{}:user:{}", group, claims.uid),
+            (None, None) => RateLimitPolicy::client_key(group, request),
+        }
+    }
+
+    // Takes a token from the client IP's bucket before the caller is known.
+    // Looking up an API key costs a query, so guessing at keys is held back
+    // before it reaches the database.
+    pub async fn check_client(
+        &self,
+        group: &str,
+        request: &Request<'_>,
+    ) -> Result<Vec<(&'static str, String)>, String> {
+        self.take(group, &RateLimitPolicy::client_key(group, request))
+            .await
+    }
+
+    // Takes a token for the request from its caller's bucket in its route group
+    pub async fn check(
+        &self,
+        group: &str,
+        request: &Request<'_>,
+    ) -> Result<Vec<(&'static str, String)>, String> {
+        self.take(group, &RateLimitPolicy::key(group, request))
+            .await
+    }
+
+    // Gives back the `RateLimit-*` headers for the response, or a 429 when the
+    // bucket at `key` is empty. Requests go through when the store cannot be
+    // reached.
+    async fn take(&self, group: &str, key: &str) -> Result<Vec<(&'static str, String)>, String> {
+        let bucket = match self.bucket(group) {
+            Some(bucket) if self.enabled => bucket,
+            _ => return Ok(vec![]),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let decision = match rate_limit_store().take(key, bucket, now).await {
+            Ok(decision) => decision,
+            Err(error) => {
+                logging::warn(
+                    "Rate limit store failed, letting the request through",
+                    &[("error", error.into())],
+                );
+                return Ok(vec![]);
+            }
+        };
+
+        let mut headers = rate_limit_headers(bucket, &decision.state);
+        if decision.allowed {
+            return Ok(headers);
+        }
+
+        headers.push((
+            "Retry-After",
+            retry_after_secs(bucket, &decision.state).to_string(),
+        ));
+        Err(error_response_with_headers(
+            429,
+            "Too many requests, try again later",
+            &headers,
+        ))
+    }
+}
+
+fn rate_limit_headers(bucket: &Bucket, state: &BucketState) -> Vec<(&'static str, String)> {
+    let reset_secs = state
+        .ms_until(bucket, bucket.capacity as f64)
+        .div_ceil(1000);
+
+    vec![
+        ("RateLimit-Limit", bucket.capacity.to_string()),
+        (
+            "RateLimit-Remaining",
+            (state.tokens.floor() as u64).to_string(),
+        ),
+        ("RateLimit-Reset", reset_secs.to_string()),
+        (
+            "RateLimit-Policy",
+            format!("{};w={}", bucket.capacity, bucket.period_secs),
+        ),
+    ]
+}
+
+// Seconds until the next token, never 0 so clients do not retry right away
+fn retry_after_secs(bucket: &Bucket, state: &BucketState) -> u64 {
+    state.ms_until(bucket, 1.0).div_ceil(1000).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PER_MINUTE: Bucket = Bucket {
+        capacity: 30,
+        period_secs: 60,
+    };
+
+    fn state(tokens: f64) -> BucketState {
+        BucketState {
+            tokens,
+            updated_at: 1_000,
+        }
+    }
+
+    fn header<'a>(headers: &'a [(&'static str, String)], name: &str) -> &'a str {
+        headers
+            .iter()
+            .find(|(header, _)| *header == name)
+            .map(|(_, value)| value.as_str())
+            .unwrap()
+    }
+
+    #[test]
+    fn buckets_are_parsed_as_requests_per_seconds() {
+        assert_eq!(Bucket::parse("30/60"), Ok(PER_MINUTE));
+        assert_eq!(Bucket::parse(" 30 / 60 "), Ok(PER_MINUTE));
+
+        for bucket in [
+            "0/60", "30/0", "30", "30/", "/60", "a/b", "-1/60", "30/60/1", "",
+        ] {
+            assert!(Bucket::parse(bucket).is_err(), "bucket {:?}", bucket);
+        }
+    }
+
+    #[test]
+    fn buckets_refill_with_time_up_to_their_capacity() {
+        // One token every 2 seconds
+        let empty = state(0.0);
+
+        assert_eq!(empty.refilled(&PER_MINUTE, 3_000).tokens, 1.0);
+        assert_eq!(empty.refilled(&PER_MINUTE, 31_000).tokens, 15.0);
+        assert_eq!(empty.refilled(&PER_MINUTE, 1_000_000).tokens, 30.0);
+
+        // A clock that went back neither drains the bucket nor moves it back
+        let refilled = empty.refilled(&PER_MINUTE, 500);
+        assert_eq!(refilled.tokens, 0.0);
+        assert_eq!(refilled.updated_at, 1_000);
+    }
+
+    #[test]
+    fn time_until_tokens_rounds_up() {
+        assert_eq!(state(0.0).ms_until(&PER_MINUTE, 1.0), 2_000);
+        assert_eq!(state(0.5).ms_until(&PER_MINUTE, 1.0), 1_000);
+        assert_eq!(state(29.0).ms_until(&PER_MINUTE, 30.0), 2_000);
+        assert_eq!(state(3.0).ms_until(&PER_MINUTE, 1.0), 0);
+    }
+
+    #[test]
+    fn headers_describe_the_bucket_after_the_request() {
+        let headers = rate_limit_headers(&PER_MINUTE, &state(27.5));
+
+        assert_eq!(header(&headers, "RateLimit-Limit"), "30");
+        assert_eq!(header(&headers, "RateLimit-Remaining"), "27");
+        assert_eq!(header(&headers, "RateLimit-Reset"), "5");
+        assert_eq!(header(&headers, "RateLimit-Policy"), "30;w=60");
+    }
+
+    #[test]
+    fn retry_after_is_at_least_a_second() {
+        assert_eq!(retry_after_secs(&PER_MINUTE, &state(0.0)), 2);
+        assert_eq!(retry_after_secs(&PER_MINUTE, &state(0.9)), 1);
+
+        let per_hour = Bucket {
+            capacity: 1,
+            period_secs: 3_600,
+        };
+        assert_eq!(retry_after_secs(&per_hour, &state(0.0)), 3_600);
+    }
+
+    #[tokio::test]
+    async fn the_memory_store_empties_and_refills_a_bucket() {
+        let bucket = Bucket {
+            capacity: 2,
+            period_secs: 10,
+        };
+        let store = MemoryRateLimitStore;
+        let key = "tests:the_memory_store_empties_and_refills_a_bucket";
+
+        assert!(store.take(key, &bucket, 0).await.unwrap().allowed);
+        assert!(store.take(key, &bucket, 0).await.unwrap().allowed);
+        assert!(!store.take(key, &bucket, 0).await.unwrap().allowed);
+        assert!(store.take(key, &bucket, 5_000).await.unwrap().allowed);
+    }
+
+    #[test]
+    fn full_buckets_are_pruned_at_most_once_per_interval() {
+        let mut memory = MemoryBuckets {
+            buckets: BTreeMap::new(),
+            pruned_at: 0,
+        };
+        for key in 0..MEMORY_BUCKETS_PRUNE_AT {
+            // Every other bucket is full again at 100_000
+            let full_at = if key % 2 == 0 { 100_000 } else { 200_000 };
+            memory
+                .buckets
+                .insert(key.to_string(), (state(0.0), full_at));
+        }
+
+        memory.prune(50_000);
+        assert_eq!(memory.buckets.len(), MEMORY_BUCKETS_PRUNE_AT);
+
+        memory.prune(150_000);
+        assert_eq!(memory.buckets.len(), MEMORY_BUCKETS_PRUNE_AT / 2);
+        assert_eq!(memory.pruned_at, 150_000);
+
+        // Below the threshold nothing is scanned
+        memory.prune(250_000);
+        assert_eq!(memory.buckets.len(), MEMORY_BUCKETS_PRUNE_AT / 2);
+    }
+}
@@ -0,0 +1,58 @@
+use std::net::IpAddr;
+
+use crate::app::models::{api_key::ApiKeyCaller, claims::Claims};
+
+use super::utils::{
+    extract_body, extract_cookies, extract_headers, extract_method, extract_uri, Cookies,
+};
+
+pub struct Request<'a> {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(&'a str, &'a str)>,
+    pub body: String,
+    pub cookies: Option<Cookies<'a>>,
+    pub peer_addr: Option<IpAddr>,
+    // Set by the connection handler once the caller has been authenticated
+    pub claims: Option<Claims>,
+    // Set instead of `claims` when the caller used an API key
+    pub api_key: Option<ApiKeyCaller>,
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(request: &'a str, peer_addr: Option<IpAddr>) -> Self {
+        let headers = extract_headers(request);
+        let cookies = extract_cookies(&headers);
+
+        Request {
+            method: extract_method(request),
+            uri: extract_uri(request),
+            headers,
+            body: extract_body(request),
+            cookies,
+            peer_addr,
+            claims: None,
+            api_key: None,
+        }
+    }
+
+    // Header names are case-insensitive, so `authorization` and `Authorization` both match
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    pub fn authorization_header(&self) -> Option<&'a str> {
+        self.header("Authorization")
+    }
+
+    pub fn user_agent(&self) -> Option<&'a str> {
+        self.header("User-Agent")
+    }
+
+    pub fn client_ip(&self) -> Option<String> {
+        self.peer_addr.map(|addr| addr.to_string())
+    }
+}
@@ -1,29 +1,64 @@
 use std::{
     future::Future,
     pin::Pin,
-    sync::{mpsc, Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
 };
+use tokio::runtime::Handle;
 
 type Job = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
 
+// There is one pool per process, its load is kept in statics so the readiness
+// check can read it without a handle to the pool
+static WORKERS: AtomicUsize = AtomicUsize::new(0);
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolLoad {
+    pub workers: usize,
+    pub busy: usize,
+    pub queued: usize,
+}
+
+impl PoolLoad {
+    // Every worker is taken and at least as many jobs again are waiting
+    pub fn is_saturated(&self) -> bool {
+        self.busy >= self.workers && self.queued >= self.workers
+    }
+}
+
+pub fn load() -> PoolLoad {
+    PoolLoad {
+        workers: WORKERS.load(Ordering::Relaxed),
+        busy: BUSY.load(Ordering::Relaxed),
+        queued: QUEUED.load(Ordering::Relaxed),
+    }
+}
+
 enum Message {
     NewJob(Job),
     Terminate,
 }
 
 struct Worker {
-    id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>, runtime: Handle) -> Worker {
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+        let thread = builder.spawn(move || loop {
             let message = receiver.lock().unwrap().recv().unwrap();
             match message {
                 Message::NewJob(job) => {
-                    let _ = tokio::runtime::Runtime::new().unwrap().block_on(job);
+                    QUEUED.fetch_sub(1, Ordering::Relaxed);
+                    BUSY.fetch_add(1, Ordering::Relaxed);
+                    runtime.block_on(job);
+                    BUSY.fetch_sub(1, Ordering::Relaxed);
                 }
                 Message::Terminate => {
                     break;
@@ -32,8 +67,7 @@ impl Worker {
         });
 
         Worker {
-            id,
-            thread: Some(thread),
+            thread: Some(thread.unwrap()),
         }
     }
 }
@@ -44,8 +78,9 @@ pub struct ThreadPool {
 }
 
 impl ThreadPool {
-    // Creates a new thread pool
-    pub fn new(size: usize) -> ThreadPool {
+    // Creates a new thread pool. Jobs run on `runtime`, the one the database
+    // pool was opened on, as its connections cannot be used from any other.
+    pub fn new(size: usize, runtime: Handle) -> ThreadPool {
         assert!(size > 0, "Size of the thread pool should be greater than 0");
 
         let (sender, channel_receiver) = mpsc::channel::<Message>();
@@ -54,9 +89,11 @@ impl ThreadPool {
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), runtime.clone()));
         }
 
+        WORKERS.fetch_add(size, Ordering::Relaxed);
+
         ThreadPool { workers, sender }
     }
 
@@ -66,6 +103,7 @@ impl ThreadPool {
         F: Future<Output = ()> + Send + 'static,
     {
         let job = Box::pin(f);
+        QUEUED.fetch_add(1, Ordering::Relaxed);
         self.sender.send(Message::NewJob(job)).unwrap();
     }
 }
@@ -83,5 +121,7 @@ impl Drop for ThreadPool {
                 thread.join().unwrap();
             }
         }
+
+        WORKERS.fetch_sub(self.workers.len(), Ordering::Relaxed);
     }
 }
@@ -0,0 +1,168 @@
+use serde_json::Value;
+use std::{
+    future::Future,
+    io::Write,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Most severe first, so a level lets through everything that compares lower
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    // `ts=... level=info msg="..." key=value`
+    Logfmt,
+    // One JSON object per line
+    Json,
+}
+
+// Set with LOG_LEVEL and LOG_FORMAT
+#[derive(Debug, Clone, Copy)]
+pub struct LogConfig {
+    pub level: Level,
+    pub format: LogFormat,
+}
+
+// Lines logged before `init`, while the configuration is still being loaded,
+// use these
+const DEFAULT_SETTINGS: LogConfig = LogConfig {
+    level: Level::Info,
+    format: LogFormat::Logfmt,
+};
+
+static SETTINGS: OnceLock<LogConfig> = OnceLock::new();
+
+// Logging is set up once, in `main`, as the first thing after loading the configuration
+pub fn init(settings: LogConfig) {
+    let _ = SETTINGS.set(settings);
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Runs `future` with `request_id` attached to every line logged while it runs
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+// RFC 3339 in UTC with milliseconds, `2024-03-01T12:00:00.000Z`
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs() as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        now.subsec_millis()
+    )
+}
+
+// Bare when it can be, quoted like a JSON string when it has spaces, quotes or `=`
+fn logfmt_value(value: &Value) -> String {
+    let text = match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+
+    let is_bare = !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_graphic() && c != '"' && c != '=' && c != '\\');
+    if is_bare {
+        text
+    } else {
+        Value::String(text).to_string()
+    }
+}
+
+pub fn log(level: Level, message: &str, fields: &[(&str, Value)]) {
+    let settings = SETTINGS.get().copied().unwrap_or(DEFAULT_SETTINGS);
+    if level > settings.level {
+        return;
+    }
+
+    let mut entries = vec![
+        ("ts", Value::from(timestamp())),
+        ("level", Value::from(level.as_str())),
+        ("msg", Value::from(message)),
+    ];
+    if let Ok(request_id) = REQUEST_ID.try_with(|request_id| request_id.clone()) {
+        entries.push(("request_id", Value::from(request_id)));
+    }
+    entries.extend(fields.iter().cloned());
+
+    let line = match settings.format {
+        // Written by hand to keep the fields in order, `ts` and `level` first
+        LogFormat::Json => {
+            let fields: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| format!("{}:{}", Value::from(*key), value))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        LogFormat::Logfmt => entries
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, logfmt_value(value)))
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+
+    // Locked so lines from different workers never interleave
+    let _ = writeln!(std::io::stdout().lock(), "{}", line);
+}
+
+pub fn error(message: &str, fields: &[(&str, Value)]) {
+    log(Level::Error, message, fields);
+}
+
+pub fn warn(message: &str, fields: &[(&str, Value)]) {
+    log(Level::Warn, message, fields);
+}
+
+pub fn info(message: &str, fields: &[(&str, Value)]) {
+    log(Level::Info, message, fields);
+}
+
+pub fn debug(message: &str, fields: &[(&str, Value)]) {
+    log(Level::Debug, message, fields);
+}
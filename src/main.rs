@@ -1,34 +1,129 @@
-use dotenv::dotenv;
+use app::{
+    router::app::POSTGRES_ONLY,
+    services::{health, validation},
+    state::AppState,
+};
+use config::Config;
 use http::connection;
 use http::thread_pool::ThreadPool;
 use std::env;
 use std::net::TcpListener;
 use std::sync::Arc;
+use tokio::runtime::Handle;
 
 mod app;
+mod cli;
+mod config;
+mod db;
 mod http;
+mod logging;
+mod metrics;
+
+// Everything that has to be in place before the server takes traffic
+async fn initialize(state: &AppState) -> Result<(), String> {
+    // Apply pending migrations, set AUTO_MIGRATE=false to only report them and
+    // run `migrate up` by hand
+    db::migrate::migrate_on_startup(&state.db, state.config.server.auto_migrate)
+        .await
+        .map_err(|error| format!("Migration failed: {}", error))?;
+
+    health::check_signing_keys(&state.config.jwt)
+        .map_err(|error| format!("Signing keys are not usable: {}", error))?;
+
+    // Read now rather than on the first registration
+    validation::breached_passwords(&state.config.password);
+
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    dotenv().ok();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (loaded, args) = match Config::load(&args) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    logging::init(loaded.log);
+    let config = Arc::new(loaded);
+
+    if let Some(result) = cli::run(&config, &args).await {
+        if let Err(error) = result {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(error) = config.check_server() {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
 
-    let address = match env::var("APP_URL") {
-        Ok(address) => address,
-        _ => String::from("127.0.0.1:7878"),
+    // One pool for every request, opened before any is taken
+    let db = match db::open(&config.database) {
+        Ok(db) => db,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
     };
+    if db.postgres().is_err() {
+        let features: Vec<&str> = POSTGRES_ONLY.iter().map(|(_, feature)| *feature).collect();
+        logging::warn(
+            "SQLite keeps users and sessions only, these features answer 501",
+            &[("features", features.join(", ").into())],
+        );
+    }
+    let state = AppState::new(config, db);
 
-    let listener = TcpListener::bind(address).unwrap();
+    health::mark_started();
+
+    let address = &state.config.server.app_url;
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            logging::error(
+                "Could not listen",
+                &[
+                    ("address", address.as_str().into()),
+                    ("error", error.to_string().into()),
+                ],
+            );
+            std::process::exit(1);
+        }
+    };
+    logging::info("Listening", &[("address", address.as_str().into())]);
 
     // Make pool a shared resource that is in sync across threads
-    let pool = Arc::new(ThreadPool::new(4));
+    let pool = Arc::new(ThreadPool::new(4, Handle::current()));
+
+    // Initialize in the background so `/healthz` answers right away, `/readyz`
+    // reports not ready until this is done
+    let initial_state = state.clone();
+    tokio::spawn(async move {
+        match initialize(&initial_state).await {
+            Ok(_) => {
+                health::mark_ready();
+                logging::info("Ready", &[]);
+            }
+            Err(error) => {
+                logging::error("Startup failed", &[("error", error.into())]);
+                std::process::exit(1);
+            }
+        }
+    });
 
     for stream in listener.incoming() {
         let stream_value = stream.unwrap();
         let thread_pool = Arc::clone(&pool);
+        let state = state.clone();
 
         // Execute the connection handling task within the thread pool
         thread_pool.execute(async move {
-            connection::handle_connection(stream_value).await;
+            connection::handle_connection(stream_value, state).await;
         });
     }
 }
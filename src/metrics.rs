@@ -0,0 +1,290 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{db::DatabasePool, http::thread_pool};
+
+// Upper bounds of the latency buckets in seconds, Prometheus' own defaults
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // Observations at or below each bound, not yet cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+// (method, route, status)
+type RequestLabels = (&'static str, String, u16);
+
+static REQUESTS: Mutex<BTreeMap<RequestLabels, Histogram>> = Mutex::new(BTreeMap::new());
+static LOGINS: Mutex<BTreeMap<(&str, &str), u64>> = Mutex::new(BTreeMap::new());
+static REFRESHES: Mutex<BTreeMap<&str, u64>> = Mutex::new(BTreeMap::new());
+
+static CONNECTIONS_IN_FLIGHT: AtomicI64 = AtomicI64::new(0);
+static SSE_SUBSCRIBERS: AtomicI64 = AtomicI64::new(0);
+
+// Counts up a gauge for as long as it is held
+pub struct Tracked(&'static AtomicI64);
+
+impl Tracked {
+    fn start(gauge: &'static AtomicI64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Tracked(gauge)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn connection_started() -> Tracked {
+    Tracked::start(&CONNECTIONS_IN_FLIGHT)
+}
+
+// Held by the thread that streams data pushed through a connection's sender
+pub fn sse_subscribed() -> Tracked {
+    Tracked::start(&SSE_SUBSCRIBERS)
+}
+
+// The method comes straight from the request line, so anything else than the
+// usual ones is counted together and clients cannot add label values at will
+fn method_label(method: &str) -> &'static str {
+    match method {
+        "GET" => "GET",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "PATCH" => "PATCH",
+        "DELETE" => "DELETE",
+        "OPTIONS" => "OPTIONS",
+        "HEAD" => "HEAD",
+        _ => "other",
+    }
+}
+
+pub fn observe_request(method: &str, route: &str, status: u16, duration: Duration) {
+    REQUESTS
+        .lock()
+        .unwrap()
+        .entry((method_label(method), route.to_string(), status))
+        .or_default()
+        .observe(duration.as_secs_f64());
+}
+
+// `step` is `password` or `mfa`. `outcome` is `success` or `mfa_required`, or for
+// failed logins `failure`, `throttled` or `error` when the server was at fault.
+pub fn record_login(step: &'static str, outcome: &'static str) {
+    *LOGINS.lock().unwrap().entry((step, outcome)).or_default() += 1;
+}
+
+// `success`, or `failure`, `reused` or `error` like failed logins
+pub fn record_refresh(outcome: &'static str) {
+    *REFRESHES.lock().unwrap().entry(outcome).or_default() += 1;
+}
+
+// Label values are quoted, with backslashes, quotes and newlines escaped
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Everything in the Prometheus text exposition format, along with the state
+// of the database pool the requests share
+pub fn render(db: &DatabasePool) -> String {
+    let mut out = String::new();
+
+    let requests = REQUESTS.lock().unwrap();
+    header(
+        &mut out,
+        "http_requests_total",
+        "counter",
+        "Requests handled, by method, route template and status.",
+    );
+    for ((method, route, status), histogram) in requests.iter() {
+        let _ = writeln!(
+            out,
+            "http_requests_total{{method={},route={},status=\"{}\"}} {}",
+            label(method),
+            label(route),
+            status,
+            histogram.count
+        );
+    }
+
+    header(
+        &mut out,
+        "http_request_duration_seconds",
+        "histogram",
+        "Time from accepting a connection to having the response, by method, route template and status.",
+    );
+    for ((method, route, status), histogram) in requests.iter() {
+        let labels = format!(
+            "method={},route={},status=\"{}\"",
+            label(method),
+            label(route),
+            status
+        );
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_sum{{{}}} {}",
+            labels, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_count{{{}}} {}",
+            labels, histogram.count
+        );
+    }
+    drop(requests);
+
+    gauge(
+        &mut out,
+        "http_connections_in_flight",
+        "Connections being handled right now.",
+        CONNECTIONS_IN_FLIGHT.load(Ordering::Relaxed),
+    );
+    gauge(
+        &mut out,
+        "sse_subscribers",
+        "Connections streaming data pushed to them.",
+        SSE_SUBSCRIBERS.load(Ordering::Relaxed),
+    );
+
+    let load = thread_pool::load();
+    gauge(
+        &mut out,
+        "thread_pool_workers",
+        "Worker threads in the pool.",
+        load.workers as i64,
+    );
+    gauge(
+        &mut out,
+        "thread_pool_busy_workers",
+        "Workers handling a connection.",
+        load.busy as i64,
+    );
+    gauge(
+        &mut out,
+        "thread_pool_queued_jobs",
+        "Connections waiting for a free worker.",
+        load.queued as i64,
+    );
+
+    header(
+        &mut out,
+        "auth_logins_total",
+        "counter",
+        "Login attempts, by step (password or mfa) and outcome.",
+    );
+    for ((step, outcome), count) in LOGINS.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "auth_logins_total{{step={},outcome={}}} {}",
+            label(step),
+            label(outcome),
+            count
+        );
+    }
+
+    header(
+        &mut out,
+        "auth_token_refreshes_total",
+        "counter",
+        "Refresh token exchanges, by outcome.",
+    );
+    for (outcome, count) in REFRESHES.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "auth_token_refreshes_total{{outcome={}}} {}",
+            label(outcome),
+            count
+        );
+    }
+
+    let connections = db.connections();
+    let backend = label(db.backend());
+    for (name, help, value) in [
+        (
+            "db_pool_connections",
+            "Connections the database pool holds, idle or in use.",
+            connections.open as usize,
+        ),
+        (
+            "db_pool_idle_connections",
+            "Connections in the database pool waiting to be used.",
+            connections.idle,
+        ),
+        (
+            "db_pool_max_connections",
+            "Most connections the database pool opens.",
+            connections.max as usize,
+        ),
+    ] {
+        header(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{}{{backend={}}} {}", name, backend, value);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unusual_methods_share_one_label() {
+        assert_eq!(method_label("GET"), "GET");
+        assert_eq!(method_label("HEAD"), "HEAD");
+        assert_eq!(method_label("get"), "other");
+        assert_eq!(method_label("BREW"), "other");
+        assert_eq!(method_label("X-RANDOM-1234"), "other");
+    }
+}